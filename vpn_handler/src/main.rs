@@ -1,27 +1,81 @@
 mod tools;
 
+use crate::tools::config;
+use crate::tools::control;
+use crate::tools::daemon::Daemon;
+use crate::tools::events::{Event, EventBus};
 use crate::tools::logger::Logger;
 use crate::tools::notifier::Notifier;
-use serialport;
-use std::io::{Error, Read, Write};
+use crate::tools::quarantine::Quarantine;
+use crate::tools::selection::Selection;
+use crate::tools::settings::{DEFAULT_CONFIG_PATH, Settings};
+use crate::tools::severity::Severity;
+use crate::tools::shutdown::{self, SHUTDOWN_SIGNALS, Shutdown};
+use crate::tools::supervisor::RunnerState;
+use crate::tools::systemd::{self, SystemdNotifier};
+use crate::tools::watcher::Watcher;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::thread::JoinHandle;
 use std::time::Duration;
 use std::{fs, thread};
-use tools::handler;
 
-static KILL_RUNNER: AtomicBool = AtomicBool::new(false);
-const CONTROL_SOCKET_PATH: &str = "/tmp/vpn-control.sock";
+// Leaves room for OpenVPN to be taken down
+const SHUTDOWN_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+// Profiles are rescanned once the directory was left alone this long, or this long at most
+const PROFILE_QUIET_TIME: Duration = Duration::from_millis(500);
+const PROFILE_MAX_DELAY: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "Usage: vpn_handler [--config PATH] [--check-config]
+
+Options:
+  -c, --config PATH   Config file (default $VPN_HANDLER_CONFIG or /etc/vpn_handler/config.toml)
+  --check-config      Validate the configuration, print it and exit
+  -h, --help          Show this message";
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    config: Option<PathBuf>,
+    check_config: bool,
+    help: bool,
+}
 
 fn main() {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2)
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    // Nothing can be logged before the settings say where the log goes
+    let settings = match Settings::load(args.config.as_deref()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Failed to load settings: {}", e);
+            std::process::exit(1)
+        }
+    };
+    if args.check_config {
+        std::process::exit(check_config(&settings))
+    }
+    Logger::set_path(&settings.log_path);
+
     let logger = Arc::new(Mutex::new(Logger::new()));
     if let Err(e) = logger.lock().unwrap().update() {
         panic!("Failed to update logger: {:?}", e)
     }
 
-    let notifier = match create_notifier() {
+    let notifier = match create_notifier(&settings.status_socket) {
         Ok(notifier) => Arc::new(Mutex::new(notifier)),
         Err(e) => {
             let logger = Arc::clone(&logger);
@@ -31,140 +85,252 @@ fn main() {
         }
     };
 
+    if let Some(path) = &settings.loaded_from {
+        let msg = format!("Loaded settings from {}", path.display());
+        logger.lock().unwrap().log(&msg).ok();
+    }
+
+    // A broken history only costs the rotation its memory, the daemon carries on without it
+    let (mut selection, error) = Selection::load(Path::new(&settings.selection_state));
+    if let Some(e) = error {
+        let msg = format!("Failed to read selection history, starting over: {}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+    if let Err(e) = selection.configure(settings.strategy, &settings.weights) {
+        let msg = format!("Failed to configure selection strategy: {}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+
+    let (mut quarantine, error) = Quarantine::load(Path::new(&settings.quarantine_state));
+    if let Some(e) = error {
+        let msg = format!("Failed to read quarantine, starting over: {}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+    quarantine.configure(settings.cool_down, settings.max_cool_down);
+
+    // A missing profile directory is not fatal, it can be fixed and picked up with a reload
+    let profiles = config::File::new(&settings.profile_dir, &settings.auth_file)
+        .with_selection(selection)
+        .with_quarantine(quarantine)
+        .with_limits(settings.scan_limits())
+        .with_credentials(settings.credentials.clone());
+    if let Err(e) = profiles.init() {
+        let msg = format!("Failed to scan profiles: {}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+    // Left out profiles are listed in full by `profiles lint`
+    for report in profiles
+        .get_lint()
+        .iter()
+        .filter(|report| !report.is_valid())
+    {
+        let reason = report
+            .findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .map(|finding| finding.message.as_str())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let msg = format!("Skipped invalid profile {}: {}", report.path, reason);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+
+    let daemon = Arc::new(Daemon::new(
+        Arc::clone(&logger),
+        Arc::clone(&notifier),
+        settings.clone(),
+        Arc::new(profiles),
+    ));
+
+    // Under socket activation systemd owns the socket file, otherwise it is ours to create
+    let (listener, activated) = match systemd::take_listener() {
+        Ok(Some(listener)) => {
+            daemon.log("Using control socket passed in by systemd");
+            (listener, true)
+        }
+        Ok(None) => match bind_control_socket(&settings.control_socket, settings.socket_mode) {
+            Ok(listener) => (listener, false),
+            Err(e) => {
+                let msg = format!("Failed to bind control socket: {}", e);
+                daemon.log(&msg);
+                panic!("{}", msg)
+            }
+        },
+        Err(e) => {
+            let msg = format!("Failed to use socket from systemd: {}", e);
+            daemon.log(&msg);
+            panic!("{}", msg)
+        }
+    };
+
+    let shutdown = Arc::new(Shutdown::new());
+    let wake_path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(|path| path.to_path_buf()));
+    let wake_accept = move || {
+        // accept() only returns for a connection, so make one
+        if let Some(path) = &wake_path {
+            UnixStream::connect(path).ok();
+        }
+    };
+    if let Err(e) = shutdown::watch_signals(Arc::clone(&shutdown), SHUTDOWN_SIGNALS, wake_accept) {
+        let msg = format!("Failed to install signal handlers: {}", e);
+        daemon.log(&msg);
+        panic!("{}", msg)
+    }
+
+    if let Err(e) = watch_reload_signal(Arc::clone(&daemon)) {
+        daemon.log(&format!("Failed to install SIGHUP handler: {}", e));
+    }
+    if settings.watch_profiles
+        && let Err(e) = watch_profiles(Arc::clone(&daemon), &settings.profile_dir)
+    {
+        daemon.log(&format!(
+            "Failed to watch {}, changes need a reload: {}",
+            settings.profile_dir, e
+        ));
+    }
+
     let update_logger = Arc::clone(&logger);
     let update_notifier = Arc::clone(&notifier);
+    let update_events = Arc::clone(daemon.events());
+    let update_shutdown = Arc::clone(&shutdown);
     let update_thread = thread::spawn(move || {
-        check_for_updates(update_logger, update_notifier);
+        check_for_updates(
+            update_logger,
+            update_notifier,
+            update_events,
+            update_shutdown,
+        );
     });
 
-    let mut process: Option<JoinHandle<()>> = None;
-    fs::remove_file(CONTROL_SOCKET_PATH).ok(); // Remove existing socket
-
-    let listener = UnixListener::bind(CONTROL_SOCKET_PATH).expect("Failed to bind socket");
+    let service = SystemdNotifier::from_env();
+    service.spawn_status_updates(daemon.events().subscribe());
+    let watched = Arc::clone(&daemon);
+    service.spawn_watchdog(move || watched.is_alive());
+    if let Err(e) = service.ready("Idle, not listening to the switch") {
+        daemon.log(&format!("Failed to notify systemd: {}", e));
+    }
 
     println!("VPN Control Daemon listening...");
 
     for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        let mut buffer = [0; 7];
-        match stream.read(&mut buffer) {
-            Ok(bytes_read) => {
-                let command = String::from_utf8_lossy(&buffer[..bytes_read])
-                    .trim()
-                    .to_string();
-
-                println!("Received command: {}!", command);
-
-                match command.as_str() {
-                    "status" => {
-                        write_to_stream(
-                            &mut stream,
-                            if process.is_some() {
-                                "Daemon is running"
-                            } else {
-                                "Daemon is not running"
-                            },
-                            &logger,
-                        );
-                    }
-                    "start" => match &process {
-                        Some(_) => {
-                            write_to_stream(&mut stream, "Daemon is already running", &logger);
-                        }
-                        None => {
-                            let notifier = Arc::clone(&notifier);
-                            let closure_logger = Arc::clone(&logger);
-                            process = Some(thread::spawn(move || {
-                                match runner(&closure_logger, &notifier) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        let msg = format!("Runner encountered error: {:?}", e);
-                                        closure_logger.lock().unwrap().log(&msg).unwrap();
-                                        panic!("{}", msg);
-                                    }
-                                }
-                                KILL_RUNNER.store(false, Ordering::Relaxed);
-                            }));
-                            let msg = "Daemon started".to_string();
-                            if let Err(_) = logger.lock().unwrap().log(&msg) {
-                                continue;
-                            }
-                            write_to_stream(&mut stream, &msg, &logger);
-                        }
-                    },
-                    "stop" => {
-                        KILL_RUNNER.store(true, Ordering::Relaxed);
-
-                        if let Some(handle) = process.take() {
-                            write_to_stream(&mut stream, "Killing VPN if needed...", &logger);
-
-                            let result = handle.join();
-
-                            write_to_stream(
-                                &mut stream,
-                                "Stopped listening to Arduino...",
-                                &logger,
-                            );
-
-                            if let Err(e) = result {
-                                write_to_stream(
-                                    &mut stream,
-                                    &format!(
-                                        "Process threw error when terminating...\nThrown error: {:?}",
-                                        e
-                                    ),
-                                    &logger,
-                                );
-                            }
-
-                            stream.flush().unwrap();
-                            if let Err(_) =
-                                logger.lock().unwrap().log(&"Stopped listening".to_string())
-                            {
-                                continue;
-                            }
-                        }
-                    }
-                    _ => {
-                        write_to_stream(&mut stream, "Received invalid command!", &logger);
-                    }
-                }
+        if shutdown.is_requested() {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                // Every client gets its own thread so a slow or stuck one cannot hold up the rest
+                let daemon = Arc::clone(&daemon);
+                thread::spawn(move || control::serve(stream, daemon));
             }
             Err(e) => {
-                write_to_stream(
-                    &mut stream,
-                    format!("Error reading from socket: {:?}", e).as_str(),
-                    &logger,
-                );
-                if let Err(_) = logger
+                logger
                     .lock()
                     .unwrap()
-                    .log(&format!("Error reading from socket: {:?}", e))
-                {
-                    continue;
-                }
+                    .log(&format!("Failed to accept connection: {:?}", e))
+                    .ok();
             }
         }
     }
+
+    daemon.log(&format!(
+        "Received {}, shutting down",
+        shutdown.get_signal_name()
+    ));
+    service.stopping("Shutting down").ok();
+    let mut clean = shut_down(&daemon, &settings, activated);
+
     if let Err(e) = update_thread.join() {
-        logger
-            .lock()
-            .unwrap()
-            .log(&format!("Failed to join update thread: {:?}", e))
-            .unwrap();
+        daemon.log(&format!("Failed to join update thread: {:?}", e));
+        clean = false;
+    }
+
+    if clean {
+        daemon.log("Daemon shut down cleanly");
+        std::process::exit(0)
+    } else {
+        daemon.log("Daemon shut down with errors");
+        std::process::exit(1)
     }
 }
 
-fn check_for_updates(logger: Arc<Mutex<Logger>>, notifier: Arc<Mutex<Notifier>>) {
-    // Every hour check
-    loop {
-        thread::sleep(Duration::from_secs(3600));
+// Stops the runner (which takes OpenVPN down with it) and removes the sockets, true when all went well
+fn shut_down(daemon: &Arc<Daemon>, settings: &Settings, activated: bool) -> bool {
+    let mut clean = true;
+
+    if daemon.stop().is_ok() && daemon.wait_for_stop(SHUTDOWN_STOP_TIMEOUT) != RunnerState::Stopped
+    {
+        daemon.log(&format!(
+            "Runner did not stop within {} seconds, OpenVPN may still be running",
+            SHUTDOWN_STOP_TIMEOUT.as_secs()
+        ));
+        clean = false;
+    }
+
+    // A socket handed over by systemd is systemd's to clean up
+    if !activated && let Err(e) = fs::remove_file(&settings.control_socket) {
+        daemon.log(&format!("Failed to remove control socket: {}", e));
+        clean = false;
+    }
+    // The notifier owns the status socket, it is only tidied up when the notifier is gone
+    remove_stale_socket(&settings.status_socket).ok();
+
+    clean
+}
+
+fn watch_reload_signal(daemon: Arc<Daemon>) -> Result<(), Error> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            daemon.log("Received SIGHUP, reloading");
+            if let Err(e) = daemon.reload() {
+                daemon.log(&format!(
+                    "Reload failed, keeping the old configuration: {}",
+                    e.message
+                ));
+            }
+        }
+    });
+    Ok(())
+}
+
+// Rescans once a burst of changes has settled, an unpacked bundle is a single rescan
+fn watch_profiles(daemon: Arc<Daemon>, dir: &str) -> Result<(), Error> {
+    let mut watcher = Watcher::new(Path::new(dir))?;
+    thread::Builder::new()
+        .name("profile-watcher".to_string())
+        .spawn(move || {
+            loop {
+                if let Err(e) = watcher.wait_for_changes(PROFILE_QUIET_TIME, PROFILE_MAX_DELAY) {
+                    daemon.log(&format!("Stopped watching profiles: {}", e));
+                    break;
+                }
+                daemon.rescan();
+            }
+        })?;
+    Ok(())
+}
+
+fn check_for_updates(
+    logger: Arc<Mutex<Logger>>,
+    notifier: Arc<Mutex<Notifier>>,
+    events: Arc<EventBus>,
+    shutdown: Arc<Shutdown>,
+) {
+    // Every hour check, until the daemon shuts down
+    while !shutdown.wait(Duration::from_secs(3600)) {
         {
             let mut logger = logger.lock().unwrap();
             match logger.update() {
-                Ok(_) => {}
+                Ok(true) => events.publish(Event::LogRotated),
+                Ok(false) => {}
                 Err(e) => {
                     let msg = format!("Failed to update logger: {:?}", e);
+                    events.publish(Event::Error {
+                        message: msg.to_string(),
+                    });
                     if let Err(_) = logger.log(&msg) {
                         continue;
                     }
@@ -178,11 +344,46 @@ fn check_for_updates(logger: Arc<Mutex<Logger>>, notifier: Arc<Mutex<Notifier>>)
     }
 }
 
-fn create_notifier() -> Result<Notifier, Error> {
+fn bind_control_socket(path: &str, mode: u32) -> Result<UnixListener, Error> {
+    remove_stale_socket(path)?;
+
+    // Create the socket owner-only so there is no window where anyone can connect, then open it up
+    let old_mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old_mask) };
+
+    let listener = listener?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+// Only a socket nobody answers on is removed, a live daemon or an unrelated file is left alone
+fn remove_stale_socket(path: &str) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path),
+        ));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("Something is still listening on {}", path),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+fn create_notifier(path: &str) -> Result<Notifier, Error> {
     let mut attempt = 0;
 
     while attempt < 10 {
-        match Notifier::new() {
+        match Notifier::new(path) {
             Ok(success) => return Ok(success),
             Err(_) => {
                 thread::sleep(Duration::from_millis(250));
@@ -197,91 +398,174 @@ fn create_notifier() -> Result<Notifier, Error> {
     ))
 }
 
-fn write_to_stream(stream: &mut UnixStream, message: &str, logger: &Arc<Mutex<Logger>>) {
-    let mut attempt = 0;
-    while attempt <= 5 {
-        match writeln!(stream, "{}\n", message) {
-            Ok(_) => {
-                break;
-            }
-            Err(_) if attempt < 5 => {
-                thread::sleep(Duration::from_millis(50)); // Small delay before retry
-                attempt += 1;
-                continue;
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(path) => parsed.config = Some(PathBuf::from(path)),
+                None => return Err(format!("{} needs a path", arg)),
+            },
+            "--check-config" => parsed.check_config = true,
+            "-h" | "--help" => parsed.help = true,
+            _ => match arg.strip_prefix("--config=") {
+                Some(path) => parsed.config = Some(PathBuf::from(path)),
+                None => return Err(format!("Unknown argument {:?}", arg)),
+            },
+        }
+    }
+    Ok(parsed)
+}
+
+// Prints the settings in effect and what is wrong with them, returns the exit code
+fn check_config(settings: &Settings) -> i32 {
+    match &settings.loaded_from {
+        Some(path) => println!("Config file:     {}", path.display()),
+        None => println!("Config file:     none ({} not found)", DEFAULT_CONFIG_PATH),
+    }
+    let serial_present = fs::metadata(&settings.serial_port).is_ok();
+    let rows = [
+        (
+            "Profiles",
+            format!(
+                "{}{}",
+                settings.profile_dir,
+                if settings.watch_profiles {
+                    " (watched for changes)"
+                } else {
+                    ""
+                }
+            ),
+        ),
+        ("Auth file", settings.auth_file.clone()),
+        ("Credentials", {
+            let rules: Vec<String> = settings
+                .credentials
+                .iter()
+                .map(|rule| rule.to_string())
+                .collect();
+            if rules.is_empty() {
+                "auth file for every profile".to_string()
+            } else {
+                format!("{}, else the auth file", rules.join("; "))
             }
-            Err(e) => {
-                let msg = format!("Failed to write to stream: {:?}", e);
-                logger.lock().unwrap().log(&msg).unwrap();
+        }),
+        (
+            "Serial port",
+            format!(
+                "{} at {} baud{}",
+                settings.serial_port,
+                settings.baud_rate,
+                if serial_present {
+                    ""
+                } else {
+                    " (not present right now)"
+                }
+            ),
+        ),
+        ("Log file", settings.log_path.clone()),
+        (
+            "Control socket",
+            format!(
+                "{} (mode {:o})",
+                settings.control_socket, settings.socket_mode
+            ),
+        ),
+        ("Status socket", settings.status_socket.clone()),
+        ("Restart", settings.restart.to_string()),
+        (
+            "Waits",
+            format!(
+                "connect {}s, disconnect {}s, switch silence {}s",
+                settings.connect_wait.as_secs(),
+                settings.disconnect_wait.as_secs(),
+                settings.silence_timeout.as_secs()
+            ),
+        ),
+        ("Start attempts", settings.start_attempts.to_string()),
+        ("Backend", settings.backend.get_name().to_string()),
+        ("Reconnect", {
+            let attempts = match settings.reconnect_attempts {
+                0 => "no limit".to_string(),
+                attempts => format!("at most {} in a row", attempts),
+            };
+            let failover = match settings.failover_after {
+                0 => "never fails over".to_string(),
+                failures => format!("another profile after {} failures", failures),
+            };
+            format!(
+                "after {}s up to {}s, {}, {}",
+                settings.reconnect_delay.as_secs(),
+                settings.max_reconnect_delay.as_secs(),
+                attempts,
+                failover
+            )
+        }),
+        ("Strategy", settings.strategy.to_string()),
+        ("Weights", {
+            let weights: Vec<String> = settings
+                .weights
+                .iter()
+                .map(|(pattern, weight)| format!("{}={}", pattern, weight))
+                .collect();
+            if weights.is_empty() {
+                "-".to_string()
+            } else {
+                weights.join(", ")
             }
+        }),
+        ("History", settings.selection_state.clone()),
+        (
+            "Quarantine",
+            if settings.cool_down.is_zero() {
+                format!("off ({})", settings.quarantine_state)
+            } else {
+                format!(
+                    "{}s doubling up to {}s ({})",
+                    settings.cool_down.as_secs(),
+                    settings.max_cool_down.as_secs(),
+                    settings.quarantine_state
+                )
+            },
+        ),
+    ];
+    for (label, value) in rows {
+        println!("{:<16} {}", format!("{}:", label), value);
+    }
+
+    let problems = settings.check();
+    if problems.is_empty() {
+        println!("Configuration OK");
+        0
+    } else {
+        for problem in &problems {
+            eprintln!("Problem: {}", problem);
         }
+        1
     }
 }
 
-fn runner(
-    logger: &Arc<Mutex<Logger>>,
-    notifier: &Arc<Mutex<Notifier>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let port_name = "/dev/ttyACM0";
-    let settings = serialport::new(port_name, 57600).timeout(Duration::from_secs(10));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut port = settings.open()?;
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
 
-    let mut handler = handler::Handler::new()?;
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(args("")).unwrap(), Args::default());
 
-    let mut previous_command: u8 = 0;
-    loop {
-        if KILL_RUNNER.load(Ordering::Relaxed) {
-            // Check KILL flag safely
-            return match handler.stop() {
-                Ok(_) => Ok(()),
-                Err(e) => Err(Box::new(e)),
-            };
-        }
-        let mut buffer = [0; 9];
-        match port.read(&mut buffer) {
-            Ok(bytes_read) if bytes_read > 0 => {
-                let message = String::from_utf8_lossy(&buffer[0..bytes_read])
-                    .trim()
-                    .to_string();
-
-                match message.as_str() {
-                    "Turn On" => {
-                        if previous_command != 255 {
-                            println!("Turning VPN On");
-                            previous_command = 255;
-                            handler.start()?;
-                            thread::sleep(Duration::from_secs(10));
-                            {
-                                let mut notifier = notifier.lock().unwrap();
-                                notifier.send_message("STATUS Connected")?;
-                                let msg = "VPN STATUS CHANGE: Connected".to_string();
-                                if let Err(_) = logger.lock().unwrap().log(&msg) {
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                    "Turn Off" => {
-                        if previous_command != 0 {
-                            println!("Turning VPN Off");
-                            previous_command = 0;
-                            handler.stop()?;
-                            thread::sleep(Duration::from_secs(5));
-                            {
-                                let mut notifier = notifier.lock().unwrap();
-                                notifier.send_message("STATUS Disconnected")?;
-                                let msg = "VPN STATUS CHANGE: Disconnected".to_string();
-                                if let Err(_) = logger.lock().unwrap().log(&msg) {
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            Err(e) => return Err(Box::new(e)),
-            _ => {}
-        }
+        let parsed = parse_args(args("--config /etc/vpn.toml --check-config")).unwrap();
+        assert_eq!(parsed.config, Some(PathBuf::from("/etc/vpn.toml")));
+        assert!(parsed.check_config);
+
+        let parsed = parse_args(args("--config=/tmp/vpn.toml")).unwrap();
+        assert_eq!(parsed.config, Some(PathBuf::from("/tmp/vpn.toml")));
+
+        assert!(parse_args(args("--config")).is_err());
+        assert!(parse_args(args("--verbose")).is_err());
     }
 }
@@ -0,0 +1,186 @@
+use crate::tools::settings::Settings;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+// Not exported by libc, the value is 59 everywhere except sparc
+#[cfg(not(target_arch = "sparc64"))]
+const SO_PEERGROUPS: libc::c_int = 59;
+#[cfg(target_arch = "sparc64")]
+const SO_PEERGROUPS: libc::c_int = 0x3d;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PeerCredentials {
+    pub(crate) pid: i32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) groups: Vec<u32>,
+}
+
+impl std::fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "pid {} uid {} gid {}", self.pid, self.uid, self.gid)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Access {
+    Denied,
+    ReadOnly,
+    Full,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AccessPolicy {
+    owner_uid: u32,
+    allowed_uids: Vec<u32>,
+    allowed_gids: Vec<u32>,
+    readonly_uids: Vec<u32>,
+    readonly_gids: Vec<u32>,
+}
+
+impl AccessPolicy {
+    pub(crate) fn new(settings: &Settings) -> Self {
+        Self {
+            owner_uid: unsafe { libc::geteuid() },
+            allowed_uids: settings.allowed_uids.clone(),
+            allowed_gids: settings.allowed_gids.clone(),
+            readonly_uids: settings.readonly_uids.clone(),
+            readonly_gids: settings.readonly_gids.clone(),
+        }
+    }
+
+    pub(crate) fn check(&self, peer: &PeerCredentials) -> Access {
+        if peer.uid == 0 || peer.uid == self.owner_uid || self.allowed_uids.contains(&peer.uid) {
+            return Access::Full;
+        }
+
+        let groups = || std::iter::once(&peer.gid).chain(&peer.groups);
+        if groups().any(|gid| self.allowed_gids.contains(gid)) {
+            Access::Full
+        } else if self.readonly_uids.contains(&peer.uid)
+            || groups().any(|gid| self.readonly_gids.contains(gid))
+        {
+            Access::ReadOnly
+        } else {
+            Access::Denied
+        }
+    }
+}
+
+pub(crate) fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, io::Error> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCredentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+        groups: peer_groups(stream),
+    })
+}
+
+// SO_PEERCRED only carries the primary group. The supplementary ones come from the socket as
+// well, the kernel captured them at connect time so they cannot belong to a recycled pid.
+// Kernels without SO_PEERGROUPS leave only the primary group to match against.
+fn peer_groups(stream: &UnixStream) -> Vec<u32> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut len = (groups.len() * std::mem::size_of::<libc::gid_t>()) as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                SO_PEERGROUPS,
+                groups.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        let count = len as usize / std::mem::size_of::<libc::gid_t>();
+        if result == 0 {
+            groups.truncate(count);
+            return groups;
+        }
+        // On ERANGE the kernel reports the size it needs
+        if io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) && count > groups.len() {
+            groups.resize(count, 0);
+        } else {
+            return Vec::new();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed_gids: Vec<u32>, readonly_uids: Vec<u32>) -> AccessPolicy {
+        AccessPolicy {
+            owner_uid: 1000,
+            allowed_uids: vec![1001],
+            allowed_gids,
+            readonly_uids,
+            readonly_gids: Vec::new(),
+        }
+    }
+
+    fn peer(uid: u32, gid: u32) -> PeerCredentials {
+        PeerCredentials {
+            pid: 0,
+            uid,
+            gid,
+            groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_peer_credentials_of_socket_pair() {
+        let (first, _second) = UnixStream::pair().unwrap();
+        let result = peer_credentials(&first);
+        assert!(result.is_ok(), "getsockopt failed: {}", result.unwrap_err());
+        let cred = result.unwrap();
+        assert_eq!(cred.uid, unsafe { libc::geteuid() });
+        assert_eq!(cred.pid as u32, std::process::id());
+
+        // The supplementary groups come from the socket, not from /proc/<pid>
+        let mut own = vec![0 as libc::gid_t; 256];
+        let count = unsafe { libc::getgroups(own.len() as libc::c_int, own.as_mut_ptr()) };
+        own.truncate(count.max(0) as usize);
+        let mut groups = cred.groups.clone();
+        groups.sort_unstable();
+        own.sort_unstable();
+        assert_eq!(groups, own);
+    }
+
+    #[test]
+    fn test_policy_levels() {
+        let policy = policy(vec![2000], vec![1002]);
+        assert_eq!(policy.check(&peer(0, 0)), Access::Full);
+        assert_eq!(policy.check(&peer(1000, 1000)), Access::Full);
+        assert_eq!(policy.check(&peer(1001, 1001)), Access::Full);
+        assert_eq!(policy.check(&peer(1003, 2000)), Access::Full);
+        assert_eq!(policy.check(&peer(1002, 1002)), Access::ReadOnly);
+        assert_eq!(policy.check(&peer(1004, 1004)), Access::Denied);
+
+        let mut member = peer(1005, 1005);
+        member.groups = vec![1005, 2000];
+        assert_eq!(policy.check(&member), Access::Full);
+    }
+}
@@ -0,0 +1,868 @@
+use crate::tools::credentials::{self, HANDOFF_TIMEOUT, Secret};
+use crate::tools::logger::Logger;
+use crate::tools::management::Management;
+use crate::tools::output::{self, Failure};
+use crate::tools::profile::ProfileKind;
+use crate::tools::severity::Severity;
+use crate::tools::supervisor::CancelToken;
+use serde::Serialize;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// What wg-quick calls the tunnel, it takes the name from the config file it is handed
+const WIREGUARD_INTERFACE: &str = "vpn_handler";
+// How long OpenVPN gets to open its management socket after being spawned
+const MANAGEMENT_TIMEOUT: Duration = Duration::from_secs(10);
+// How often a process being stopped is checked on
+const EXIT_POLL: Duration = Duration::from_millis(50);
+// How long OpenVPN's last lines get to reach us once it failed, they race its management reports
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
+
+/*
+    Brings a tunnel up and down for the handler. One backend value serves one tunnel at a time,
+    `health` is asked after connecting and whenever the runner wants to know whether the tunnel
+    is still there.
+*/
+pub(crate) trait VpnBackend: Send {
+    fn start(&mut self, profile: &str, secret: Option<Secret>) -> Result<(), io::Error>;
+    // Gives the tunnel `timeout` to go down cleanly before forcing it
+    fn stop(&mut self, timeout: Duration) -> Result<Teardown, io::Error>;
+    fn status(&self) -> BackendStatus;
+    fn health(&mut self) -> Health;
+
+    /*
+        Returns once the tunnel carries traffic, fails with the reason it did not come up or with
+        Interrupted when the token is cancelled. Backends that cannot tell more than `health`
+        settle for it.
+    */
+    fn wait_connected(&mut self, _: Duration, _: &CancelToken) -> Result<(), io::Error> {
+        match self.health() {
+            Health::Up => Ok(()),
+            Health::Down(reason) => Err(io::Error::other(reason)),
+        }
+    }
+
+    // Only asked when true, so no password manager is woken for a tunnel that cannot use it
+    fn wants_credentials(&self) -> bool {
+        false
+    }
+
+    // The last known failure the tunnel reported since it was started or last connected
+    fn failure(&self) -> Option<Failure> {
+        None
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct BackendStatus {
+    pub(crate) backend: &'static str,
+    pub(crate) pid: Option<u32>,
+    pub(crate) interface: Option<String>,
+    // What the backend last reported about the connection, e.g. OpenVPN's `CONNECTED`
+    pub(crate) state: Option<String>,
+}
+
+// How a tunnel went down, reported by the runner
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Teardown {
+    // How the process ended, None for backends without one
+    pub(crate) exit: Option<ExitStatus>,
+    // Killed after it did not go down within the timeout
+    pub(crate) forced: bool,
+}
+
+impl std::fmt::Display for Teardown {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.exit, self.forced) {
+            (Some(exit), true) => write!(f, "killed after it did not stop in time ({})", exit),
+            (Some(exit), false) => write!(f, "{}", exit),
+            (None, _) => write!(f, "stopped"),
+        }
+    }
+}
+
+/*
+    Asks `child` to stop with `ask` (SIGTERM when that fails), waits up to `timeout` for it to
+    exit and kills it otherwise. Always reaps it, so no zombie is left behind.
+*/
+pub(crate) fn terminate(
+    child: &mut Child,
+    timeout: Duration,
+    ask: impl FnOnce() -> Result<(), io::Error>,
+) -> Result<Teardown, io::Error> {
+    if let Some(exit) = child.try_wait()? {
+        return Ok(Teardown {
+            exit: Some(exit),
+            forced: false,
+        });
+    }
+    if ask().is_err() && unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if let Some(exit) = child.try_wait()? {
+            return Ok(Teardown {
+                exit: Some(exit),
+                forced: false,
+            });
+        }
+        std::thread::sleep(EXIT_POLL.min(timeout.saturating_sub(started.elapsed())));
+    }
+    child.kill()?;
+    Ok(Teardown {
+        exit: Some(child.wait()?),
+        forced: true,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Health {
+    Up,
+    // Why the tunnel is gone
+    Down(String),
+}
+
+// Which backend brings profiles up, `auto` goes by what the profile is
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum BackendKind {
+    #[default]
+    Auto,
+    OpenVpn,
+    WireGuard,
+    // In process, for running the daemon without root or a real tunnel
+    Mock,
+}
+
+impl BackendKind {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "auto" => Some(Self::Auto),
+            "openvpn" => Some(Self::OpenVpn),
+            "wireguard" | "wg-quick" => Some(Self::WireGuard),
+            "mock" => Some(Self::Mock),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_name(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::OpenVpn => "openvpn",
+            Self::WireGuard => "wireguard",
+            Self::Mock => "mock",
+        }
+    }
+}
+
+// Hands out a backend per tunnel, the mock's state is shared so tests can steer it
+#[derive(Default)]
+pub(crate) struct Backends {
+    kind: BackendKind,
+    mock: MockBackend,
+    // Where OpenVPN's output goes, stdout without one
+    logger: Option<Arc<Mutex<Logger>>>,
+    // What a dropped OpenVPN gets to go down cleanly, the runner stops it with the same
+    stop_timeout: Duration,
+}
+
+impl Backends {
+    pub(crate) fn new(kind: BackendKind) -> Self {
+        Self {
+            kind,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn with_logger(mut self, logger: Arc<Mutex<Logger>>) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    pub(crate) fn with_stop_timeout(mut self, timeout: Duration) -> Self {
+        self.stop_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn create(&self, profile: ProfileKind) -> Box<dyn VpnBackend> {
+        match (self.kind, profile) {
+            (BackendKind::Mock, _) => Box::new(self.mock.clone()),
+            (BackendKind::OpenVpn, _) | (BackendKind::Auto, ProfileKind::OpenVpn) => {
+                Box::new(OpenVpn::new(self.logger.clone(), self.stop_timeout))
+            }
+            (BackendKind::WireGuard, _) | (BackendKind::Auto, ProfileKind::WireGuard) => {
+                Box::new(WireGuard::default())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn mock(&self) -> &MockBackend {
+        &self.mock
+    }
+}
+
+/*
+    Runs OpenVPN with its management interface on a socket in a private temporary directory.
+    `--management-hold` keeps it from connecting until the management client is listening, so
+    not a single state change is missed. Its stdout and stderr are read into the log, which also
+    picks out the failures the management interface does not name.
+*/
+#[derive(Default)]
+pub(crate) struct OpenVpn {
+    child: Option<Child>,
+    management: Option<Management>,
+    dir: Option<tempfile::TempDir>,
+    logger: Option<Arc<Mutex<Logger>>>,
+    // The threads reading its output, they end when OpenVPN does
+    output: Vec<JoinHandle<()>>,
+    failure: Arc<Mutex<Option<Failure>>>,
+    stop_timeout: Duration,
+}
+
+impl OpenVpn {
+    pub(crate) fn new(logger: Option<Arc<Mutex<Logger>>>, stop_timeout: Duration) -> Self {
+        let mut openvpn = Self::default();
+        openvpn.logger = logger;
+        openvpn.stop_timeout = stop_timeout;
+        openvpn
+    }
+
+    // Reads both pipes of a freshly spawned OpenVPN
+    fn capture_output(&mut self, child: &mut Child) -> Result<(), io::Error> {
+        *self.failure.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        let mut output = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            output.push(output::capture(
+                stdout,
+                false,
+                Arc::clone(&self.failure),
+                Self::forward(self.logger.clone()),
+            )?);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            output.push(output::capture(
+                stderr,
+                true,
+                Arc::clone(&self.failure),
+                Self::forward(self.logger.clone()),
+            )?);
+        }
+        self.output = output;
+        Ok(())
+    }
+
+    fn forward(logger: Option<Arc<Mutex<Logger>>>) -> impl Fn(Severity, &str) + Send + 'static {
+        move |severity, line| match &logger {
+            Some(logger) => {
+                let msg = format!("OpenVPN: {}", line);
+                logger
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .log_at(severity, &msg)
+                    .ok();
+            }
+            None => println!("OpenVPN {}: {}", severity.get_name(), line),
+        }
+    }
+
+    // Waits a little for the output to be read up to the end, unless a failure was found in it
+    fn settle_output(&self) {
+        let started = Instant::now();
+        while self.failure().is_none()
+            && !self.output.iter().all(JoinHandle::is_finished)
+            && started.elapsed() < OUTPUT_GRACE
+        {
+            std::thread::sleep(EXIT_POLL);
+        }
+    }
+
+    // A failure OpenVPN got over by connecting after all is not its failure any more
+    fn open_management(&self, stream: UnixStream) -> Result<Management, io::Error> {
+        let failure = Arc::clone(&self.failure);
+        Management::new(stream, move || {
+            *failure.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        })
+    }
+
+    // Connects to the management socket as soon as OpenVPN has made it
+    fn connect_management(child: &mut Child, socket: &Path) -> Result<UnixStream, io::Error> {
+        let started = Instant::now();
+        loop {
+            if let Ok(stream) = UnixStream::connect(socket) {
+                return Ok(stream);
+            }
+            if let Some(status) = child.try_wait()? {
+                return Err(io::Error::other(format!(
+                    "OpenVPN exited ({}) before opening its management interface",
+                    status
+                )));
+            }
+            if started.elapsed() > MANAGEMENT_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "OpenVPN did not open its management interface within {}s",
+                        MANAGEMENT_TIMEOUT.as_secs()
+                    ),
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+// Last resort for a runner that went away without stopping the tunnel, it is stopped the same way
+impl Drop for OpenVpn {
+    fn drop(&mut self) {
+        if self.child.is_none() {
+            return;
+        }
+        let msg = match self.stop(self.stop_timeout) {
+            Ok(teardown) => format!("Stopped OpenVPN that was left running: {}", teardown),
+            Err(e) => format!("Failed to stop OpenVPN that was left running: {}", e),
+        };
+        match &self.logger {
+            Some(logger) => {
+                logger
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .log(&msg)
+                    .ok();
+            }
+            None => println!("{}", msg),
+        }
+    }
+}
+
+impl VpnBackend for OpenVpn {
+    fn start(&mut self, profile: &str, secret: Option<Secret>) -> Result<(), io::Error> {
+        if self.child.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "OpenVPN is already running",
+            ));
+        }
+        // Certificates and keys named in the profile are relative to its directory, like the
+        // lint checks them
+        let path = Path::new(profile);
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        let private = tempfile::Builder::new()
+            .prefix("vpn_handler-openvpn-")
+            .tempdir()?;
+        std::fs::set_permissions(private.path(), std::fs::Permissions::from_mode(0o700))?;
+        let socket = private.path().join("management");
+
+        let mut command = Command::new("openvpn");
+        command
+            .arg("--cd")
+            .arg(dir)
+            .arg("--config")
+            .arg(path.file_name().unwrap_or(path.as_os_str()))
+            .arg("--management")
+            .arg(&socket)
+            .arg("unix")
+            .arg("--management-hold")
+            // The log has timestamps of its own
+            .arg("--suppress-timestamps")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(secret) = secret {
+            command
+                .arg("--auth-user-pass")
+                .arg(credentials::hand_off(secret, HANDOFF_TIMEOUT)?);
+        }
+        let mut child = command.spawn()?;
+        let management = match self
+            .capture_output(&mut child)
+            .and_then(|_| Self::connect_management(&mut child, &socket))
+            .and_then(|stream| self.open_management(stream))
+        {
+            Ok(management) => management,
+            Err(e) => {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(e);
+            }
+        };
+        self.child = Some(child);
+        self.management = Some(management);
+        self.dir = Some(private);
+        println!("OpenVPN process started.");
+        Ok(())
+    }
+
+    // Over the management interface when it is there, so OpenVPN takes its routes and DNS along
+    fn stop(&mut self, timeout: Duration) -> Result<Teardown, io::Error> {
+        let Some(mut child) = self.child.take() else {
+            return Ok(Teardown::default());
+        };
+        let mut management = self.management.take();
+        let result = terminate(&mut child, timeout, || match management.as_mut() {
+            Some(management) => management.send("signal SIGTERM"),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        });
+        if result.is_err() {
+            // Kept, so the next stop tries again
+            self.child = Some(child);
+            self.management = management;
+            return result;
+        }
+        self.dir = None;
+        result
+    }
+
+    fn status(&self) -> BackendStatus {
+        BackendStatus {
+            backend: "openvpn",
+            pid: self.child.as_ref().map(|child| child.id()),
+            interface: None,
+            state: self
+                .management
+                .as_ref()
+                .and_then(Management::get_state)
+                .map(|change| change.state.get_name().to_string()),
+        }
+    }
+
+    // Down once OpenVPN has exited on its own, e.g. after failing to authenticate
+    fn health(&mut self) -> Health {
+        match self.child.as_mut().map(|child| child.try_wait()) {
+            Some(Ok(None)) => Health::Up,
+            Some(Ok(Some(status))) => {
+                self.settle_output();
+                match self.failure() {
+                    Some(failure) => {
+                        Health::Down(format!("OpenVPN exited ({}): {}", status, failure))
+                    }
+                    None => Health::Down(format!("OpenVPN exited ({})", status)),
+                }
+            }
+            Some(Err(e)) => Health::Down(format!("Cannot tell whether OpenVPN runs: {}", e)),
+            None => Health::Down("Not started".to_string()),
+        }
+    }
+
+    // A failure recognized in the output says more than the management interface, so it wins
+    fn wait_connected(&mut self, timeout: Duration, token: &CancelToken) -> Result<(), io::Error> {
+        let result = match &self.management {
+            Some(management) => management.wait_connected(timeout, token),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "OpenVPN is not running",
+            )),
+        };
+        match result {
+            Err(e) if e.kind() != io::ErrorKind::Interrupted && self.child.is_some() => {
+                // After a timeout anything worth knowing was printed long ago
+                if e.kind() != io::ErrorKind::TimedOut {
+                    self.settle_output();
+                }
+                match self.failure() {
+                    Some(failure) => Err(io::Error::new(e.kind(), failure)),
+                    None => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn wants_credentials(&self) -> bool {
+        true
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.failure
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/*
+    Runs `wg-quick up` on a copy of the profile named after WIREGUARD_INTERFACE, since wg-quick
+    names the interface after the file and provider file names are rarely valid interface names.
+    The copy lives in a private temporary directory until the tunnel is taken down again.
+*/
+#[derive(Default)]
+pub(crate) struct WireGuard {
+    config: Option<(tempfile::TempDir, PathBuf)>,
+}
+
+impl WireGuard {
+    fn wg_quick(action: &str, config: &Path) -> Result<(), io::Error> {
+        let output = Command::new("wg-quick").arg(action).arg(config).output()?;
+        if output.status.success() {
+            return Ok(());
+        }
+        // wg-quick echoes every command it runs, the reason is on the last line
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(io::Error::other(format!(
+            "wg-quick {} failed ({}): {}",
+            action,
+            output.status,
+            stderr.lines().last().unwrap_or_default().trim()
+        )))
+    }
+}
+
+// Last resort for a runner that went away without stopping the tunnel
+impl Drop for WireGuard {
+    fn drop(&mut self) {
+        if self.stop(Duration::ZERO).is_err() {
+            // The interface is still up, its config has to outlive us to take it down by hand
+            if let Some((dir, _)) = self.config.take() {
+                let _ = dir.keep();
+            }
+        }
+    }
+}
+
+impl VpnBackend for WireGuard {
+    fn start(&mut self, profile: &str, _: Option<Secret>) -> Result<(), io::Error> {
+        if self.config.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "WireGuard tunnel is already up",
+            ));
+        }
+        let dir = tempfile::Builder::new()
+            .prefix("vpn_handler-wg-")
+            .tempdir()?;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+        let config = dir.path().join(format!("{}.conf", WIREGUARD_INTERFACE));
+        std::fs::copy(profile, &config)?;
+        std::fs::set_permissions(&config, std::fs::Permissions::from_mode(0o600))?;
+
+        Self::wg_quick("up", &config)?;
+        self.config = Some((dir, config));
+        Ok(())
+    }
+
+    fn stop(&mut self, _: Duration) -> Result<Teardown, io::Error> {
+        match self.config.take() {
+            Some((dir, config)) => {
+                let result = Self::wg_quick("down", &config);
+                if result.is_err() {
+                    // Kept, so the tunnel can still be taken down by hand
+                    self.config = Some((dir, config));
+                }
+                result.map(|_| Teardown::default())
+            }
+            None => Ok(Teardown::default()),
+        }
+    }
+
+    fn status(&self) -> BackendStatus {
+        BackendStatus {
+            backend: "wireguard",
+            pid: None,
+            interface: self
+                .config
+                .as_ref()
+                .map(|_| WIREGUARD_INTERFACE.to_string()),
+            state: None,
+        }
+    }
+
+    fn health(&mut self) -> Health {
+        if self.config.is_none() {
+            return Health::Down("Not started".to_string());
+        }
+        match Path::new("/sys/class/net")
+            .join(WIREGUARD_INTERFACE)
+            .exists()
+        {
+            true => Health::Up,
+            false => Health::Down(format!("Interface {} is gone", WIREGUARD_INTERFACE)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    up: Option<String>,
+    // Every start fails with this while set
+    failure: Option<String>,
+    dropped: bool,
+    started: Vec<String>,
+    reported: Option<Failure>,
+}
+
+// A tunnel that only exists in memory, clones share it
+#[derive(Clone, Default)]
+pub(crate) struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fail_starts(&self, failure: Option<&str>) {
+        self.lock().failure = failure.map(str::to_string);
+    }
+
+    // As if the server went away, the tunnel reports itself down until the next start
+    #[cfg(test)]
+    pub(crate) fn drop_tunnel(&self) {
+        self.lock().dropped = true;
+    }
+
+    // As if the tunnel had printed a failure, until the next start
+    #[cfg(test)]
+    pub(crate) fn report_failure(&self, failure: Failure) {
+        self.lock().reported = Some(failure);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn started(&self) -> Vec<String> {
+        self.lock().started.clone()
+    }
+}
+
+impl VpnBackend for MockBackend {
+    fn start(&mut self, profile: &str, _: Option<Secret>) -> Result<(), io::Error> {
+        let mut state = self.lock();
+        if let Some(failure) = &state.failure {
+            return Err(io::Error::other(failure.clone()));
+        }
+        if state.up.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Mock tunnel is already up",
+            ));
+        }
+        state.up = Some(profile.to_string());
+        state.dropped = false;
+        state.reported = None;
+        state.started.push(profile.to_string());
+        Ok(())
+    }
+
+    fn stop(&mut self, _: Duration) -> Result<Teardown, io::Error> {
+        self.lock().up = None;
+        Ok(Teardown::default())
+    }
+
+    fn status(&self) -> BackendStatus {
+        BackendStatus {
+            backend: "mock",
+            pid: None,
+            interface: None,
+            state: None,
+        }
+    }
+
+    fn health(&mut self) -> Health {
+        let state = self.lock();
+        match (&state.up, state.dropped) {
+            (Some(_), false) => Health::Up,
+            (Some(_), true) => Health::Down("Mock tunnel dropped".to_string()),
+            (None, _) => Health::Down("Not started".to_string()),
+        }
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.lock().reported.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_picks_backend() {
+        let backends = Backends::new(BackendKind::Auto);
+        assert_eq!(
+            backends.create(ProfileKind::OpenVpn).status().backend,
+            "openvpn"
+        );
+        assert_eq!(
+            backends.create(ProfileKind::WireGuard).status().backend,
+            "wireguard"
+        );
+        let backends = Backends::new(BackendKind::Mock);
+        assert_eq!(
+            backends.create(ProfileKind::WireGuard).status().backend,
+            "mock"
+        );
+
+        assert_eq!(BackendKind::parse("wg-quick"), Some(BackendKind::WireGuard));
+        assert_eq!(BackendKind::parse("ipsec"), None);
+    }
+
+    #[test]
+    fn test_mock() {
+        let backends = Backends::new(BackendKind::Mock);
+        let mut backend = backends.create(ProfileKind::OpenVpn);
+        assert!(matches!(backend.health(), Health::Down(_)));
+
+        backend.start("/vpn/se.ovpn", None).unwrap();
+        assert_eq!(backend.health(), Health::Up);
+        assert!(backend.start("/vpn/us.ovpn", None).is_err());
+        backends.mock().drop_tunnel();
+        assert!(matches!(backend.health(), Health::Down(_)));
+
+        backend.stop(Duration::ZERO).unwrap();
+        backends.mock().fail_starts(Some("no route"));
+        assert_eq!(
+            backend.start("/vpn/us.ovpn", None).unwrap_err().to_string(),
+            "no route"
+        );
+        assert_eq!(backends.mock().started(), vec!["/vpn/se.ovpn"]);
+    }
+
+    #[test]
+    fn test_not_started() {
+        let mut openvpn = OpenVpn::default();
+        assert_eq!(openvpn.stop(Duration::ZERO).unwrap(), Teardown::default());
+        assert_eq!(openvpn.status().pid, None);
+        let mut wireguard = WireGuard::default();
+        assert!(wireguard.stop(Duration::ZERO).is_ok());
+        assert_eq!(wireguard.health(), Health::Down("Not started".to_string()));
+    }
+
+    #[test]
+    fn test_terminate() {
+        // Goes at the first SIGTERM
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let teardown = terminate(&mut child, Duration::from_secs(5), || {
+            Err(io::Error::from(io::ErrorKind::NotConnected))
+        })
+        .unwrap();
+        assert!(!teardown.forced);
+        assert_eq!(teardown.to_string(), "signal: 15 (SIGTERM)");
+
+        // Ignores it and has to be killed, reaped either way
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' TERM; while :; do sleep 0.1; done"])
+            .spawn()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let teardown = terminate(&mut child, Duration::from_millis(300), || Ok(())).unwrap();
+        assert!(teardown.forced);
+        assert_eq!(teardown.exit.unwrap().signal(), Some(libc::SIGKILL));
+        assert!(child.try_wait().unwrap().is_some());
+
+        // Already gone
+        let teardown = terminate(&mut child, Duration::ZERO, || Ok(())).unwrap();
+        assert!(!teardown.forced);
+    }
+
+    #[test]
+    fn test_stop_over_management() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let mut openvpn = OpenVpn::default();
+        openvpn.child = Some(Command::new("sleep").arg("30").spawn().unwrap());
+        openvpn.management = Some(openvpn.open_management(ours).unwrap());
+        // `sleep` does not listen, so asking nicely ends in a kill
+        let teardown = openvpn.stop(Duration::from_millis(200)).unwrap();
+        assert!(teardown.forced);
+        assert!(openvpn.child.is_none());
+
+        let mut sent = String::new();
+        theirs.read_to_string(&mut sent).unwrap();
+        assert_eq!(sent, "state on\nhold release\nsignal SIGTERM\n");
+    }
+
+    #[test]
+    fn test_failure_from_output() {
+        let mut child = Command::new("sh")
+            .args([
+                "-c",
+                "echo 'AUTH: Received control message: AUTH_FAILED'; echo 'Exiting' >&2; exit 1",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut openvpn = OpenVpn::default();
+        openvpn.capture_output(&mut child).unwrap();
+        openvpn.child = Some(child);
+        openvpn.management = Some(openvpn.open_management(ours).unwrap());
+
+        // OpenVPN hanging up on its management interface is all that is said there
+        drop(theirs);
+        let error = openvpn
+            .wait_connected(Duration::from_secs(5), &CancelToken::new())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The server rejected the credentials (AUTH_FAILED)"
+        );
+        let failure = error.get_ref().unwrap().downcast_ref::<Failure>();
+        assert_eq!(failure, Some(&Failure::Auth));
+
+        while openvpn
+            .child
+            .as_mut()
+            .unwrap()
+            .try_wait()
+            .unwrap()
+            .is_none()
+        {
+            std::thread::sleep(EXIT_POLL);
+        }
+        assert_eq!(
+            openvpn.health(),
+            Health::Down(
+                "OpenVPN exited (exit status: 1): The server rejected the credentials (AUTH_FAILED)"
+                    .to_string()
+            )
+        );
+        assert_eq!(openvpn.failure(), Some(Failure::Auth));
+    }
+
+    #[test]
+    fn test_failure_got_over() {
+        let mut child = Command::new("sh")
+            .args([
+                "-c",
+                "echo 'AUTH: Received control message: AUTH_FAILED'; sleep 30",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let mut openvpn = OpenVpn::default();
+        openvpn.capture_output(&mut child).unwrap();
+        openvpn.child = Some(child);
+        openvpn.management = Some(openvpn.open_management(ours).unwrap());
+        let started = Instant::now();
+        while openvpn.failure().is_none() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(EXIT_POLL);
+        }
+        assert_eq!(openvpn.failure(), Some(Failure::Auth));
+
+        // Retried and got in, so a later exit is not down to the credentials
+        theirs
+            .write_all(b">STATE:1714557602,CONNECTED,SUCCESS,10.8.0.2,185.65.134.1,1194,,\n")
+            .unwrap();
+        openvpn
+            .wait_connected(Duration::from_secs(5), &CancelToken::new())
+            .unwrap();
+        assert_eq!(openvpn.failure(), None);
+        theirs
+            .write_all(b">STATE:1714557900,EXITING,SIGTERM,,,,,\n")
+            .unwrap();
+        while openvpn.status().state.as_deref() != Some("EXITING") {
+            std::thread::sleep(EXIT_POLL);
+        }
+        assert_eq!(openvpn.failure(), None);
+        openvpn.stop(Duration::ZERO).unwrap();
+    }
+}
@@ -1,72 +1,402 @@
-use rand::Rng;
+use crate::tools::credentials::{self, Rule, Secret};
+use crate::tools::lint::{self, Linter, Report};
+use crate::tools::profile::{Profile, ProfileKind};
+use crate::tools::quarantine::{self, Quarantine};
+use crate::tools::scan::{self, ScanLimits};
+use crate::tools::selection::{Selection, Strategy};
+use glob::{MatchOptions, Pattern};
+use std::collections::HashSet;
 use std::path::Path;
 use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ProfileChanges {
+    pub(crate) count: usize,
+    pub(crate) added: Vec<String>,
+    pub(crate) removed: Vec<String>,
+    pub(crate) invalid: usize,
+}
+
+// A scan that has been read, linted and parsed but not swapped in yet
+pub(crate) struct Scanned {
+    profiles: Vec<Profile>,
+    reports: Vec<Report>,
+}
 
 pub(crate) struct File {
-    files: Mutex<Vec<String>>,
+    files: Mutex<Vec<Profile>>,
+    lint: Mutex<Vec<Report>>,
     auth: String,
     main_dir: String,
+    selection: Mutex<Selection>,
+    quarantine: Mutex<Quarantine>,
+    limits: Mutex<ScanLimits>,
+    credentials: Mutex<Vec<Rule>>,
+    // Held from a scan until it is applied, so an older scan never replaces a newer one
+    scanning: Mutex<()>,
 }
 
 impl File {
-    pub(crate) fn new() -> Self {
-        let main_dir = "/home/kwunch/VPN".to_string();
-        let auth = "/home/kwunch/VPN/auth.txt".to_string();
+    pub(crate) fn new(main_dir: &str, auth: &str) -> Self {
         Self {
             files: Mutex::new(Vec::new()),
-            auth,
-            main_dir,
+            lint: Mutex::new(Vec::new()),
+            auth: auth.to_string(),
+            main_dir: main_dir.to_string(),
+            selection: Mutex::new(Selection::default()),
+            quarantine: Mutex::new(Quarantine::default()),
+            limits: Mutex::new(ScanLimits::default()),
+            credentials: Mutex::new(Vec::new()),
+            scanning: Mutex::new(()),
         }
     }
 
+    pub(crate) fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = Mutex::new(selection);
+        self
+    }
+
+    pub(crate) fn with_quarantine(mut self, quarantine: Quarantine) -> Self {
+        self.quarantine = Mutex::new(quarantine);
+        self
+    }
+
+    pub(crate) fn with_limits(mut self, limits: ScanLimits) -> Self {
+        self.limits = Mutex::new(limits);
+        self
+    }
+
+    pub(crate) fn with_credentials(self, rules: Vec<Rule>) -> Self {
+        self.configure_credentials(rules);
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_dir(main_dir: &str) -> Self {
+        Self::new(main_dir, &format!("{}/auth.txt", main_dir))
+    }
+
     pub(crate) fn init(&self) -> Result<(), std::io::Error> {
-        let main_dir = Path::new(&self.main_dir);
-        self.recurse_dir(main_dir)?;
+        self.reload()?;
         Ok(())
     }
 
-    pub(crate) fn get_auth(&self) -> &String {
-        &self.auth
+    // Scans with the current limits and swaps the result in, a failed scan leaves the old list
+    pub(crate) fn reload(&self) -> Result<ProfileChanges, std::io::Error> {
+        let _scanning = self.lock_scan();
+        let limits = self
+            .limits
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let scanned = self.scan(&limits)?;
+        Ok(self.apply_scan(scanned))
     }
 
-    pub(crate) fn get_random_file_path(&self) -> Result<String, std::io::Error> {
-        {
-            let mut file = self.lock_file()?;
-            let ridx = rand::rng().random_range(0..file.len());
-            let file = &mut file[ridx];
-            Ok(file.to_string())
-        }
+    // For a caller that scans and applies on its own, held from `scan` through `apply_scan`
+    pub(crate) fn lock_scan(&self) -> MutexGuard<'_, ()> {
+        self.scanning.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    fn recurse_dir(&self, path: &Path) -> Result<(), std::io::Error> {
-        //TODO Add multithreading
-        for entry in path.read_dir()? {
-            let entry = entry?;
-            if entry.file_name() == "auth.txt" {
-                continue;
+    /*
+        Scans into a fresh list without touching the current one, see `apply_scan`. Files are
+        read, linted and parsed on a few threads; only the check for copies, which needs to see
+        every profile, runs afterwards in path order.
+    */
+    pub(crate) fn scan(&self, limits: &ScanLimits) -> Result<Scanned, std::io::Error> {
+        let scanned = scan::scan(Path::new(&self.main_dir), limits, Path::new(&self.auth))?;
+        let checked = scan::parallel_map(&scanned.files, |path| match std::fs::read(path) {
+            Ok(contents) => {
+                let report = lint::lint(path, &contents);
+                let profile = report.is_valid().then(|| {
+                    Profile::parse(path, &String::from_utf8_lossy(&contents), &self.main_dir)
+                });
+                (report, Some(lint::fingerprint(&contents)), profile)
+            }
+            Err(e) => (Report::unreadable(path, &e), None, None),
+        });
+
+        // Only profiles without lint errors are handed to the selection
+        let mut linter = Linter::default();
+        let mut profiles = Vec::new();
+        let mut reports: Vec<Report> = scanned
+            .issues
+            .into_iter()
+            .map(|issue| Report::single(&issue.path, issue.severity, issue.message))
+            .collect();
+        for (report, fingerprint, profile) in checked {
+            let report = match fingerprint {
+                Some(fingerprint) => linter.check(report, fingerprint),
+                None => report,
+            };
+            if let Some(profile) = profile.filter(|_| report.is_valid()) {
+                profiles.push(profile);
             }
-            let path = entry.path();
-            if path.is_dir() {
-                self.recurse_dir(&path)?;
-            } else {
-                {
-                    let mut file = self.lock_file()?;
-                    match path.to_str() {
-                        Some(path) => file.push(path.to_string()),
-                        None => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                "Failed to convert path to string",
-                            ));
-                        }
-                    }
-                }
+            if !report.findings.is_empty() {
+                reports.push(report);
             }
         }
-        Ok(())
+
+        Ok(Scanned { profiles, reports })
+    }
+
+    pub(crate) fn apply_scan(&self, scanned: Scanned) -> ProfileChanges {
+        let Scanned { profiles, reports } = scanned;
+        let mut changes = self.replace(profiles);
+        changes.invalid = reports.iter().filter(|report| !report.is_valid()).count();
+        *self.lint.lock().unwrap_or_else(|e| e.into_inner()) = reports;
+        changes
+    }
+
+    // What the next scan may take in
+    pub(crate) fn set_limits(&self, limits: ScanLimits) {
+        *self.limits.lock().unwrap_or_else(|e| e.into_inner()) = limits;
+    }
+
+    // What the last scan found wrong with the profiles, including the ones it left out
+    pub(crate) fn get_lint(&self) -> Vec<Report> {
+        self.lint.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn replace(&self, files: Vec<Profile>) -> ProfileChanges {
+        let mut current = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        let old: HashSet<&String> = current.iter().map(|profile| &profile.path).collect();
+        let new: HashSet<&String> = files.iter().map(|profile| &profile.path).collect();
+
+        let mut changes = ProfileChanges {
+            count: files.len(),
+            added: files
+                .iter()
+                .filter(|file| !old.contains(&file.path))
+                .map(|file| file.path.clone())
+                .collect(),
+            removed: current
+                .iter()
+                .filter(|file| !new.contains(&file.path))
+                .map(|file| file.path.clone())
+                .collect(),
+            invalid: 0,
+        };
+        changes.removed.sort();
+        *current = files;
+        changes
+    }
+
+    // Told from the contents when the profile is not in the list, it may have been added since
+    pub(crate) fn profile_kind(&self, path: &str) -> ProfileKind {
+        match self.get_profile(path) {
+            Some(profile) => profile.kind,
+            None => std::fs::read(path)
+                .map(|contents| ProfileKind::of(&String::from_utf8_lossy(&contents)))
+                .unwrap_or_default(),
+        }
+    }
+
+    pub(crate) fn configure_credentials(&self, rules: Vec<Rule>) {
+        *self.credentials.lock().unwrap_or_else(|e| e.into_inner()) = rules;
+    }
+
+    // Read from their source for every connection, so a changed password needs no reload
+    pub(crate) fn credentials_for(&self, path: &str) -> Result<Option<Secret>, std::io::Error> {
+        // A profile left out of the list, or scanned away since, is assumed to want them
+        let profile = self.get_profile(path).unwrap_or_else(|| Profile {
+            path: path.to_string(),
+            name: Path::new(path)
+                .file_stem()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            auth_user_pass: true,
+            ..Profile::default()
+        });
+        let rules = self
+            .credentials
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        credentials::resolve(&rules, &self.auth, &profile, &self.main_dir)
+    }
+
+    /*
+        The profile the switch connects to, as the selection strategy sees fit among the ones
+        not in quarantine. When every profile is cooling down one of them is picked anyway,
+        rather a server that failed a while ago than no VPN at all, pinned ones excepted.
+    */
+    pub(crate) fn pick_profile(&self) -> Result<String, std::io::Error> {
+        self.pick_profile_avoiding(&[])
+    }
+
+    // Like `pick_profile` without the profiles in `avoid`, unless nothing else is left
+    pub(crate) fn pick_profile_avoiding(&self, avoid: &[String]) -> Result<String, std::io::Error> {
+        let files = self.lock_file()?;
+        let mut all: Vec<&String> = files.iter().map(|profile| &profile.path).collect();
+        if all.iter().any(|file| !avoid.contains(file)) {
+            all.retain(|file| !avoid.contains(file));
+        }
+        let candidates = {
+            let quarantine = self.lock_quarantine();
+            let now = quarantine::now();
+            let free: Vec<&String> = all
+                .iter()
+                .filter(|file| !quarantine.is_held(file, now))
+                .copied()
+                .collect();
+            match free.is_empty() {
+                true => all
+                    .iter()
+                    .filter(|file| !quarantine.is_pinned(file))
+                    .copied()
+                    .collect(),
+                false => free,
+            }
+        };
+        match self.lock_selection().pick(&candidates, &self.main_dir) {
+            Ok(file) => Ok(file.to_string()),
+            Err(_) if all.is_empty() => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No profiles found in {}", self.main_dir),
+            )),
+            Err(_) if candidates.is_empty() => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Every profile is pinned in quarantine",
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn get_strategy(&self) -> Strategy {
+        self.lock_selection().get_strategy()
+    }
+
+    // Weights come compiled, see `selection::compile_weights`
+    pub(crate) fn configure_selection(&self, strategy: Strategy, weights: Vec<(Pattern, u32)>) {
+        self.lock_selection().set_strategy(strategy, weights);
+    }
+
+    // Called once OpenVPN was started with `profile`, errors only mean the history was not saved
+    pub(crate) fn record_used(&self, profile: &str) -> Result<(), std::io::Error> {
+        self.lock_selection().record_used(profile)
+    }
+
+    pub(crate) fn record_good(&self, profile: &str) -> Result<(), std::io::Error> {
+        self.lock_selection().record_good(profile)?;
+        self.lock_quarantine().record_success(profile)
+    }
+
+    // Returns the cool-down the profile was put in quarantine for, if quarantine is on
+    pub(crate) fn record_failed(&self, profile: &str) -> Result<Option<Duration>, std::io::Error> {
+        self.lock_selection().record_failed(profile)?;
+        self.lock_quarantine()
+            .record_failure(profile, quarantine::now())
+    }
+
+    pub(crate) fn configure_quarantine(&self, cool_down: Duration, max_cool_down: Duration) {
+        self.lock_quarantine().configure(cool_down, max_cool_down);
+    }
+
+    pub(crate) fn quarantine_report(&self) -> serde_json::Value {
+        self.lock_quarantine().to_json(quarantine::now())
+    }
+
+    // Pins every profile matching `query`, see `find_profile`
+    pub(crate) fn pin_profiles(&self, query: &str) -> Result<Vec<String>, std::io::Error> {
+        let files = self.lock_file()?;
+        let matches: Vec<String> = matching_profiles(&files, &self.main_dir, query)
+            .into_iter()
+            .cloned()
+            .collect();
+        if matches.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No profile matches {:?}", query),
+            ));
+        }
+        self.lock_quarantine().pin(&matches)?;
+        Ok(matches)
+    }
+
+    // Lets out the profiles matching `query`, or every one without a query
+    pub(crate) fn clear_quarantine(
+        &self,
+        query: Option<&str>,
+    ) -> Result<Vec<String>, std::io::Error> {
+        let Some(query) = query else {
+            return self.lock_quarantine().clear(None);
+        };
+        let files = self.lock_file()?;
+        let mut targets: Vec<String> = matching_profiles(&files, &self.main_dir, query)
+            .into_iter()
+            .cloned()
+            .collect();
+        // Profiles that have since left the directory can still be named by their path
+        if !targets.iter().any(|target| target == query) {
+            targets.push(query.to_string());
+        }
+        self.lock_quarantine().clear(Some(&targets))
+    }
+
+    /*
+        Picks a profile for `query`, tried in this order:
+          an exact path, a file name with or without its extension, a glob like `se-*` or
+          `nordvpn/us*` (when the query has `*`, `?` or `[`), and finally a substring of the path
+          below the profile directory. Case is ignored past the exact path. A query made only of
+        `key:value` filters, like `country:se city:sto`, picks by what was read from the profiles
+        instead, see `Profile::matches_filter`. When several profiles match the selection
+        strategy picks one, like it does for the switch.
+    */
+    pub(crate) fn find_profile(&self, query: &str) -> Result<String, std::io::Error> {
+        let files = self.lock_file()?;
+        let matches = matching_profiles(&files, &self.main_dir, query);
+        if matches.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No profile matches {:?}", query),
+            ));
+        }
+        // Asking for a profile by name gets it even in quarantine, a query matching several
+        // prefers the ones that are not
+        let free: Vec<&String> = {
+            let quarantine = self.lock_quarantine();
+            let now = quarantine::now();
+            matches
+                .iter()
+                .filter(|file| !quarantine.is_held(file, now))
+                .copied()
+                .collect()
+        };
+        let candidates = if free.is_empty() { &matches } else { &free };
+        // A profile named on its own is wanted whatever it weighs
+        if let [only] = candidates.as_slice() {
+            return Ok(only.to_string());
+        }
+        let pick = self.lock_selection().pick(candidates, &self.main_dir)?;
+        Ok(pick.to_string())
     }
 
-    fn lock_file(&self) -> Result<MutexGuard<Vec<String>>, std::io::Error> {
+    pub(crate) fn get_file_paths(&self) -> Result<Vec<String>, std::io::Error> {
+        let files = self.lock_file()?;
+        Ok(files.iter().map(|profile| profile.path.clone()).collect())
+    }
+
+    pub(crate) fn get_profiles(&self) -> Result<Vec<Profile>, std::io::Error> {
+        let files = self.lock_file()?;
+        Ok(files.clone())
+    }
+
+    pub(crate) fn get_profile(&self, path: &str) -> Option<Profile> {
+        let files = self.lock_file().ok()?;
+        files.iter().find(|profile| profile.path == path).cloned()
+    }
+
+    fn lock_selection(&self) -> MutexGuard<'_, Selection> {
+        self.selection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_quarantine(&self) -> MutexGuard<'_, Quarantine> {
+        self.quarantine.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_file(&self) -> Result<MutexGuard<Vec<Profile>>, std::io::Error> {
         match self.files.lock() {
             Ok(file) => Ok(file),
             Err(_) => Err(std::io::Error::new(
@@ -77,20 +407,285 @@ impl File {
     }
 }
 
+fn matching_profiles<'a>(profiles: &'a [Profile], main_dir: &str, query: &str) -> Vec<&'a String> {
+    let filters: Option<Vec<(&str, &str)>> = query
+        .split_whitespace()
+        .map(|word| word.split_once(':'))
+        .collect();
+    if let Some(filters) = filters.filter(|filters| !filters.is_empty()) {
+        return profiles
+            .iter()
+            .filter(|profile| {
+                filters
+                    .iter()
+                    .all(|(key, value)| profile.matches_filter(key, value))
+            })
+            .map(|profile| &profile.path)
+            .collect();
+    }
+
+    let files: Vec<&String> = profiles.iter().map(|profile| &profile.path).collect();
+    if let Some(file) = files.iter().find(|file| file.as_str() == query) {
+        return vec![file];
+    }
+
+    let query = query.to_lowercase();
+    let relative = |file: &str| {
+        file.strip_prefix(main_dir)
+            .unwrap_or(file)
+            .trim_start_matches('/')
+            .to_lowercase()
+    };
+    let file_name = |file: &str| {
+        Path::new(file)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    };
+
+    let by_name: Vec<&String> = files
+        .iter()
+        .copied()
+        .filter(|file| {
+            let name = file_name(file);
+            name == query || name.rsplit_once('.').map(|(stem, _)| stem) == Some(query.as_str())
+        })
+        .collect();
+    if !by_name.is_empty() {
+        return by_name;
+    }
+
+    if query.contains(['*', '?', '[']) {
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+        return match Pattern::new(&query) {
+            Ok(pattern) => files
+                .into_iter()
+                .filter(|file| {
+                    pattern.matches_with(&file_name(file), options)
+                        || pattern.matches_with(&relative(file), options)
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+    }
+
+    files
+        .into_iter()
+        .filter(|file| relative(file).contains(&query))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
+
+    fn profiles() -> Vec<Profile> {
+        [
+            "/vpn/nordvpn/se-sto-01.ovpn",
+            "/vpn/nordvpn/se-sto-02.ovpn",
+            "/vpn/nordvpn/us-nyc-01.ovpn",
+            "/vpn/mullvad/SE-GOT.ovpn",
+        ]
+        .iter()
+        .map(|path| Profile::parse(path, "", "/vpn"))
+        .collect()
+    }
+
+    #[test]
+    fn test_matching_profiles() {
+        let profiles = profiles();
+        let files: Vec<&String> = profiles.iter().map(|profile| &profile.path).collect();
+
+        // Exact path and file name, with or without the extension
+        let matches = matching_profiles(&profiles, "/vpn", "/vpn/nordvpn/us-nyc-01.ovpn");
+        assert_eq!(matches, vec![files[2]]);
+        let matches = matching_profiles(&profiles, "/vpn", "se-sto-01");
+        assert_eq!(matches, vec![files[0]]);
+        let matches = matching_profiles(&profiles, "/vpn", "se-got.ovpn");
+        assert_eq!(matches, vec![files[3]]);
+
+        // Globs match the file name or the path below the profile directory
+        let matches = matching_profiles(&profiles, "/vpn", "se-*");
+        assert_eq!(matches.len(), 3);
+        let matches = matching_profiles(&profiles, "/vpn", "nordvpn/se*");
+        assert_eq!(matches.len(), 2);
+
+        // Anything else is a substring
+        let matches = matching_profiles(&profiles, "/vpn", "mullvad");
+        assert_eq!(matches, vec![files[3]]);
+        let matches = matching_profiles(&profiles, "/vpn", "nyc");
+        assert_eq!(matches, vec![files[2]]);
+        assert!(matching_profiles(&profiles, "/vpn", "ch-zur").is_empty());
+
+        // Filters go by what was read from the profiles, every one has to fit
+        let matches = matching_profiles(&profiles, "/vpn", "country:se");
+        assert_eq!(matches.len(), 3);
+        let matches = matching_profiles(&profiles, "/vpn", "country:sweden provider:mullvad");
+        assert_eq!(matches, vec![files[3]]);
+        let matches = matching_profiles(&profiles, "/vpn", "city:nyc");
+        assert_eq!(matches, vec![files[2]]);
+        assert!(matching_profiles(&profiles, "/vpn", "country:ch").is_empty());
+    }
+
+    fn write_profile(path: &Path) {
+        let host = path.file_stem().unwrap().to_str().unwrap();
+        let contents = format!("remote {}.example.com 1194\n<ca>\ncert\n</ca>\n", host);
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_reload_reports_changes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let main_dir = dir.path().to_str().unwrap();
+        std::fs::write(dir.path().join("auth.txt"), "user\npass\n").unwrap();
+        write_profile(&dir.path().join("se.ovpn"));
+        write_profile(&dir.path().join("us.ovpn"));
+
+        let file = File::with_dir(main_dir);
+        let changes = file.reload().unwrap();
+        assert_eq!(changes.count, 2);
+        assert_eq!(changes.added.len(), 2);
+        assert!(changes.removed.is_empty());
+
+        std::fs::remove_file(dir.path().join("us.ovpn")).unwrap();
+        std::fs::create_dir(dir.path().join("nordvpn")).unwrap();
+        write_profile(&dir.path().join("nordvpn/ch.ovpn"));
+        let changes = file.reload().unwrap();
+        assert_eq!(changes.count, 2);
+        assert_eq!(changes.added, vec![format!("{}/nordvpn/ch.ovpn", main_dir)]);
+        assert_eq!(changes.removed, vec![format!("{}/us.ovpn", main_dir)]);
+
+        // A directory that vanished fails the scan and keeps what was there
+        drop(dir);
+        assert!(file.reload().is_err());
+        assert_eq!(file.get_file_paths().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_scan_waits_for_apply() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let main_dir = dir.path().to_str().unwrap();
+        write_profile(&dir.path().join("se.ovpn"));
+
+        let file = File::with_dir(main_dir);
+        let scanned = file.scan(&ScanLimits::default()).unwrap();
+        assert!(file.get_file_paths().unwrap().is_empty());
+
+        let changes = file.apply_scan(scanned);
+        assert_eq!(changes.added, vec![format!("{}/se.ovpn", main_dir)]);
+        assert_eq!(file.get_file_paths().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reload_skips_invalid_profiles() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let main_dir = dir.path().to_str().unwrap();
+        write_profile(&dir.path().join("se.ovpn"));
+        std::fs::create_dir(dir.path().join("updated")).unwrap();
+        write_profile(&dir.path().join("updated/se.ovpn"));
+        std::fs::write(dir.path().join("README.md"), "Servers by country\n").unwrap();
+
+        let file = File::with_dir(main_dir);
+        let changes = file.reload().unwrap();
+        assert_eq!(changes.count, 1);
+        assert_eq!(changes.invalid, 2);
+        assert_eq!(
+            file.get_file_paths().unwrap(),
+            vec![format!("{}/se.ovpn", main_dir)]
+        );
+
+        let lint = file.get_lint();
+        let paths: Vec<&str> = lint.iter().map(|report| report.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                format!("{}/README.md", main_dir),
+                format!("{}/updated/se.ovpn", main_dir)
+            ]
+        );
+        assert!(lint.iter().all(|report| !report.is_valid()));
+    }
+
+    #[test]
+    fn test_find_profile() {
+        let file = File::with_dir("/home/kwunch/VPN");
+        *file.files.lock().unwrap() = profiles();
+
+        let result = file.find_profile("se-sto*");
+        assert!(result.is_ok());
+        assert!(result.unwrap().starts_with("/vpn/nordvpn/se-sto-"));
+
+        let result = file.find_profile("ch-zur");
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_quarantine_steers_picks() {
+        let file = File::with_dir("/vpn");
+        *file.files.lock().unwrap() = profiles();
+        let first = "/vpn/nordvpn/se-sto-01.ovpn";
+
+        // Failing profiles are passed over as long as something else is left
+        assert!(file.record_failed(first).unwrap().is_some());
+        for _ in 0..20 {
+            assert_ne!(file.find_profile("se-sto*").unwrap(), first);
+            assert_ne!(file.pick_profile().unwrap(), first);
+        }
+        assert_eq!(file.find_profile("se-sto-01").unwrap(), first);
+
+        let pinned = file.pin_profiles("nordvpn/*").unwrap();
+        assert_eq!(pinned.len(), 3);
+        for _ in 0..20 {
+            assert_eq!(file.pick_profile().unwrap(), "/vpn/mullvad/SE-GOT.ovpn");
+        }
+        assert_eq!(file.quarantine_report()["count"], json!(3));
+
+        assert_eq!(file.clear_quarantine(Some("us-nyc-01")).unwrap().len(), 1);
+        assert_eq!(
+            file.clear_quarantine(Some("/vpn/nordvpn/se-sto-02.ovpn"))
+                .unwrap(),
+            vec!["/vpn/nordvpn/se-sto-02.ovpn"]
+        );
+        assert_eq!(file.clear_quarantine(None).unwrap().len(), 1);
+        assert_eq!(file.quarantine_report()["count"], json!(0));
+    }
+
+    #[test]
+    fn test_pick_avoiding() {
+        let file = File::with_dir("/vpn");
+        *file.files.lock().unwrap() = profiles();
+        let avoid: Vec<String> = profiles()
+            .into_iter()
+            .map(|profile| profile.path)
+            .filter(|path| path != "/vpn/mullvad/SE-GOT.ovpn")
+            .collect();
+        for _ in 0..20 {
+            assert_eq!(
+                file.pick_profile_avoiding(&avoid).unwrap(),
+                "/vpn/mullvad/SE-GOT.ovpn"
+            );
+        }
+
+        // Avoiding everything is the same as avoiding nothing
+        let avoid: Vec<String> = profiles().into_iter().map(|profile| profile.path).collect();
+        assert!(file.pick_profile_avoiding(&avoid).is_ok());
+    }
 
     #[test]
     fn test_init() {
-        let file = File::new();
+        let file = File::with_dir("/home/kwunch/VPN");
         let result = file.init();
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_file_count() {
-        let file = File::new();
+        let file = File::with_dir("/home/kwunch/VPN");
         let result = file.init();
         assert!(result.is_ok());
         assert_eq!(file.files.lock().unwrap().len(), 458);
@@ -98,10 +693,10 @@ mod tests {
 
     #[test]
     fn test_is_valid_path() {
-        let file = File::new();
+        let file = File::with_dir("/home/kwunch/VPN");
         let result = file.init();
         assert!(result.is_ok());
-        let path = file.get_random_file_path();
+        let path = file.pick_profile();
         assert!(path.is_ok());
         let path = path.unwrap();
         assert!(Path::new(&path).exists());
@@ -109,7 +704,7 @@ mod tests {
 
     #[test]
     fn test_lock_file() {
-        let file = File::new();
+        let file = File::with_dir("/home/kwunch/VPN");
         let result = file.init();
         assert!(result.is_ok());
         let result = file.lock_file();
@@ -0,0 +1,594 @@
+use crate::tools::access::{self, Access, PeerCredentials};
+use crate::tools::daemon::Daemon;
+use crate::tools::events::EventRecord;
+use crate::tools::protocol::{self, Command, ErrorBody, ErrorCode, Incoming, Response};
+use crate::tools::supervisor::RunnerState;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+// Idle clients are dropped so a forgotten connection does not keep a thread around forever
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const STOP_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(crate) fn serve(stream: UnixStream, daemon: Arc<Daemon>) {
+    stream.set_read_timeout(Some(CLIENT_IDLE_TIMEOUT)).ok();
+    stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)).ok();
+
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            daemon.log(&format!("Failed to clone control stream: {:?}", e));
+            return;
+        }
+    };
+
+    let peer = match access::peer_credentials(&stream) {
+        Ok(peer) => peer,
+        Err(e) => {
+            daemon.log(&format!("Dropped client without peer credentials: {:?}", e));
+            return;
+        }
+    };
+    let access = daemon.access().check(&peer);
+    if access == Access::Denied {
+        daemon.log(&format!("Denied control connection from {}", peer));
+        let error = ErrorBody::new(ErrorCode::PermissionDenied, "Permission denied");
+        send_response(&mut writer, &Response::err(Value::Null, error), &daemon);
+        return;
+    }
+    let mut reader = BufReader::new(stream);
+
+    // A connection may carry any number of requests, one per line, until the client hangs up
+    loop {
+        let mut line = String::new();
+        match reader
+            .by_ref()
+            .take(protocol::MAX_LINE_LENGTH as u64)
+            .read_line(&mut line)
+        {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                let msg = format!("Error reading from socket: {:?}", e);
+                write_to_stream(&mut writer, &msg, &daemon);
+                daemon.log(&msg);
+                break;
+            }
+        }
+
+        if !line.ends_with('\n') && line.len() >= protocol::MAX_LINE_LENGTH {
+            let error = ErrorBody::new(
+                ErrorCode::ParseError,
+                format!("Request exceeds {} bytes", protocol::MAX_LINE_LENGTH),
+            );
+            send_response(&mut writer, &Response::err(Value::Null, error), &daemon);
+            break;
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match protocol::parse_line(line) {
+            Ok(Incoming::Json(request)) => {
+                let command = Command::from_request(&request).and_then(|command| {
+                    authorize(&command, access, &peer, &daemon).map(|_| command)
+                });
+                let response = match command {
+                    Ok(Command::Subscribe { events }) => {
+                        // The connection belongs to the event stream from here on
+                        let receiver = daemon.events().subscribe();
+                        let response = Response::ok(request.id, json!({ "subscribed": true }));
+                        send_response(&mut writer, &response, &daemon);
+                        stream_events(&mut writer, receiver, events);
+                        break;
+                    }
+                    Ok(command) => match execute(command, &request.args, &daemon) {
+                        Ok(result) => Response::ok(request.id, result),
+                        Err(error) => Response::err(request.id, error),
+                    },
+                    Err(error) => Response::err(request.id, error),
+                };
+                send_response(&mut writer, &response, &daemon);
+            }
+            Ok(Incoming::Legacy(word)) => handle_legacy(&word, &mut writer, access, &peer, &daemon),
+            Err(error) => send_response(&mut writer, &Response::err(Value::Null, error), &daemon),
+        }
+    }
+}
+
+fn stream_events(
+    stream: &mut UnixStream,
+    receiver: Receiver<EventRecord>,
+    filter: Option<Vec<String>>,
+) {
+    loop {
+        let line = match receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(record) => {
+                if let Some(filter) = &filter
+                    && !filter.iter().any(|name| name == record.event.name())
+                {
+                    continue;
+                }
+                record.to_line()
+            }
+            // Heartbeats are how a client that silently went away gets noticed
+            Err(RecvTimeoutError::Timeout) => {
+                format!(
+                    "{}\n",
+                    json!({ "v": protocol::PROTOCOL_VERSION, "event": "heartbeat" })
+                )
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if stream.write_all(line.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn authorize(
+    command: &Command,
+    access: Access,
+    peer: &PeerCredentials,
+    daemon: &Daemon,
+) -> Result<(), ErrorBody> {
+    if command.is_mutating() && access < Access::Full {
+        daemon.log(&format!(
+            "Denied {} from read-only client {}",
+            command.get_name(),
+            peer
+        ));
+        return Err(ErrorBody::new(
+            ErrorCode::PermissionDenied,
+            format!("{} needs full access to the daemon", command.get_name()),
+        ));
+    }
+    Ok(())
+}
+
+fn handle_legacy(
+    word: &str,
+    stream: &mut UnixStream,
+    access: Access,
+    peer: &PeerCredentials,
+    daemon: &Arc<Daemon>,
+) {
+    // Replies mirror the free text the daemon sent before the JSON protocol existed
+    let command = match Command::from_legacy(word) {
+        Some(command) => command,
+        None => {
+            write_to_stream(stream, "Received invalid command!", daemon);
+            return;
+        }
+    };
+
+    if let Err(error) = authorize(&command, access, peer, daemon) {
+        write_to_stream(stream, &error.message, daemon);
+        return;
+    }
+
+    match command {
+        Command::Status => {
+            write_to_stream(
+                stream,
+                if daemon.runner_state().is_active() {
+                    "Daemon is running"
+                } else {
+                    "Daemon is not running"
+                },
+                daemon,
+            );
+        }
+        Command::Start => match daemon.start() {
+            Ok(_) => write_to_stream(stream, "Daemon started", daemon),
+            Err(error) => write_to_stream(stream, &error.message, daemon),
+        },
+        Command::Stop => {
+            if daemon.stop().is_err() {
+                return;
+            }
+            write_to_stream(stream, "Killing VPN if needed...", daemon);
+            // Only this connection waits, every other client keeps being served
+            if daemon.wait_for_stop(STOP_WAIT_TIMEOUT) == RunnerState::Stopped {
+                write_to_stream(stream, "Stopped listening to Arduino...", daemon);
+            } else {
+                write_to_stream(stream, "Still stopping, check status later...", daemon);
+            }
+            stream.flush().ok();
+        }
+        _ => write_to_stream(stream, "Received invalid command!", daemon),
+    }
+}
+
+fn execute(command: Command, args: &Value, daemon: &Arc<Daemon>) -> Result<Value, ErrorBody> {
+    match command {
+        Command::Hello { versions } => protocol::negotiate(&versions),
+        Command::Status => Ok(daemon.status_report()),
+        Command::Start => {
+            daemon.start()?;
+            Ok(json!({ "started": true, "state": RunnerState::Running }))
+        }
+        Command::Stop => {
+            let mut state = daemon.stop()?;
+            // Stopping is asynchronous, clients that want to block until it is done ask for it
+            if args.get("wait").and_then(Value::as_bool).unwrap_or(false) {
+                state = daemon.wait_for_stop(STOP_WAIT_TIMEOUT);
+            }
+            Ok(json!({ "stopped": state == RunnerState::Stopped, "state": state }))
+        }
+        Command::Connect { profile } => daemon.connect(&profile),
+        Command::Reload => daemon.reload(),
+        Command::Override { mode, duration } => Ok(daemon.set_override(mode, duration)),
+        Command::Logs { lines } => {
+            let logger = daemon.logger().lock().unwrap();
+            match logger.tail(lines) {
+                Ok(lines) => Ok(json!({ "path": logger.get_log_path(), "lines": lines })),
+                Err(e) => Err(ErrorBody::new(
+                    ErrorCode::Internal,
+                    format!("Failed to read log: {}", e),
+                )),
+            }
+        }
+        // `profiles lint` in vpnctl, what the last scan found wrong and what it left out
+        Command::Profiles if args.get("lint").and_then(Value::as_bool).unwrap_or(false) => {
+            let reports = daemon.config().get_lint();
+            Ok(json!({
+                "invalid": reports.iter().filter(|report| !report.is_valid()).count(),
+                "reports": reports.iter().map(|report| report.to_json()).collect::<Vec<_>>(),
+            }))
+        }
+        Command::Profiles => match daemon.config().get_profiles() {
+            Ok(profiles) => {
+                // Paths by default, everything read from the profiles when asked for details
+                let listed: Vec<Value> = if args
+                    .get("details")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false)
+                {
+                    profiles.iter().map(|profile| profile.to_json()).collect()
+                } else {
+                    profiles.iter().map(|profile| json!(profile.path)).collect()
+                };
+                Ok(json!({
+                    "count": listed.len(),
+                    "profiles": listed,
+                    "strategy": daemon.config().get_strategy().to_string(),
+                }))
+            }
+            Err(e) => Err(ErrorBody::new(
+                ErrorCode::Internal,
+                format!("Failed to list profiles: {}", e),
+            )),
+        },
+        Command::Quarantine { action } => daemon.quarantine(action),
+        Command::Subscribe { .. } => Err(ErrorBody::new(
+            ErrorCode::InvalidRequest,
+            "subscribe is only available on its own connection",
+        )),
+    }
+}
+
+fn send_response(stream: &mut UnixStream, response: &Response, daemon: &Daemon) {
+    if let Err(e) = stream.write_all(response.to_line().as_bytes()) {
+        daemon.log(&format!("Failed to write response: {:?}", e));
+    }
+}
+
+fn write_to_stream(stream: &mut UnixStream, message: &str, daemon: &Daemon) {
+    let mut attempt = 0;
+    while attempt <= 5 {
+        match writeln!(stream, "{}\n", message) {
+            Ok(_) => {
+                break;
+            }
+            Err(_) if attempt < 5 => {
+                thread::sleep(Duration::from_millis(50)); // Small delay before retry
+                attempt += 1;
+                continue;
+            }
+            Err(e) => {
+                daemon.log(&format!("Failed to write to stream: {:?}", e));
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::config;
+    use crate::tools::events::Event;
+    use crate::tools::logger::Logger;
+    use crate::tools::notifier::Notifier;
+    use crate::tools::settings::Settings;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    // The log directory has to outlive the daemon, so it goes back to the test
+    fn test_daemon() -> (TempDir, Arc<Daemon>) {
+        let settings = Settings::default();
+        test_daemon_with(config::File::new(
+            &settings.profile_dir,
+            &settings.auth_file,
+        ))
+    }
+
+    fn test_daemon_with(config: config::File) -> (TempDir, Arc<Daemon>) {
+        let log_dir = TempDir::new().unwrap();
+        let logger = Logger::with_path(&log_dir.path().join("log.txt"));
+        let (notifier_socket, _peer) = UnixStream::pair().unwrap();
+        let daemon = Arc::new(Daemon::new(
+            Arc::new(Mutex::new(logger)),
+            Arc::new(Mutex::new(Notifier::from_stream(notifier_socket))),
+            Settings::default(),
+            Arc::new(config),
+        ));
+        (log_dir, daemon)
+    }
+
+    fn connect(daemon: &Arc<Daemon>) -> (UnixStream, BufReader<UnixStream>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let daemon = Arc::clone(daemon);
+        thread::spawn(move || serve(server, daemon));
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(client.try_clone().unwrap());
+        (client, reader)
+    }
+
+    fn call(client: &mut UnixStream, reader: &mut BufReader<UnixStream>, line: &str) -> Value {
+        writeln!(client, "{}", line).unwrap();
+        let mut response = String::new();
+        let result = reader.read_line(&mut response);
+        assert!(result.is_ok(), "Failed to read response: {:?}", result);
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_status_and_stop_when_idle() {
+        let (_log_dir, daemon) = test_daemon();
+        let (mut client, mut reader) = connect(&daemon);
+
+        let response = call(&mut client, &mut reader, r#"{"id":1,"cmd":"status"}"#);
+        assert_eq!(response["ok"], json!(true));
+        assert_eq!(response["result"]["state"], json!("stopped"));
+        assert_eq!(response["result"]["pid"], Value::Null);
+        assert!(response["result"]["switch"].is_object());
+        assert_eq!(response["result"]["runner"]["restarts"], json!(0));
+        assert_eq!(response["result"]["runner"]["last_exit"], Value::Null);
+
+        let response = call(&mut client, &mut reader, r#"{"id":2,"cmd":"stop"}"#);
+        assert_eq!(response["ok"], json!(false));
+        assert_eq!(response["error"]["code"], json!("not_running"));
+
+        // Picking a server needs the runner, which owns OpenVPN
+        let response = call(
+            &mut client,
+            &mut reader,
+            r#"{"id":3,"cmd":"connect","args":{"profile":"se-*"}}"#,
+        );
+        assert_eq!(response["id"], json!(3));
+        assert_eq!(response["error"]["code"], json!("not_running"));
+    }
+
+    #[test]
+    fn test_legacy_status() {
+        let (_log_dir, daemon) = test_daemon();
+        let (mut client, mut reader) = connect(&daemon);
+
+        writeln!(client, "status").unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert_eq!(response.trim(), "Daemon is not running");
+    }
+
+    #[test]
+    fn test_subscribe_streams_events() {
+        let (_log_dir, daemon) = test_daemon();
+        let (mut client, mut reader) = connect(&daemon);
+
+        let response = call(
+            &mut client,
+            &mut reader,
+            r#"{"id":1,"cmd":"subscribe","args":{"events":["error"]}}"#,
+        );
+        assert_eq!(response["result"]["subscribed"], json!(true));
+        assert_eq!(daemon.events().get_subscriber_count(), 1);
+
+        // Filtered out events never reach the client, matching ones do
+        daemon.events().publish(Event::LogRotated);
+        daemon.events().publish(Event::Error {
+            message: "Serial port vanished".to_string(),
+        });
+
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let event: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event["event"], json!("error"));
+        assert_eq!(event["data"]["message"], json!("Serial port vanished"));
+    }
+
+    #[test]
+    fn test_override_shows_in_status() {
+        let (_log_dir, daemon) = test_daemon();
+        let (mut client, mut reader) = connect(&daemon);
+
+        let response = call(&mut client, &mut reader, r#"{"id":1,"cmd":"status"}"#);
+        assert_eq!(
+            response["result"]["override"]["mode"],
+            json!("follow-switch")
+        );
+
+        let response = call(
+            &mut client,
+            &mut reader,
+            r#"{"id":2,"cmd":"override","args":{"mode":"force-on","duration":"1h"}}"#,
+        );
+        assert_eq!(response["ok"], json!(true));
+        assert_eq!(response["result"]["mode"], json!("force-on"));
+
+        let response = call(&mut client, &mut reader, r#"{"id":3,"cmd":"status"}"#);
+        assert_eq!(response["result"]["override"]["mode"], json!("force-on"));
+        assert!(response["result"]["override"]["until"].is_string());
+
+        call(
+            &mut client,
+            &mut reader,
+            r#"{"id":4,"cmd":"override","args":{"mode":"follow-switch"}}"#,
+        );
+        let response = call(&mut client, &mut reader, r#"{"id":5,"cmd":"status"}"#);
+        assert_eq!(
+            response["result"]["override"]["mode"],
+            json!("follow-switch")
+        );
+        assert_eq!(response["result"]["override"]["until"], Value::Null);
+    }
+
+    #[test]
+    fn test_reload_rescans_profiles() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("se.ovpn"),
+            "remote se.example.com\n<ca>\ncert\n</ca>\n",
+        )
+        .unwrap();
+        let (_log_dir, daemon) =
+            test_daemon_with(config::File::with_dir(dir.path().to_str().unwrap()));
+        let (mut client, mut reader) = connect(&daemon);
+
+        // Nothing is known until the first scan
+        let response = call(&mut client, &mut reader, r#"{"id":1,"cmd":"profiles"}"#);
+        assert_eq!(response["result"]["count"], json!(0));
+
+        let response = call(&mut client, &mut reader, r#"{"id":2,"cmd":"reload"}"#);
+        assert_eq!(response["ok"], json!(true));
+        assert_eq!(response["result"]["profiles"]["count"], json!(1));
+        assert_eq!(
+            response["result"]["profiles"]["added"][0],
+            json!(dir.path().join("se.ovpn").to_str().unwrap())
+        );
+        assert_eq!(response["result"]["settings"]["changed"], json!([]));
+
+        std::fs::remove_file(dir.path().join("se.ovpn")).unwrap();
+        let response = call(&mut client, &mut reader, r#"{"id":3,"cmd":"reload"}"#);
+        assert_eq!(response["result"]["profiles"]["count"], json!(0));
+        assert_eq!(
+            response["result"]["profiles"]["removed"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let response = call(&mut client, &mut reader, r#"{"id":4,"cmd":"profiles"}"#);
+        assert_eq!(response["result"]["profiles"], json!([]));
+    }
+
+    #[test]
+    fn test_profile_details() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("mullvad")).unwrap();
+        std::fs::write(
+            dir.path().join("mullvad/se-got-01.ovpn"),
+            "remote se-got.example.com 1194 udp\nauth-user-pass\n<ca>\ncert\n</ca>\n",
+        )
+        .unwrap();
+        let config = config::File::with_dir(dir.path().to_str().unwrap());
+        config.init().unwrap();
+        let (_log_dir, daemon) = test_daemon_with(config);
+        let (mut client, mut reader) = connect(&daemon);
+
+        let response = call(
+            &mut client,
+            &mut reader,
+            r#"{"id":1,"cmd":"profiles","args":{"details":true}}"#,
+        );
+        let profile = &response["result"]["profiles"][0];
+        assert_eq!(profile["name"], json!("se-got-01"));
+        assert_eq!(profile["provider"], json!("mullvad"));
+        assert_eq!(profile["country"], json!("SE"));
+        assert_eq!(profile["city"], json!("got"));
+        assert_eq!(profile["remotes"][0]["port"], json!(1194));
+        assert_eq!(profile["auth_user_pass"], json!(true));
+    }
+
+    #[test]
+    fn test_profiles_lint() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("README.txt"), "Servers by country\n").unwrap();
+        std::fs::write(
+            dir.path().join("se.ovpn"),
+            "remote se.example.com\ncomp-lzo\n<ca>\ncert\n</ca>\n",
+        )
+        .unwrap();
+        let config = config::File::with_dir(dir.path().to_str().unwrap());
+        config.init().unwrap();
+        let (_log_dir, daemon) = test_daemon_with(config);
+        let (mut client, mut reader) = connect(&daemon);
+
+        let response = call(
+            &mut client,
+            &mut reader,
+            r#"{"id":1,"cmd":"profiles","args":{"lint":true}}"#,
+        );
+        assert_eq!(response["result"]["invalid"], json!(1));
+        let reports = &response["result"]["reports"];
+        assert_eq!(reports[0]["valid"], json!(false));
+        assert_eq!(reports[1]["valid"], json!(true));
+        assert_eq!(reports[1]["findings"][0]["severity"], json!("warning"));
+        assert_eq!(reports[1]["findings"][0]["line"], json!(2));
+
+        // Only the profile made it into the list
+        let response = call(&mut client, &mut reader, r#"{"id":2,"cmd":"profiles"}"#);
+        assert_eq!(response["result"]["count"], json!(1));
+    }
+
+    #[test]
+    fn test_read_only_access() {
+        let (_log_dir, daemon) = test_daemon();
+        let peer = PeerCredentials {
+            pid: 0,
+            uid: 1234,
+            gid: 1234,
+            groups: Vec::new(),
+        };
+
+        let result = authorize(&Command::Start, Access::ReadOnly, &peer, &daemon);
+        assert_eq!(result.unwrap_err().code, ErrorCode::PermissionDenied);
+        let result = authorize(&Command::Stop, Access::ReadOnly, &peer, &daemon);
+        assert!(result.is_err());
+        let connect = Command::Connect {
+            profile: "se-*".to_string(),
+        };
+        assert!(authorize(&connect, Access::ReadOnly, &peer, &daemon).is_err());
+
+        assert!(authorize(&Command::Status, Access::ReadOnly, &peer, &daemon).is_ok());
+        assert!(authorize(&Command::Start, Access::Full, &peer, &daemon).is_ok());
+    }
+
+    #[test]
+    fn test_idle_client_does_not_block_others() {
+        let (_log_dir, daemon) = test_daemon();
+
+        // First client connects and never sends a full line
+        let (mut idle, _idle_reader) = connect(&daemon);
+        idle.write_all(br#"{"id":1,"cmd":"#).unwrap();
+
+        // Second client must still be answered right away
+        let (mut client, mut reader) = connect(&daemon);
+        let response = call(&mut client, &mut reader, r#"{"id":2,"cmd":"status"}"#);
+        assert_eq!(response["id"], json!(2));
+        assert_eq!(response["ok"], json!(true));
+    }
+}
@@ -0,0 +1,532 @@
+use crate::tools::profile::Profile;
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// `pass` and `secret-tool` may wait on an agent, but never on a person at the keyboard
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(15);
+// How long OpenVPN gets to read its credentials before they are taken away again
+pub(crate) const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/*
+    Where the credentials of some profiles come from, one `[[credentials]]` table in the config
+    file. `provider` and `profiles` (a glob on the file name or the path below the profile
+    directory, like the selection weights) pick the profiles, a rule with neither applies to all
+    of them. Exactly one source is given: a `file` like auth.txt, `env` variables, a `keyring`
+    entry looked up with secret-tool, or a `command` such as `pass show vpn/nordvpn`. Each source
+    yields the username and the password on two lines, or only the password when `username` is
+    set in the rule. The keyring only ever holds the password, so it needs `username`.
+*/
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Rule {
+    pub(crate) provider: Option<String>,
+    pub(crate) profiles: Option<String>,
+    pub(crate) username: Option<String>,
+    pub(crate) file: Option<String>,
+    pub(crate) env: Option<EnvNames>,
+    pub(crate) keyring: Option<BTreeMap<String, String>>,
+    pub(crate) command: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EnvNames {
+    pub(crate) username: Option<String>,
+    pub(crate) password: String,
+}
+
+impl Rule {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let sources = [
+            self.file.is_some(),
+            self.env.is_some(),
+            self.keyring.is_some(),
+            self.command.is_some(),
+        ];
+        match sources.iter().filter(|given| **given).count() {
+            0 => return Err("no source, give one of file, env, keyring or command".to_string()),
+            1 => {}
+            _ => return Err("more than one of file, env, keyring and command".to_string()),
+        }
+        if let Some(pattern) = &self.profiles {
+            Pattern::new(pattern).map_err(|e| format!("profiles {:?}: {}", pattern, e))?;
+        }
+        if self
+            .command
+            .as_ref()
+            .is_some_and(|command| command.is_empty())
+        {
+            return Err("empty command".to_string());
+        }
+        if self
+            .keyring
+            .as_ref()
+            .is_some_and(|keyring| keyring.is_empty())
+        {
+            return Err("keyring needs at least one attribute".to_string());
+        }
+        // secret-tool prints the password alone
+        if self.keyring.is_some() && self.username.is_none() {
+            return Err("keyring needs the rule to give a username".to_string());
+        }
+        if let Some(env) = &self.env
+            && env.username.is_none()
+            && self.username.is_none()
+        {
+            return Err("env needs a username variable or the rule a username".to_string());
+        }
+        Ok(())
+    }
+
+    fn matches(&self, profile: &Profile, main_dir: &str) -> bool {
+        let provider = match &self.provider {
+            Some(wanted) => profile
+                .provider
+                .as_deref()
+                .is_some_and(|provider| provider.eq_ignore_ascii_case(wanted)),
+            None => true,
+        };
+        let profiles = match &self.profiles {
+            Some(pattern) => {
+                let options = MatchOptions {
+                    case_sensitive: false,
+                    ..MatchOptions::new()
+                };
+                let relative = profile
+                    .path
+                    .strip_prefix(main_dir)
+                    .unwrap_or(&profile.path)
+                    .trim_start_matches('/');
+                Pattern::new(pattern).is_ok_and(|pattern| {
+                    pattern.matches_with(&profile.name, options)
+                        || pattern.matches_with(relative, options)
+                })
+            }
+            None => true,
+        };
+        provider && profiles
+    }
+
+    // What the rule's source holds right now, sources are read on every connection
+    fn read(&self) -> Result<Secret, io::Error> {
+        if let Some(file) = &self.file {
+            return read_file(Path::new(file), self.username.as_deref());
+        }
+        if let Some(env) = &self.env {
+            let var = |name: &str| {
+                std::env::var(name).map_err(|_| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("${} is not set", name))
+                })
+            };
+            let username = match (&self.username, &env.username) {
+                (Some(username), _) => username.clone(),
+                (None, Some(name)) => var(name)?,
+                (None, None) => return Err(invalid("no username")),
+            };
+            return Secret::new(username, var(&env.password)?);
+        }
+        if let Some(keyring) = &self.keyring {
+            let mut command = vec!["secret-tool".to_string(), "lookup".to_string()];
+            for (attribute, value) in keyring {
+                command.push(attribute.clone());
+                command.push(value.clone());
+            }
+            return parse(&run(&command)?, self.username.as_deref(), "the keyring");
+        }
+        match &self.command {
+            Some(command) => parse(&run(command)?, self.username.as_deref(), &command[0]),
+            None => Err(invalid("no source")),
+        }
+    }
+}
+
+// Which profiles and where from, for --check-config; never what the source holds
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let profiles = match (&self.provider, &self.profiles) {
+            (Some(provider), Some(pattern)) => format!("{} {}", provider, pattern),
+            (Some(provider), None) => provider.clone(),
+            (None, Some(pattern)) => pattern.clone(),
+            (None, None) => "all".to_string(),
+        };
+        let source = if let Some(file) = &self.file {
+            file.clone()
+        } else if let Some(env) = &self.env {
+            format!("${}", env.password)
+        } else if self.keyring.is_some() {
+            "keyring".to_string()
+        } else {
+            self.command
+                .as_ref()
+                .map(|command| command.join(" "))
+                .unwrap_or_default()
+        };
+        write!(f, "{} from {}", profiles, source)
+    }
+}
+
+pub(crate) struct Secret {
+    username: String,
+    password: String,
+}
+
+impl Secret {
+    fn new(username: String, password: String) -> Result<Self, io::Error> {
+        if username.is_empty() || password.is_empty() {
+            return Err(invalid("empty username or password"));
+        }
+        Ok(Self { username, password })
+    }
+}
+
+// Never let the password reach a log through `{:?}`
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secret")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/*
+    The credentials for `profile`: from the first rule that matches it, else from the auth file.
+    None when no rule matches and the profile does not ask for credentials, then OpenVPN is not
+    given any.
+*/
+pub(crate) fn resolve(
+    rules: &[Rule],
+    auth_file: &str,
+    profile: &Profile,
+    main_dir: &str,
+) -> Result<Option<Secret>, io::Error> {
+    let rule = rules.iter().find(|rule| rule.matches(profile, main_dir));
+    let secret = match rule {
+        Some(rule) => rule.read(),
+        None if profile.auth_user_pass => read_file(Path::new(auth_file), None),
+        None => return Ok(None),
+    };
+    secret.map(Some).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("No credentials for {}: {}", profile.name, e),
+        )
+    })
+}
+
+/*
+    Hands the credentials to OpenVPN through a FIFO in a directory only we can enter, so they are
+    never written to disk. A thread waits for OpenVPN to open it, writes them and removes the
+    FIFO, or removes it unread after `timeout`. OpenVPN keeps them in memory after reading, a
+    profile with `auth-nocache` cannot reconnect on its own and is restarted by the runner.
+*/
+pub(crate) fn hand_off(secret: Secret, timeout: Duration) -> Result<PathBuf, io::Error> {
+    let dir = tempfile::Builder::new()
+        .prefix("vpn_handler-auth-")
+        .tempdir()?;
+    // Before anything is put in it
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+    let path = dir.path().join("auth");
+    let name = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::mkfifo(name.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fifo = path.clone();
+    std::thread::Builder::new()
+        .name("auth-handoff".to_string())
+        .spawn(move || {
+            write_when_read(&fifo, &secret, timeout).ok();
+            // Removes the FIFO with its directory
+            drop(dir);
+        })?;
+    Ok(path)
+}
+
+fn write_when_read(fifo: &Path, secret: &Secret, timeout: Duration) -> Result<(), io::Error> {
+    let started = Instant::now();
+    loop {
+        // Opening for writing without blocking fails with ENXIO until a reader is there
+        match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(fifo)
+        {
+            Ok(mut pipe) => {
+                return pipe
+                    .write_all(format!("{}\n{}\n", secret.username, secret.password).as_bytes());
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) && started.elapsed() < timeout => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Like OpenVPN's own auth file, which must not be readable by anyone else
+fn read_file(path: &Path, username: Option<&str>) -> Result<Secret, io::Error> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let metadata = file.metadata()?;
+    if metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} can be read by others (mode {:o}), chmod 600 it",
+                path.display(),
+                metadata.mode() & 0o777
+            ),
+        ));
+    }
+    let owner = metadata.uid();
+    if owner != 0 && owner != unsafe { libc::geteuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} belongs to someone else (uid {})", path.display(), owner),
+        ));
+    }
+
+    let mut contents = String::new();
+    (&file).read_to_string(&mut contents)?;
+    parse(&contents, username, &path.display().to_string())
+}
+
+fn parse(output: &str, username: Option<&str>, source: &str) -> Result<Secret, io::Error> {
+    let mut lines = output.lines().map(|line| line.trim_end_matches('\r'));
+    let secret = match username {
+        Some(username) => Secret::new(
+            username.to_string(),
+            lines.next().unwrap_or_default().to_string(),
+        ),
+        None => Secret::new(
+            lines.next().unwrap_or_default().to_string(),
+            lines.next().unwrap_or_default().to_string(),
+        ),
+    };
+    secret.map_err(|_| invalid(&format!("{} gave no username and password", source)))
+}
+
+// Stdout of `command`, which has to exit successfully within COMMAND_TIMEOUT
+fn run(command: &[String]) -> Result<String, io::Error> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", command[0], e)))?;
+
+    // Read while waiting, a helper that fills a pipe would otherwise block until it times out
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > COMMAND_TIMEOUT {
+            // Killing it closes the pipes, which lets the readers finish
+            child.kill().ok();
+            child.wait().ok();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} did not finish in time", command[0]),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+
+    let stdout = stdout
+        .join()
+        .map_err(|_| io::Error::other("stdout reader panicked"))??;
+    let stderr = stderr.join().ok().and_then(Result::ok).unwrap_or_default();
+    let stdout = String::from_utf8(stdout).map_err(|e| invalid(&e.to_string()))?;
+    let stderr = String::from_utf8_lossy(&stderr);
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed ({}): {}",
+            command[0],
+            status,
+            stderr.trim()
+        )));
+    }
+    Ok(stdout)
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Result<Vec<u8>, io::Error>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut output)?;
+        }
+        Ok(output)
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN_DIR: &str = "/vpn";
+
+    fn profile(path: &str, provider: Option<&str>) -> Profile {
+        Profile {
+            path: path.to_string(),
+            name: Path::new(path)
+                .file_stem()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+            provider: provider.map(str::to_string),
+            auth_user_pass: true,
+            ..Profile::default()
+        }
+    }
+
+    fn command(script: &str) -> Rule {
+        Rule {
+            command: Some(vec!["sh".to_string(), "-c".to_string(), script.to_string()]),
+            ..Rule::default()
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = vec![
+            Rule {
+                provider: Some("NordVPN".to_string()),
+                username: Some("nord-user".to_string()),
+                ..command("echo nord-pass")
+            },
+            Rule {
+                profiles: Some("work/*".to_string()),
+                ..command("printf 'work-user\\nwork-pass\\n'")
+            },
+            Rule {
+                profiles: Some("broken*".to_string()),
+                ..command("echo oops >&2; exit 3")
+            },
+        ];
+        assert!(rules.iter().all(|rule| rule.validate().is_ok()));
+
+        let nord = profile("/vpn/nordvpn/se.ovpn", Some("nordvpn"));
+        let secret = resolve(&rules, "/nonexistent", &nord, MAIN_DIR)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (secret.username.as_str(), secret.password.as_str()),
+            ("nord-user", "nord-pass")
+        );
+        assert!(!format!("{:?}", secret).contains("nord-pass"));
+
+        let work = profile("/vpn/work/office.ovpn", None);
+        let secret = resolve(&rules, "/nonexistent", &work, MAIN_DIR)
+            .unwrap()
+            .unwrap();
+        assert_eq!(secret.username, "work-user");
+        assert_eq!(secret.password, "work-pass");
+
+        let error = resolve(&rules, "/", &profile("/vpn/broken.ovpn", None), MAIN_DIR);
+        assert!(error.unwrap_err().to_string().contains("oops"));
+
+        // More than a pipe holds is read while the helper runs
+        let chatty = command("head -c 1000000 /dev/zero >&2; echo user; echo pass");
+        let secret = chatty.read().unwrap();
+        assert_eq!(secret.username, "user");
+
+        // Nothing matches: the auth file, unless the profile does not want credentials
+        let mut other = profile("/vpn/us.ovpn", None);
+        assert!(resolve(&rules, "/nonexistent", &other, MAIN_DIR).is_err());
+        other.auth_user_pass = false;
+        assert!(
+            resolve(&rules, "/nonexistent", &other, MAIN_DIR)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Rule::default().validate().is_err());
+        let rule = Rule {
+            file: Some("auth.txt".to_string()),
+            ..command("true")
+        };
+        assert!(rule.validate().is_err());
+        let rule = Rule {
+            env: Some(EnvNames {
+                username: None,
+                password: "VPN_PASS".to_string(),
+            }),
+            ..Rule::default()
+        };
+        assert!(rule.validate().is_err());
+        let mut rule = Rule {
+            keyring: Some(BTreeMap::from([("vpn".to_string(), "nordvpn".to_string())])),
+            ..Rule::default()
+        };
+        assert!(rule.validate().is_err());
+        rule.username = Some("nord-user".to_string());
+        assert!(rule.validate().is_ok());
+        assert!(command("").validate().is_ok());
+        let rule = Rule {
+            command: Some(Vec::new()),
+            ..Rule::default()
+        };
+        assert!(rule.validate().is_err());
+    }
+
+    #[test]
+    fn test_auth_file_permissions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("auth.txt");
+        std::fs::write(&path, "user\npass\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let error = read_file(&path, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let secret = read_file(&path, None).unwrap();
+        assert_eq!(secret.username, "user");
+        assert_eq!(secret.password, "pass");
+        assert!(read_file(&path, Some("other")).is_ok());
+    }
+
+    #[test]
+    fn test_hand_off() {
+        let secret = Secret::new("user".to_string(), "pass".to_string()).unwrap();
+        let path = hand_off(secret, Duration::from_secs(5)).unwrap();
+        let mode = std::fs::metadata(path.parent().unwrap()).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "user\npass\n");
+        // Gone once read
+        let started = Instant::now();
+        while path.parent().unwrap().exists() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!path.exists());
+
+        // Nobody came for it
+        let secret = Secret::new("user".to_string(), "pass".to_string()).unwrap();
+        let path = hand_off(secret, Duration::from_millis(100)).unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(!path.exists());
+    }
+}
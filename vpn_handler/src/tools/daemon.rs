@@ -0,0 +1,387 @@
+use crate::tools::access::AccessPolicy;
+use crate::tools::config;
+use crate::tools::events::{Event, EventBus};
+use crate::tools::logger::Logger;
+use crate::tools::notifier::Notifier;
+use crate::tools::overrides::{self, Override, OverrideMode};
+use crate::tools::protocol::{ErrorBody, ErrorCode};
+use crate::tools::quarantine::QuarantineAction;
+use crate::tools::runner::{self, Heartbeat, RunnerCommand, RunnerContext};
+use crate::tools::selection;
+use crate::tools::settings::Settings;
+use crate::tools::status::Status;
+use crate::tools::supervisor::{CancelToken, RunFn, RunnerState, Supervisor};
+use serde_json::{Value, json};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, channel};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Switching servers waits for OpenVPN to come up, so give the runner a while to answer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/*
+    State shared by every control connection. Each client is served on its own thread, so
+    nothing in here may block while holding a lock for longer than a state change.
+*/
+pub(crate) struct Daemon {
+    logger: Arc<Mutex<Logger>>,
+    status: Arc<Mutex<Status>>,
+    events: Arc<EventBus>,
+    config: Arc<config::File>,
+    overrides: Arc<Mutex<Override>>,
+    settings: Arc<Mutex<Settings>>,
+    access: Mutex<AccessPolicy>,
+    runner: Arc<Supervisor>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+impl Daemon {
+    pub(crate) fn new(
+        logger: Arc<Mutex<Logger>>,
+        notifier: Arc<Mutex<Notifier>>,
+        settings: Settings,
+        config: Arc<config::File>,
+    ) -> Self {
+        let access = AccessPolicy::new(&settings);
+        let restart = settings.restart;
+        let context = RunnerContext {
+            logger,
+            notifier,
+            config,
+            overrides: Arc::new(Mutex::new(Override::new())),
+            status: Arc::new(Mutex::new(Status::new())),
+            events: Arc::new(EventBus::new()),
+            settings: Arc::new(Mutex::new(settings)),
+            heartbeat: Arc::new(Heartbeat::new()),
+        };
+        let runner = Supervisor::new(
+            run_with(context.clone()),
+            restart,
+            Arc::clone(&context.logger),
+            Arc::clone(&context.events),
+        );
+
+        Self {
+            logger: context.logger,
+            status: context.status,
+            events: context.events,
+            config: context.config,
+            overrides: context.overrides,
+            settings: context.settings,
+            access: Mutex::new(access),
+            runner: Arc::new(runner),
+            heartbeat: context.heartbeat,
+        }
+    }
+
+    pub(crate) fn logger(&self) -> &Arc<Mutex<Logger>> {
+        &self.logger
+    }
+
+    pub(crate) fn events(&self) -> &Arc<EventBus> {
+        &self.events
+    }
+
+    pub(crate) fn config(&self) -> &Arc<config::File> {
+        &self.config
+    }
+
+    // A copy, so a reload never changes the rules under a check that is already running
+    pub(crate) fn access(&self) -> AccessPolicy {
+        self.access
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn log(&self, msg: &str) {
+        if let Ok(logger) = self.logger.lock() {
+            logger.log(&msg.to_string()).ok();
+        }
+    }
+
+    pub(crate) fn status_report(&self) -> Value {
+        let state = self.runner_state();
+        let mut report = self
+            .status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .to_json();
+        report["running"] = json!(state.is_active());
+        report["state"] = json!(state);
+        // What the connected profile is, so clients can show where the traffic goes
+        let profile = report["profile"]
+            .as_str()
+            .and_then(|path| self.config.get_profile(path));
+        report["profile_info"] = match profile {
+            Some(profile) => profile.to_json(),
+            None => Value::Null,
+        };
+        report["runner"] = self.runner.to_json();
+        report["override"] =
+            overrides::current(&self.overrides, &self.logger, &self.events).to_json();
+        report
+    }
+
+    /*
+        What the systemd watchdog asks before each ping. A deadlock on the shared state blocks
+        this call, and so the pings, and a running runner has to have made a pass recently.
+    */
+    pub(crate) fn is_alive(&self) -> bool {
+        drop(self.status.lock().unwrap_or_else(|e| e.into_inner()));
+        let budget = runner::pass_budget(&self.settings.lock().unwrap_or_else(|e| e.into_inner()));
+        self.runner_state() != RunnerState::Running || self.heartbeat.age() <= budget
+    }
+
+    pub(crate) fn runner_state(&self) -> RunnerState {
+        self.runner.state()
+    }
+
+    pub(crate) fn start(&self) -> Result<(), ErrorBody> {
+        self.runner.start()
+    }
+
+    // Only asks the runner to stop, callers never wait on OpenVPN being torn down unless they
+    // choose to through `wait_for_stop`
+    pub(crate) fn stop(&self) -> Result<RunnerState, ErrorBody> {
+        self.runner.stop()
+    }
+
+    pub(crate) fn wait_for_stop(&self, timeout: Duration) -> RunnerState {
+        self.runner.wait_for_stop(timeout)
+    }
+
+    // Hands the request to the runner, which owns OpenVPN, and waits for it to be carried out
+    pub(crate) fn connect(&self, query: &str) -> Result<Value, ErrorBody> {
+        let commands = self.runner.commands().ok_or_else(|| {
+            ErrorBody::new(
+                ErrorCode::NotRunning,
+                "Daemon is not running, start it first",
+            )
+        })?;
+
+        let (reply, response) = channel();
+        let command = RunnerCommand::Connect {
+            query: query.to_string(),
+            reply,
+        };
+        if commands.send(command).is_err() {
+            return Err(ErrorBody::new(
+                ErrorCode::NotRunning,
+                "Runner exited before it could connect",
+            ));
+        }
+        match response.recv_timeout(CONNECT_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Disconnected) => Err(ErrorBody::new(
+                ErrorCode::NotRunning,
+                "Runner exited before it could connect",
+            )),
+            Err(RecvTimeoutError::Timeout) => Err(ErrorBody::new(
+                ErrorCode::Busy,
+                "Runner did not answer in time, check status later",
+            )),
+        }
+    }
+
+    // Takes effect on the runner's next pass, or as soon as it is started
+    pub(crate) fn set_override(&self, mode: OverrideMode, duration: Option<Duration>) -> Value {
+        let state = {
+            let mut state = self.overrides.lock().unwrap_or_else(|e| e.into_inner());
+            state.set(mode, duration);
+            state.clone()
+        };
+        let until = state
+            .get_until()
+            .map(|until| until.format("%Y-%m-%d %H:%M:%S").to_string());
+
+        self.log(&match (mode, &until) {
+            (OverrideMode::FollowSwitch, _) => "Override cleared, following the switch".to_string(),
+            (_, Some(until)) => format!("Override {} until {}", mode.get_name(), until),
+            (_, None) => format!("Override {} until cleared", mode.get_name()),
+        });
+        self.events.publish(Event::OverrideChanged { mode, until });
+        state.to_json()
+    }
+
+    // Lists, clears or pins quarantined profiles, answering with the quarantine as it now is
+    pub(crate) fn quarantine(&self, action: QuarantineAction) -> Result<Value, ErrorBody> {
+        let changed = match &action {
+            QuarantineAction::List => Ok(Vec::new()),
+            QuarantineAction::Clear(query) => self.config.clear_quarantine(query.as_deref()),
+            QuarantineAction::Pin(query) => self.config.pin_profiles(query),
+        }
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                ErrorBody::new(ErrorCode::ProfileNotFound, e.to_string())
+            }
+            _ => ErrorBody::new(
+                ErrorCode::Internal,
+                format!("Failed to save quarantine: {}", e),
+            ),
+        })?;
+        if !changed.is_empty() {
+            let verb = match action {
+                QuarantineAction::Pin(_) => "Pinned",
+                _ => "Cleared",
+            };
+            self.log(&format!("{} in quarantine: {}", verb, changed.join(", ")));
+        }
+
+        let mut report = self.config.quarantine_report();
+        report["changed"] = json!(changed);
+        Ok(report)
+    }
+
+    /*
+        Picks up profiles that were added, removed or edited on disk, called by the profile
+        watcher. A profile deleted while it is connected is left running: OpenVPN read it when it
+        started, and the next pick only sees what is still there.
+    */
+    pub(crate) fn rescan(&self) {
+        let profiles = match self.config.reload() {
+            Ok(profiles) => profiles,
+            Err(e) => {
+                self.log(&format!("Failed to rescan profiles: {}", e));
+                return;
+            }
+        };
+        // Edits only show in the lint reports, nothing worth telling subscribers about
+        if profiles.added.is_empty() && profiles.removed.is_empty() {
+            return;
+        }
+
+        self.log(&format!(
+            "Profiles changed on disk: {} profiles ({} added, {} removed, {} invalid)",
+            profiles.count,
+            profiles.added.len(),
+            profiles.removed.len(),
+            profiles.invalid,
+        ));
+        let connected = self
+            .status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_profile()
+            .map(str::to_string);
+        if let Some(profile) = connected.filter(|profile| profiles.removed.contains(profile)) {
+            self.log(&format!(
+                "Connected profile {} was removed, staying connected until the next switch",
+                profile
+            ));
+        }
+        self.events.publish(Event::Reloaded {
+            added: profiles.added,
+            removed: profiles.removed,
+            settings: Vec::new(),
+        });
+    }
+
+    /*
+        Re-reads the settings and rescans the profile directory. Both are loaded and checked
+        before anything is swapped in, so a bad setting or an unreadable directory leaves
+        everything as it was, and the settings are only locked to commit the results.
+        Clients already connected keep the access level they were given, the runner picks up the
+        serial port on its next start and the timings right away.
+    */
+    pub(crate) fn reload(&self) -> Result<Value, ErrorBody> {
+        let config_path = self
+            .settings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .config_path
+            .clone();
+        let mut new_settings = Settings::load(config_path.as_deref()).map_err(|e| {
+            ErrorBody::new(
+                ErrorCode::Internal,
+                format!("Failed to load settings: {}", e),
+            )
+        })?;
+        let weights = selection::compile_weights(&new_settings.weights)
+            .map_err(|e| ErrorBody::new(ErrorCode::Internal, e.to_string()))?;
+        let limits = new_settings.scan_limits();
+        // A rescan from the watcher waits, it would otherwise apply what it found over this
+        let _scanning = self.config.lock_scan();
+        let scanned = self.config.scan(&limits).map_err(|e| {
+            ErrorBody::new(
+                ErrorCode::Internal,
+                format!("Failed to scan profiles: {}", e),
+            )
+        })?;
+
+        // Everything loaded, nothing from here on can fail
+        let mut settings = self.settings.lock().unwrap_or_else(|e| e.into_inner());
+        self.config.set_limits(limits);
+        let profiles = self.config.apply_scan(scanned);
+        self.config
+            .configure_selection(new_settings.strategy, weights);
+        self.config
+            .configure_quarantine(new_settings.cool_down, new_settings.max_cool_down);
+        self.config
+            .configure_credentials(new_settings.credentials.clone());
+
+        let changed = settings.changed_fields(&new_settings);
+        new_settings.keep_startup_values(&settings);
+        *self.access.lock().unwrap_or_else(|e| e.into_inner()) = AccessPolicy::new(&new_settings);
+        // Applies from the next failure on, restarts already counted stay counted
+        self.runner.set_policy(new_settings.restart);
+        *settings = new_settings;
+        drop(settings);
+
+        self.log(&format!(
+            "Reloaded: {} profiles ({} added, {} removed, {} invalid), settings changed: {}",
+            profiles.count,
+            profiles.added.len(),
+            profiles.removed.len(),
+            profiles.invalid,
+            if changed.is_empty() {
+                "none".to_string()
+            } else {
+                changed.join(", ")
+            }
+        ));
+        self.events.publish(Event::Reloaded {
+            added: profiles.added.clone(),
+            removed: profiles.removed.clone(),
+            settings: changed.iter().map(|field| field.to_string()).collect(),
+        });
+
+        Ok(json!({
+            "profiles": {
+                "count": profiles.count,
+                "added": profiles.added,
+                "removed": profiles.removed,
+                "invalid": profiles.invalid,
+            },
+            "settings": {
+                "changed": changed,
+                // Sockets, the log and the profile directory are set up once at startup
+                "restart_required": changed
+                    .iter()
+                    .filter(|field| Settings::needs_restart(field))
+                    .collect::<Vec<_>>(),
+            },
+        }))
+    }
+}
+
+// What the supervisor runs: the runner itself, with its failures recorded for status and subscribers
+fn run_with(context: RunnerContext) -> Arc<RunFn> {
+    Arc::new(
+        move |token: &CancelToken, commands: &Receiver<RunnerCommand>| {
+            runner::run(&context, token, commands).map_err(|e| {
+                let msg = format!("Runner encountered error: {:?}", e);
+                {
+                    let mut status = context.status.lock().unwrap_or_else(|e| e.into_inner());
+                    status.record_error(&msg);
+                    status.record_disconnected();
+                    status.set_serial_port(None);
+                }
+                context.events.publish(Event::Error {
+                    message: msg.to_string(),
+                });
+                msg
+            })
+        },
+    )
+}
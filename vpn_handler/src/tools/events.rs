@@ -0,0 +1,215 @@
+use crate::tools::overrides::OverrideMode;
+use crate::tools::protocol::PROTOCOL_VERSION;
+use crate::tools::supervisor::RunnerState;
+use chrono::{Local, NaiveDateTime};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+
+// A subscriber that falls this far behind is dropped instead of buffering without bound
+const SUBSCRIBER_BACKLOG: usize = 64;
+
+pub(crate) const EVENT_NAMES: &[&str] = &[
+    "switch_toggled",
+    "vpn_connecting",
+    "vpn_connected",
+    "vpn_failed",
+    "vpn_reconnecting",
+    "vpn_disconnected",
+    "runner_state",
+    "error",
+    "log_rotated",
+    "reloaded",
+    "override_changed",
+];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub(crate) enum Event {
+    SwitchToggled {
+        position: String,
+    },
+    VpnConnecting,
+    VpnConnected {
+        profile: Option<String>,
+        pid: Option<u32>,
+    },
+    // A tunnel was started but never came up, `kind` names a failure OpenVPN printed, if any
+    VpnFailed {
+        profile: Option<String>,
+        reason: String,
+        kind: Option<&'static str>,
+    },
+    // The next try at getting a lost or failed tunnel back, `attempt` counts from 1
+    VpnReconnecting {
+        attempt: u32,
+        delay_secs: u64,
+    },
+    VpnDisconnected,
+    RunnerState {
+        state: RunnerState,
+    },
+    Error {
+        message: String,
+    },
+    LogRotated,
+    Reloaded {
+        added: Vec<String>,
+        removed: Vec<String>,
+        settings: Vec<String>,
+    },
+    OverrideChanged {
+        mode: OverrideMode,
+        until: Option<String>,
+    },
+}
+
+impl Event {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Event::SwitchToggled { .. } => "switch_toggled",
+            Event::VpnConnecting => "vpn_connecting",
+            Event::VpnConnected { .. } => "vpn_connected",
+            Event::VpnFailed { .. } => "vpn_failed",
+            Event::VpnReconnecting { .. } => "vpn_reconnecting",
+            Event::VpnDisconnected => "vpn_disconnected",
+            Event::RunnerState { .. } => "runner_state",
+            Event::Error { .. } => "error",
+            Event::LogRotated => "log_rotated",
+            Event::Reloaded { .. } => "reloaded",
+            Event::OverrideChanged { .. } => "override_changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct EventRecord {
+    pub(crate) at: NaiveDateTime,
+    pub(crate) event: Event,
+}
+
+impl EventRecord {
+    pub(crate) fn to_line(&self) -> String {
+        let mut record = serde_json::to_value(&self.event).unwrap_or(Value::Null);
+        record["v"] = json!(PROTOCOL_VERSION);
+        record["at"] = json!(self.at.format("%Y-%m-%d %H:%M:%S").to_string());
+        let mut line = record.to_string();
+        line.push('\n');
+        line
+    }
+}
+
+pub(crate) struct EventBus {
+    subscribers: Mutex<Vec<SyncSender<EventRecord>>>,
+}
+
+impl EventBus {
+    pub(crate) fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> Receiver<EventRecord> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BACKLOG);
+        self.lock_subscribers().push(sender);
+        receiver
+    }
+
+    pub(crate) fn publish(&self, event: Event) {
+        let record = EventRecord {
+            at: Local::now().naive_local(),
+            event,
+        };
+        // Subscribers that hung up or stopped reading are forgotten here
+        self.lock_subscribers()
+            .retain(|subscriber| match subscriber.try_send(record.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get_subscriber_count(&self) -> usize {
+        self.lock_subscribers().len()
+    }
+
+    fn lock_subscribers(&self) -> std::sync::MutexGuard<'_, Vec<SyncSender<EventRecord>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_reaches_every_subscriber() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+
+        bus.publish(Event::SwitchToggled {
+            position: "on".to_string(),
+        });
+
+        for receiver in [first, second] {
+            let record = receiver.try_recv();
+            assert!(record.is_ok(), "Subscriber missed the event");
+            assert_eq!(
+                record.unwrap().event,
+                Event::SwitchToggled {
+                    position: "on".to_string()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_dropped_and_slow_subscribers_are_removed() {
+        let bus = EventBus::new();
+        let gone = bus.subscribe();
+        let _slow = bus.subscribe();
+        drop(gone);
+
+        // The dropped receiver goes away on the first publish
+        bus.publish(Event::VpnConnecting);
+        assert_eq!(bus.get_subscriber_count(), 1);
+
+        // The slow one goes away once its backlog is full
+        for _ in 0..SUBSCRIBER_BACKLOG {
+            bus.publish(Event::LogRotated);
+        }
+        assert_eq!(bus.get_subscriber_count(), 0);
+    }
+
+    #[test]
+    fn test_record_line() {
+        let record = EventRecord {
+            at: Local::now().naive_local(),
+            event: Event::VpnConnected {
+                profile: Some("/vpn/se.ovpn".to_string()),
+                pid: Some(10),
+            },
+        };
+        let line = record.to_line();
+        assert!(line.ends_with('\n'));
+
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["event"], json!("vpn_connected"));
+        assert_eq!(parsed["data"]["pid"], json!(10));
+        assert_eq!(parsed["v"], json!(PROTOCOL_VERSION));
+        assert!(parsed["at"].is_string());
+
+        let parsed: Value = serde_json::from_str(
+            &EventRecord {
+                at: record.at,
+                event: Event::LogRotated,
+            }
+            .to_line(),
+        )
+        .unwrap();
+        assert_eq!(parsed["event"], json!(Event::LogRotated.name()));
+    }
+}
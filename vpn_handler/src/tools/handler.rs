@@ -1,77 +1,188 @@
+use crate::tools::backend::{BackendStatus, Backends, Health, Teardown, VpnBackend};
 use crate::tools::config;
-use std::process::{Child, Command};
+use crate::tools::output::Failure;
+#[cfg(test)]
+use crate::tools::settings::Settings;
+use crate::tools::supervisor::CancelToken;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub(crate) struct Handler {
-    config: config::File,
-    child: Option<Child>,
+    config: Arc<config::File>,
+    backends: Backends,
+    // The tunnel that is up, by whichever backend suits its profile
+    backend: Option<Box<dyn VpnBackend>>,
+    profile: Option<String>,
+    attempts: u32,
+    stop_timeout: Duration,
 }
 
 impl Handler {
+    #[cfg(test)]
     pub(crate) fn new() -> Result<Self, std::io::Error> {
-        let config = config::File::new();
+        let settings = Settings::default();
+        let config = config::File::new(&settings.profile_dir, &settings.auth_file);
         config.init()?;
 
-        Ok(Self {
+        Ok(Self::with_config(Arc::new(config)))
+    }
+
+    // Shares the profile list with the daemon so a reload reaches a running handler
+    pub(crate) fn with_config(config: Arc<config::File>) -> Self {
+        Self {
             config,
-            child: None,
-        })
+            backends: Backends::default(),
+            backend: None,
+            profile: None,
+            attempts: 10,
+            stop_timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub(crate) fn with_backends(mut self, backends: Backends) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    // How often spawning OpenVPN is tried, a second apart, before giving up
+    pub(crate) fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    // How long a tunnel gets to go down cleanly before it is killed
+    pub(crate) fn with_stop_timeout(mut self, timeout: Duration) -> Self {
+        self.stop_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn get_profile(&self) -> Option<&String> {
+        self.profile.as_ref()
+    }
+
+    pub(crate) fn get_pid(&self) -> Option<u32> {
+        self.get_status().and_then(|status| status.pid)
+    }
+
+    pub(crate) fn get_status(&self) -> Option<BackendStatus> {
+        self.backend.as_ref().map(|backend| backend.status())
+    }
+
+    // Down once the tunnel went away on its own, e.g. OpenVPN exiting, with the reason
+    pub(crate) fn health(&mut self) -> Health {
+        match self.backend.as_mut() {
+            Some(backend) => backend.health(),
+            None => Health::Down("No tunnel is up".to_string()),
+        }
+    }
+
+    // What the tunnel reported going wrong, e.g. rejected credentials, see `output::Failure`
+    pub(crate) fn get_failure(&self) -> Option<Failure> {
+        self.backend.as_ref().and_then(|backend| backend.failure())
     }
 
     pub(crate) fn start(&mut self) -> Result<(), std::io::Error> {
-        if self.child.is_some() {
+        self.spawn(|config| config.pick_profile())
+    }
+
+    // Fails over: any profile the selection strategy picks, except those in `avoid`
+    pub(crate) fn start_other(&mut self, avoid: &[String]) -> Result<(), std::io::Error> {
+        self.spawn(|config| config.pick_profile_avoiding(avoid))
+    }
+
+    pub(crate) fn find_profile(&self, query: &str) -> Result<String, std::io::Error> {
+        self.config.find_profile(query)
+    }
+
+    // Starts OpenVPN with the profile matching `query`, see `config::File::find_profile`
+    pub(crate) fn start_profile(&mut self, query: &str) -> Result<(), std::io::Error> {
+        let profile = self.config.find_profile(query)?;
+        self.spawn(|_| Ok(profile.clone()))
+    }
+
+    /*
+        Moves a running connection to another server. Fails before touching the current
+        connection when `query` matches nothing; a failed start leaves no tunnel up.
+    */
+    pub(crate) fn switch_profile(&mut self, query: &str) -> Result<(), std::io::Error> {
+        let profile = self.config.find_profile(query)?;
+        self.stop()?;
+        self.spawn(|_| Ok(profile.clone()))
+    }
+
+    fn spawn(
+        &mut self,
+        pick: impl Fn(&config::File) -> Result<String, std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        if self.backend.is_some() {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::AlreadyExists,
-                "OpenVPN is already running",
+                "A tunnel is already up",
             ));
         }
-        for _ in 0..10 {
-            let child = Command::new("openvpn")
-                .arg("--config")
-                .arg(self.config.get_random_file_path()?)
-                .arg("--auth-user-pass")
-                .arg(self.config.get_auth())
-                .spawn();
-            match child {
-                Ok(child) => {
-                    println!("OpenVPN process started.");
-                    self.child = Some(child);
+        let mut failure = None;
+        for attempt in 1..=self.attempts {
+            let profile = pick(&self.config)?;
+            let mut backend = self.backends.create(self.config.profile_kind(&profile));
+            let secret = match backend.wants_credentials() {
+                true => self.config.credentials_for(&profile)?,
+                false => None,
+            };
+            match backend.start(&profile, secret) {
+                Ok(_) => {
+                    self.backend = Some(backend);
+                    self.profile = Some(profile);
                     return Ok(());
                 }
-                Err(_) => {
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                }
-            }
-        }
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Failed to start OpenVPN",
-        ))
-    }
-
-    pub(crate) fn stop(&mut self) -> Result<(), std::io::Error> {
-        match self.child.take() {
-            Some(mut child) => {
-                for _ in 0..10 {
-                    match child.kill() {
-                        Ok(_) => return Ok(()),
-                        Err(_) => {
-                            std::thread::sleep(std::time::Duration::from_secs(1));
-                        }
+                Err(e) => {
+                    failure = Some(format!("{}: {}", backend.status().backend, e));
+                    if attempt < self.attempts {
+                        std::thread::sleep(std::time::Duration::from_secs(1));
                     }
                 }
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to stop OpenVPN",
-                ))
             }
-            None => Ok(()),
+        }
+        Err(std::io::Error::other(format!(
+            "Failed to start the tunnel, {}",
+            failure.unwrap_or_else(|| "no attempts allowed".to_string())
+        )))
+    }
+
+    // Waits for the tunnel that was started to carry traffic, see `VpnBackend::wait_connected`
+    pub(crate) fn wait_connected(
+        &mut self,
+        timeout: Duration,
+        token: &CancelToken,
+    ) -> Result<(), std::io::Error> {
+        match self.backend.as_mut() {
+            Some(backend) => backend.wait_connected(timeout, token),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "No tunnel is up",
+            )),
         }
     }
+
+    /*
+        Takes the tunnel down and says how it went, None when nothing was up. A tunnel that could
+        not be stopped is kept, so the next stop has another go at it.
+    */
+    pub(crate) fn stop(&mut self) -> Result<Option<Teardown>, std::io::Error> {
+        let Some(backend) = self.backend.as_mut() else {
+            self.profile = None;
+            return Ok(None);
+        };
+        let teardown = backend.stop(self.stop_timeout)?;
+        self.backend = None;
+        self.profile = None;
+        Ok(Some(teardown))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tools::backend::BackendKind;
 
     #[test]
     fn test_new() {
@@ -89,12 +200,12 @@ mod tests {
         let mut handler = handler.unwrap();
         let result = handler.start();
         assert!(result.is_ok());
-        assert!(handler.child.is_some());
+        assert!(handler.backend.is_some());
 
         //Attempt to stop a handler
         let result = handler.stop();
         assert!(result.is_ok());
-        assert!(handler.child.is_none());
+        assert!(handler.backend.is_none());
     }
 
     #[test]
@@ -105,12 +216,34 @@ mod tests {
 
         //Assert handler.child is None
         let mut handler = handler.unwrap();
-        assert!(handler.child.is_none());
+        assert!(handler.backend.is_none());
 
         //Make sure stopping didnt change anything
         let result = handler.stop();
         assert!(result.is_ok());
-        assert!(handler.child.is_none());
+        assert!(handler.backend.is_none());
+    }
+
+    #[test]
+    fn test_start_unknown_profile() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("se.ovpn"),
+            "remote se.example.com 1194\n<ca>\ncert\n</ca>\n",
+        )
+        .unwrap();
+        let config = config::File::with_dir(dir.path().to_str().unwrap());
+        config.init().unwrap();
+
+        //Nothing may be started when no profile matches
+        let mut handler = Handler::with_config(Arc::new(config))
+            .with_backends(Backends::new(BackendKind::Mock))
+            .with_attempts(1);
+        let result = handler.start_profile("no-such-profile-anywhere");
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert!(handler.backend.is_none());
+        assert!(handler.get_profile().is_none());
     }
 
     #[test]
@@ -123,16 +256,71 @@ mod tests {
         let mut handler = handler.unwrap();
         let result = handler.start();
         assert!(result.is_ok());
-        assert!(handler.child.is_some());
+        assert!(handler.backend.is_some());
 
         //Attempt to start handler again
         let result = handler.start();
         assert!(result.is_err());
-        assert!(handler.child.is_some());
+        assert!(handler.backend.is_some());
 
         //Attempt to stop the running handler
         let result = handler.stop();
         assert!(result.is_ok());
-        assert!(handler.child.is_none());
+        assert!(handler.backend.is_none());
+    }
+
+    #[test]
+    fn test_mock_backend() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("se.ovpn"),
+            "remote se.example.com 1194\n<ca>\ncert\n</ca>\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("ch.conf"),
+            "[Interface]\nPrivateKey = a\n[Peer]\nPublicKey = b\nEndpoint = ch.example.com:51820\n",
+        )
+        .unwrap();
+        let config = config::File::with_dir(dir.path().to_str().unwrap());
+        config.init().unwrap();
+
+        let mut handler = Handler::with_config(Arc::new(config))
+            .with_backends(Backends::new(BackendKind::Mock))
+            .with_attempts(1);
+        handler.start_profile("se").unwrap();
+        handler
+            .wait_connected(Duration::from_secs(1), &CancelToken::new())
+            .unwrap();
+        assert_eq!(handler.health(), Health::Up);
+        assert_eq!(handler.get_status().unwrap().backend, "mock");
+
+        // WireGuard profiles go through the same handler
+        handler.switch_profile("ch").unwrap();
+        assert!(handler.get_profile().unwrap().ends_with("ch.conf"));
+        handler.backends.mock().drop_tunnel();
+        assert_ne!(handler.health(), Health::Up);
+        assert_eq!(handler.get_failure(), None);
+        handler
+            .backends
+            .mock()
+            .report_failure(Failure::TlsHandshake);
+        assert_eq!(handler.get_failure(), Some(Failure::TlsHandshake));
+        assert!(
+            handler
+                .wait_connected(Duration::from_secs(1), &CancelToken::new())
+                .is_err()
+        );
+        assert_eq!(handler.stop().unwrap(), Some(Teardown::default()));
+        assert!(handler.get_status().is_none());
+        assert_eq!(handler.stop().unwrap(), None);
+
+        handler.backends.mock().fail_starts(Some("no route"));
+        let error = handler.start_profile("se").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to start the tunnel, mock: no route"
+        );
+        assert!(handler.get_profile().is_none());
     }
 }
@@ -0,0 +1,787 @@
+use crate::tools::profile::{INLINE_BLOCKS, ProfileKind};
+use crate::tools::severity::Severity;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::Path;
+
+// Client side options OpenVPN 2.4 to 2.6 understand, anything else makes it refuse the profile
+const KNOWN_DIRECTIVES: &[&str] = &[
+    "allow-compression",
+    "allow-pull-fqdn",
+    "askpass",
+    "auth",
+    "auth-nocache",
+    "auth-retry",
+    "auth-token",
+    "auth-token-user",
+    "auth-user-pass",
+    "bind",
+    "block-ipv6",
+    "block-outside-dns",
+    "ca",
+    "cert",
+    "cipher",
+    "client",
+    "compress",
+    "connect-freq",
+    "connect-retry",
+    "connect-retry-max",
+    "connect-timeout",
+    "crl-verify",
+    "cryptoapicert",
+    "daemon",
+    "data-ciphers",
+    "data-ciphers-fallback",
+    "dev",
+    "dev-node",
+    "dev-type",
+    "dh",
+    "dhcp-option",
+    "disable-occ",
+    "dns",
+    "down",
+    "down-pre",
+    "echo",
+    "engine",
+    "explicit-exit-notify",
+    "extra-certs",
+    "fast-io",
+    "float",
+    "fragment",
+    "group",
+    "hand-window",
+    "http-proxy",
+    "http-proxy-option",
+    "http-proxy-retry",
+    "http-proxy-user-pass",
+    "ifconfig",
+    "ifconfig-ipv6",
+    "ifconfig-nowarn",
+    "ignore-unknown-option",
+    "inactive",
+    "ip-win32",
+    "ipchange",
+    "keepalive",
+    "key",
+    "key-direction",
+    "link-mtu",
+    "log",
+    "log-append",
+    "lport",
+    "machine-readable-output",
+    "management",
+    "management-hold",
+    "management-query-passwords",
+    "mark",
+    "mssfix",
+    "mtu-disc",
+    "mtu-test",
+    "mute",
+    "mute-replay-warnings",
+    "nice",
+    "nobind",
+    "opt-verify",
+    "peer-id",
+    "persist-key",
+    "persist-local-ip",
+    "persist-remote-ip",
+    "persist-tun",
+    "ping",
+    "ping-exit",
+    "ping-restart",
+    "ping-timer-rem",
+    "pkcs12",
+    "port",
+    "providers",
+    "proto",
+    "proto-force",
+    "pull",
+    "pull-filter",
+    "push-peer-info",
+    "rcvbuf",
+    "redirect-gateway",
+    "redirect-private",
+    "register-dns",
+    "remap-usr1",
+    "remote",
+    "remote-cert-eku",
+    "remote-cert-ku",
+    "remote-cert-tls",
+    "remote-random",
+    "remote-random-hostname",
+    "reneg-bytes",
+    "reneg-pkts",
+    "reneg-sec",
+    "replay-window",
+    "resolv-retry",
+    "route",
+    "route-delay",
+    "route-gateway",
+    "route-ipv6",
+    "route-method",
+    "route-metric",
+    "route-noexec",
+    "route-nopull",
+    "route-up",
+    "rport",
+    "script-security",
+    "secret",
+    "server-poll-timeout",
+    "setenv",
+    "setenv-opt",
+    "setenv-safe",
+    "single-session",
+    "sndbuf",
+    "socks-proxy",
+    "socks-proxy-retry",
+    "static-challenge",
+    "status",
+    "suppress-timestamps",
+    "syslog",
+    "tcp-nodelay",
+    "tls-auth",
+    "tls-cert-profile",
+    "tls-cipher",
+    "tls-ciphersuites",
+    "tls-client",
+    "tls-crypt",
+    "tls-crypt-v2",
+    "tls-exit",
+    "tls-groups",
+    "tls-timeout",
+    "tls-version-max",
+    "tls-version-min",
+    "topology",
+    "tran-window",
+    "tun-mtu",
+    "tun-mtu-extra",
+    "txqueuelen",
+    "up",
+    "up-delay",
+    "up-restart",
+    "user",
+    "verb",
+    "verify-hash",
+    "verify-x509-name",
+    "windows-driver",
+    "writepid",
+    "x509-username-field",
+];
+
+// Options that still work but are on their way out, or are gone and stop OpenVPN cold
+const DEPRECATED_DIRECTIVES: &[(&str, Severity, &str)] = &[
+    (
+        "comp-lzo",
+        Severity::Warning,
+        "deprecated, use compress or allow-compression",
+    ),
+    (
+        "ns-cert-type",
+        Severity::Warning,
+        "deprecated, use remote-cert-tls server",
+    ),
+    ("ncp-ciphers", Severity::Warning, "renamed to data-ciphers"),
+    (
+        "ncp-disable",
+        Severity::Warning,
+        "deprecated, data-ciphers decides",
+    ),
+    (
+        "keysize",
+        Severity::Warning,
+        "deprecated, the cipher sets the key size",
+    ),
+    (
+        "tun-ipv6",
+        Severity::Warning,
+        "ignored, IPv6 is always available",
+    ),
+    (
+        "key-method",
+        Severity::Warning,
+        "ignored, only method 2 is left",
+    ),
+    ("max-routes", Severity::Warning, "ignored since OpenVPN 2.4"),
+    (
+        "no-replay",
+        Severity::Warning,
+        "deprecated, it weakens replay protection",
+    ),
+    (
+        "tls-remote",
+        Severity::Error,
+        "removed in OpenVPN 2.4, use verify-x509-name",
+    ),
+    ("no-iv", Severity::Error, "removed in OpenVPN 2.5"),
+];
+
+// Options that take one value, a second one silently replaces the first
+const SINGLE_VALUED: &[&str] = &[
+    "auth",
+    "ca",
+    "cert",
+    "cipher",
+    "dev",
+    "key",
+    "pkcs12",
+    "port",
+    "proto",
+    "tls-auth",
+    "tls-crypt",
+];
+
+// Options naming a file, `[inline]` points at a block in the profile instead
+const FILE_DIRECTIVES: &[&str] = &[
+    "ca",
+    "cert",
+    "key",
+    "pkcs12",
+    "tls-auth",
+    "tls-crypt",
+    "tls-crypt-v2",
+    "secret",
+    "dh",
+    "extra-certs",
+    "crl-verify",
+];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Finding {
+    pub(crate) severity: Severity,
+    pub(crate) line: Option<usize>,
+    pub(crate) message: String,
+}
+
+// Everything wrong with one file, errors keep it out of the profile list
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Report {
+    pub(crate) path: String,
+    pub(crate) findings: Vec<Finding>,
+}
+
+impl Report {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            findings: Vec::new(),
+        }
+    }
+
+    pub(crate) fn unreadable(path: &str, error: &std::io::Error) -> Self {
+        Self::single(path, Severity::Error, format!("Cannot read: {}", error))
+    }
+
+    pub(crate) fn single(path: &str, severity: Severity, message: String) -> Self {
+        let mut report = Self::new(path);
+        report.add(severity, None, message);
+        report
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        self.findings
+            .iter()
+            .all(|finding| finding.severity < Severity::Error)
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let mut report = serde_json::to_value(self).unwrap_or(Value::Null);
+        report["valid"] = Value::Bool(self.is_valid());
+        report
+    }
+
+    fn add(&mut self, severity: Severity, line: Option<usize>, message: String) {
+        self.findings.push(Finding {
+            severity,
+            line,
+            message,
+        });
+    }
+}
+
+/*
+    Checks profiles before they are handed to the selection, so a README or a half downloaded
+    bundle is never what the switch connects to. One `Linter` is used per scan: it remembers what
+    it has seen to catch the same profile filed twice, which would otherwise only skew the odds
+    of being picked.
+*/
+#[derive(Default)]
+pub(crate) struct Linter {
+    seen: HashMap<u64, String>,
+}
+
+impl Linter {
+    // Takes a report from `lint`, which can run on any thread, and adds what only a whole scan sees
+    pub(crate) fn check(&mut self, mut report: Report, fingerprint: u64) -> Report {
+        match self.seen.entry(fingerprint) {
+            Entry::Occupied(first) => {
+                report.add(Severity::Error, None, format!("Same as {}", first.get()))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(report.path.clone());
+            }
+        }
+        report
+    }
+}
+
+// Equal for profiles that only differ in surrounding whitespace
+pub(crate) fn fingerprint(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    String::from_utf8_lossy(contents).trim().hash(&mut hasher);
+    hasher.finish()
+}
+
+pub(crate) fn lint(path: &str, contents: &[u8]) -> Report {
+    let mut report = Report::new(path);
+    if contents.contains(&0) {
+        report.add(
+            Severity::Error,
+            None,
+            "Binary file, not an OpenVPN profile".to_string(),
+        );
+        return report;
+    }
+    let contents = String::from_utf8_lossy(contents);
+    if ProfileKind::of(&contents) == ProfileKind::WireGuard {
+        return lint_wireguard(report, &contents);
+    }
+    let dir = Path::new(path).parent().unwrap_or(Path::new("/"));
+
+    let mut findings = Report::new(path);
+    let mut ignored: Vec<String> = Vec::new();
+    let mut first_seen: HashMap<&str, usize> = HashMap::new();
+    let mut inline: Vec<&str> = Vec::new();
+    let mut open: Option<(&str, usize, bool)> = None;
+    let mut has_remote = false;
+
+    for (index, line) in contents.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if let Some((block, start, has_content)) = open {
+            if line == format!("</{}>", block) {
+                if !has_content {
+                    findings.add(
+                        Severity::Error,
+                        Some(start),
+                        format!("<{}> is empty", block),
+                    );
+                }
+                inline.push(block);
+                open = None;
+            } else if !line.is_empty() {
+                open = Some((block, start, true));
+            }
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(block) = line.strip_prefix('<').and_then(|l| l.strip_suffix('>')) {
+            if let Some(block) = INLINE_BLOCKS.iter().find(|known| **known == block) {
+                open = Some((block, number, false));
+            } else if block == "connection" || block == "/connection" {
+                has_remote |= block == "connection";
+            } else {
+                findings.add(
+                    Severity::Warning,
+                    Some(number),
+                    format!("Unknown block <{}>", block),
+                );
+            }
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let Some(directive) = words.next() else {
+            continue;
+        };
+        // Options may be written like on the command line
+        let directive = directive.trim_start_matches("--");
+        let args: Vec<&str> = words.collect();
+
+        if let Some((_, severity, note)) = DEPRECATED_DIRECTIVES
+            .iter()
+            .find(|(name, _, _)| *name == directive)
+        {
+            findings.add(
+                *severity,
+                Some(number),
+                format!("`{}` is {}", directive, note),
+            );
+            continue;
+        }
+        if !KNOWN_DIRECTIVES.contains(&directive) {
+            if !ignored.iter().any(|name| name == directive) {
+                findings.add(
+                    Severity::Warning,
+                    Some(number),
+                    format!("Unknown directive `{}`", directive),
+                );
+            }
+            continue;
+        }
+
+        match directive {
+            "remote" => has_remote = true,
+            "ignore-unknown-option" => ignored.extend(args.iter().map(|name| name.to_string())),
+            _ => {}
+        }
+        if SINGLE_VALUED.contains(&directive) {
+            match first_seen.entry(directive) {
+                Entry::Occupied(first) => findings.add(
+                    Severity::Warning,
+                    Some(number),
+                    format!(
+                        "`{}` repeats line {}, the last one wins",
+                        directive,
+                        first.get()
+                    ),
+                ),
+                Entry::Vacant(entry) => {
+                    entry.insert(number);
+                }
+            }
+        }
+        if FILE_DIRECTIVES.contains(&directive) {
+            match args.first() {
+                Some(&"[inline]") => {}
+                Some(file) if !dir.join(file).exists() => findings.add(
+                    Severity::Error,
+                    Some(number),
+                    format!("`{}` file {} does not exist", directive, file),
+                ),
+                Some(_) => inline.push(directive),
+                None => findings.add(
+                    Severity::Error,
+                    Some(number),
+                    format!("`{}` needs a file", directive),
+                ),
+            }
+        }
+    }
+
+    if let Some((block, start, _)) = open {
+        findings.add(
+            Severity::Error,
+            Some(start),
+            format!("<{}> is never closed", block),
+        );
+    }
+
+    // Without a server to talk to the rest is noise, most likely a README or some other file
+    if !has_remote {
+        report.add(
+            Severity::Error,
+            None,
+            "No `remote`, not an OpenVPN client profile".to_string(),
+        );
+        return report;
+    }
+    report.findings = findings.findings;
+
+    let has = |name: &str| inline.contains(&name);
+    if !has("ca") && !has("pkcs12") && !has("secret") {
+        report.add(
+            Severity::Error,
+            None,
+            "No CA certificate, inline or as a file".to_string(),
+        );
+    }
+    if has("cert") != has("key") && !has("pkcs12") {
+        let (present, missing) = if has("cert") {
+            ("cert", "key")
+        } else {
+            ("key", "cert")
+        };
+        report.add(
+            Severity::Error,
+            None,
+            format!("Has a {} but no {}", present, missing),
+        );
+    }
+    report
+}
+
+// Keys wg-quick understands per section, matched without regard to case like it does
+const WIREGUARD_KEYS: &[(&str, &[&str])] = &[
+    (
+        "interface",
+        &[
+            "privatekey",
+            "listenport",
+            "fwmark",
+            "address",
+            "dns",
+            "mtu",
+            "table",
+            "preup",
+            "postup",
+            "predown",
+            "postdown",
+            "saveconfig",
+        ],
+    ),
+    (
+        "peer",
+        &[
+            "publickey",
+            "presharedkey",
+            "allowedips",
+            "endpoint",
+            "persistentkeepalive",
+        ],
+    ),
+];
+
+// A WireGuard client needs its own key and at least one peer it can reach
+fn lint_wireguard(mut report: Report, contents: &str) -> Report {
+    let mut section: Option<&str> = None;
+    let mut has_private_key = false;
+    // Line of each [Peer] with whether it has a key and an endpoint
+    let mut peers: Vec<(usize, bool, bool)> = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = WIREGUARD_KEYS
+                .iter()
+                .map(|(known, _)| *known)
+                .find(|known| known.eq_ignore_ascii_case(name));
+            match section {
+                Some("peer") => peers.push((number, false, false)),
+                Some(_) => {}
+                None => report.add(
+                    Severity::Error,
+                    Some(number),
+                    format!("Unknown section [{}]", name),
+                ),
+            }
+            continue;
+        }
+        let Some((key, _)) = line.split_once('=') else {
+            report.add(
+                Severity::Error,
+                Some(number),
+                "Not a `Key = Value` line".to_string(),
+            );
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        let Some(section) = section else {
+            report.add(
+                Severity::Error,
+                Some(number),
+                format!("`{}` is outside of a section", key),
+            );
+            continue;
+        };
+        let known = WIREGUARD_KEYS
+            .iter()
+            .any(|(name, keys)| *name == section && keys.contains(&key.as_str()));
+        if !known {
+            report.add(
+                Severity::Warning,
+                Some(number),
+                format!("Unknown key `{}` in [{}]", key, section),
+            );
+        }
+        match (section, key.as_str()) {
+            ("interface", "privatekey") => has_private_key = true,
+            ("peer", "publickey") => {
+                if let Some(peer) = peers.last_mut() {
+                    peer.1 = true
+                }
+            }
+            ("peer", "endpoint") => {
+                if let Some(peer) = peers.last_mut() {
+                    peer.2 = true
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !has_private_key {
+        report.add(
+            Severity::Error,
+            None,
+            "No `PrivateKey` in [Interface]".to_string(),
+        );
+    }
+    if peers.is_empty() {
+        report.add(
+            Severity::Error,
+            None,
+            "No [Peer], nothing to connect to".to_string(),
+        );
+    }
+    for (line, has_key, has_endpoint) in peers {
+        if !has_key {
+            report.add(
+                Severity::Error,
+                Some(line),
+                "Peer has no `PublicKey`".to_string(),
+            );
+        }
+        if !has_endpoint {
+            report.add(
+                Severity::Error,
+                Some(line),
+                "Peer has no `Endpoint`, a client has to know where to connect".to_string(),
+            );
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA: &str = "<ca>\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n</ca>\n";
+
+    fn messages(report: &Report) -> Vec<(Severity, &str)> {
+        report
+            .findings
+            .iter()
+            .map(|finding| (finding.severity, finding.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_profile() {
+        let profile = format!("client\ndev tun\nremote se.example.com 1194\n{}", CA);
+        let report = lint("/vpn/se.ovpn", profile.as_bytes());
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        assert!(report.is_valid());
+    }
+
+    #[test]
+    fn test_not_a_profile() {
+        let report = lint(
+            "/vpn/README.md",
+            b"# Servers\nDownload more from the website\n",
+        );
+        assert_eq!(
+            messages(&report),
+            vec![(
+                Severity::Error,
+                "No `remote`, not an OpenVPN client profile"
+            )]
+        );
+        assert!(!report.is_valid());
+
+        let report = lint("/vpn/logo.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+        assert!(!report.is_valid());
+    }
+
+    #[test]
+    fn test_missing_certificates() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("ca.crt"), "cert").unwrap();
+        let path = dir.path().join("se.ovpn");
+        let path = path.to_str().unwrap();
+
+        // Referenced files are looked up next to the profile
+        let report = lint(path, b"remote se.example.com\nca ca.crt\n");
+        assert!(report.is_valid(), "{:?}", report.findings);
+        let report = lint(
+            path,
+            b"remote se.example.com\nca missing.crt\ncert client.crt\n",
+        );
+        assert_eq!(
+            messages(&report),
+            vec![
+                (Severity::Error, "`ca` file missing.crt does not exist"),
+                (Severity::Error, "`cert` file client.crt does not exist"),
+                (Severity::Error, "No CA certificate, inline or as a file"),
+            ]
+        );
+
+        let report = lint(path, b"remote se.example.com\n<ca>\n</ca>\n<key>\nsecret\n");
+        assert_eq!(
+            messages(&report),
+            vec![
+                (Severity::Error, "<ca> is empty"),
+                (Severity::Error, "<key> is never closed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_and_deprecated_directives() {
+        let profile = format!(
+            "remote se.example.com\nproto udp\ncomp-lzo\nfrobnicate yes\nignore-unknown-option \
+             block-dns\nblock-dns\nproto tcp\n{}",
+            CA
+        );
+        let report = lint("/vpn/se.ovpn", profile.as_bytes());
+        assert_eq!(
+            messages(&report),
+            vec![
+                (
+                    Severity::Warning,
+                    "`comp-lzo` is deprecated, use compress or allow-compression"
+                ),
+                (Severity::Warning, "Unknown directive `frobnicate`"),
+                (
+                    Severity::Warning,
+                    "`proto` repeats line 2, the last one wins"
+                ),
+            ]
+        );
+        assert_eq!(report.findings[1].line, Some(4));
+        assert!(report.is_valid());
+
+        let profile = format!("remote se.example.com\ntls-remote server\n{}", CA);
+        assert!(!lint("/vpn/se.ovpn", profile.as_bytes()).is_valid());
+    }
+
+    #[test]
+    fn test_wireguard() {
+        let profile = "[Interface]\nPrivateKey = abc=\nAddress = 10.64.0.2/32\n\
+            [Peer]\nPublicKey = def=\nAllowedIPs = 0.0.0.0/0\nEndpoint = 185.65.134.66:51820\n";
+        assert!(lint("/vpn/se.conf", profile.as_bytes()).findings.is_empty());
+
+        let profile =
+            "[Interface]\nAddress = 10.64.0.2/32\nColor = blue\n[Peer]\nPublicKey = def=\n";
+        let report = lint("/vpn/se.conf", profile.as_bytes());
+        assert_eq!(
+            messages(&report),
+            vec![
+                (Severity::Warning, "Unknown key `color` in [interface]"),
+                (Severity::Error, "No `PrivateKey` in [Interface]"),
+                (
+                    Severity::Error,
+                    "Peer has no `Endpoint`, a client has to know where to connect"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let profile = format!("remote se.example.com\n{}", CA);
+        let copy = format!("{}\n", profile);
+        let mut linter = Linter::default();
+        let report = lint("/vpn/a.ovpn", profile.as_bytes());
+        assert!(
+            linter
+                .check(report, fingerprint(profile.as_bytes()))
+                .is_valid()
+        );
+        let report = lint("/vpn/copy/a.ovpn", copy.as_bytes());
+        let report = linter.check(report, fingerprint(copy.as_bytes()));
+        assert_eq!(
+            messages(&report),
+            vec![(Severity::Error, "Same as /vpn/a.ovpn")]
+        );
+    }
+}
@@ -1,9 +1,14 @@
+use crate::tools::severity::Severity;
 use chrono::{Duration, Local, NaiveDateTime, ParseError as TimeParseError};
 use std::cell::RefCell;
 use std::fs::OpenOptions;
 use std::io::{BufRead, Write};
+use std::sync::OnceLock;
 use std::{fs, io};
 
+// Set once at startup from the settings, moving the log needs a restart
+static CONFIGURED_LOG_PATH: OnceLock<String> = OnceLock::new();
+
 thread_local! {
     static TEST_LOG_PATH: RefCell<Option<String>> = RefCell::new(None);
 }
@@ -49,35 +54,59 @@ impl From<io::Error> for LoggerError {
 #[derive(Debug)]
 pub(crate) struct Logger {
     timestamp: NaiveDateTime,
+    // Only set by tests, whose loggers are also used from threads they spawn
+    path: Option<String>,
 }
 
 impl Logger {
-    const LOG_PATH: &'static str = "/home/kwunch/Documents/Rust/vpn_handler/log.txt";
+    pub(crate) const LOG_PATH: &'static str = "/home/kwunch/Documents/Rust/vpn_handler/log.txt";
 
-    fn log_path() -> String {
+    fn log_path(&self) -> String {
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
         TEST_LOG_PATH.with(|p| {
-            p.borrow()
-                .clone()
-                .unwrap_or_else(|| Self::LOG_PATH.to_string())
+            p.borrow().clone().unwrap_or_else(|| {
+                CONFIGURED_LOG_PATH
+                    .get()
+                    .cloned()
+                    .unwrap_or_else(|| Self::LOG_PATH.to_string())
+            })
         })
     }
 
+    // Has to happen before the first logger is used, returns false if the path was already set
+    pub(crate) fn set_path(path: &str) -> bool {
+        CONFIGURED_LOG_PATH.set(path.to_string()).is_ok()
+    }
+
     pub(crate) fn new() -> Self {
         let logger = Self {
             timestamp: Local::now().naive_local(),
+            path: None,
         };
         logger
     }
 
-    pub(crate) fn update(&mut self) -> Result<(), LoggerError> {
+    #[cfg(test)]
+    pub(crate) fn with_path(path: &std::path::Path) -> Self {
+        Self {
+            path: Some(path.to_string_lossy().to_string()),
+            ..Self::new()
+        }
+    }
+
+    // Returns whether a fresh log file was started
+    pub(crate) fn update(&mut self) -> Result<bool, LoggerError> {
         if self.rotate_needed()? {
             self.rotate_logs()?;
+            return Ok(true);
         }
-        Ok(())
+        Ok(false)
     }
 
     fn rotate_needed(&mut self) -> Result<bool, LoggerError> {
-        let file = fs::File::open(Self::log_path());
+        let file = fs::File::open(self.log_path());
 
         match file {
             Ok(file) => {
@@ -130,13 +159,13 @@ impl Logger {
         let now = Local::now().naive_local();
 
         // Ensure old log removal doesn't cause unnecessary errors
-        if fs::metadata(Self::log_path()).is_ok() {
-            fs::remove_file(Self::log_path()).map_err(LoggerError::IOError)?;
+        if fs::metadata(self.log_path()).is_ok() {
+            fs::remove_file(self.log_path()).map_err(LoggerError::IOError)?;
         }
 
         // Create the new log file with Timestamp at the first line
         let contents = format!("LOG CREATED AT: {}\n", now.format("%Y-%m-%d %H:%M:%S"));
-        fs::write(Self::log_path(), contents).map_err(LoggerError::IOError)?;
+        fs::write(self.log_path(), contents).map_err(LoggerError::IOError)?;
 
         // Update stored timestamp
         self.timestamp = now;
@@ -149,7 +178,7 @@ impl Logger {
 
         let mut file = OpenOptions::new()
             .append(true)
-            .open(Self::log_path())
+            .open(self.log_path())
             .map_err(LoggerError::IOError)?;
 
         let msg = format!("[{}] > {}\n", now.format("%Y-%m-%d %H:%M:%S"), msg);
@@ -159,6 +188,28 @@ impl Logger {
             Err(e) => Err(LoggerError::IOError(e)),
         }
     }
+
+    // `[time] > WARNING: msg`, so forwarded output can be told apart and grepped by severity
+    pub(crate) fn log_at(&self, severity: Severity, msg: &str) -> Result<(), LoggerError> {
+        self.log(&format!("{}: {}", severity.get_name(), msg))
+    }
+
+    pub(crate) fn get_log_path(&self) -> String {
+        self.log_path()
+    }
+
+    pub(crate) fn tail(&self, lines: usize) -> Result<Vec<String>, LoggerError> {
+        let file = fs::File::open(self.log_path()).map_err(LoggerError::IOError)?;
+
+        // Skip the creation header, only the logged messages are interesting
+        let messages = io::BufReader::new(file)
+            .lines()
+            .skip(1)
+            .collect::<Result<Vec<String>, io::Error>>()?;
+
+        let start = messages.len().saturating_sub(lines);
+        Ok(messages[start..].to_vec())
+    }
 }
 
 #[cfg(test)]
@@ -176,7 +227,7 @@ mod tests {
         // Set the test path as the LOG_PATH in the main program and assert the change
         TEST_LOG_PATH
             .with(|p| *p.borrow_mut() = Some(log_path.path().to_str().unwrap().to_string()));
-        assert_eq!(Logger::log_path(), log_path.path().to_str().unwrap());
+        assert_eq!(Logger::new().log_path(), log_path.path().to_str().unwrap());
 
         // Remove the log file if it exists
         if log_path.path().exists() {
@@ -571,6 +622,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_logger_tail() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut logger = Logger::with_path(&dir.path().join("log.txt"));
+
+        // Create a fresh log file
+        let result = logger.rotate_logs();
+        assert!(
+            result.is_ok(),
+            "Failed to run rotate logs! Error: {}",
+            result.unwrap_err()
+        );
+
+        // Write a few messages
+        for i in 0..5 {
+            assert!(logger.log(&format!("Message {}", i)).is_ok());
+        }
+
+        // Asking for the last two lines should only return the last two messages
+        let result = logger.tail(2);
+        assert!(
+            result.is_ok(),
+            "Failed to tail log file! Error: {}",
+            result.unwrap_err()
+        );
+        let lines = result.unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("> Message 3"));
+        assert!(lines[1].ends_with("> Message 4"));
+
+        // Asking for more lines than exist should return every message but not the header
+        let lines = logger.tail(100).unwrap();
+        assert_eq!(lines.len(), 5);
+        assert!(!lines[0].starts_with("LOG CREATED AT: "));
+    }
+
     #[test]
     fn test_log_rotate_log() {
         let log_path = setup_log_path();
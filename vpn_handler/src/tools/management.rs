@@ -0,0 +1,434 @@
+use crate::tools::supervisor::CancelToken;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// How often a wait looks at the cancel token, the management thread wakes it for everything else
+const WAIT_SLICE: Duration = Duration::from_millis(250);
+
+// The states OpenVPN reports in `>STATE:` lines, see its management-notes.txt
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum OpenVpnState {
+    Connecting,
+    Wait,
+    Auth,
+    GetConfig,
+    AssignIp,
+    AddRoutes,
+    Connected,
+    Reconnecting,
+    Exiting,
+    Resolve,
+    TcpConnect,
+    // Whatever a newer OpenVPN comes up with
+    Other(String),
+}
+
+impl OpenVpnState {
+    pub(crate) fn parse(name: &str) -> Self {
+        match name {
+            "CONNECTING" => Self::Connecting,
+            "WAIT" => Self::Wait,
+            "AUTH" => Self::Auth,
+            "GET_CONFIG" => Self::GetConfig,
+            "ASSIGN_IP" => Self::AssignIp,
+            "ADD_ROUTES" => Self::AddRoutes,
+            "CONNECTED" => Self::Connected,
+            "RECONNECTING" => Self::Reconnecting,
+            "EXITING" => Self::Exiting,
+            "RESOLVE" => Self::Resolve,
+            "TCP_CONNECT" => Self::TcpConnect,
+            other => Self::Other(other.to_string()),
+        }
+    }
+
+    pub(crate) fn get_name(&self) -> &str {
+        match self {
+            Self::Connecting => "CONNECTING",
+            Self::Wait => "WAIT",
+            Self::Auth => "AUTH",
+            Self::GetConfig => "GET_CONFIG",
+            Self::AssignIp => "ASSIGN_IP",
+            Self::AddRoutes => "ADD_ROUTES",
+            Self::Connected => "CONNECTED",
+            Self::Reconnecting => "RECONNECTING",
+            Self::Exiting => "EXITING",
+            Self::Resolve => "RESOLVE",
+            Self::TcpConnect => "TCP_CONNECT",
+            Self::Other(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StateChange {
+    pub(crate) state: OpenVpnState,
+    // `SUCCESS` once connected, why for RECONNECTING and EXITING (`tls-error`, `auth-failure`...)
+    pub(crate) detail: String,
+    pub(crate) local_ip: Option<String>,
+    pub(crate) remote_ip: Option<String>,
+}
+
+impl StateChange {
+    // `>STATE:1714557600,CONNECTED,SUCCESS,10.8.0.2,185.65.134.1,1194,,`
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.strip_prefix(">STATE:")?.trim_end().split(',');
+        fields.next()?.parse::<u64>().ok()?;
+        let state = OpenVpnState::parse(fields.next()?);
+        let mut field = || {
+            fields
+                .next()
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        Some(Self {
+            state,
+            detail: field().unwrap_or_default(),
+            local_ip: field(),
+            remote_ip: field(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        match self.detail.is_empty() {
+            true => self.state.get_name().to_string(),
+            false => format!("{} ({})", self.state.get_name(), self.detail),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tracker {
+    state: Option<StateChange>,
+    // Why the last attempt went wrong, kept over the RECONNECTING that follows it until CONNECTED
+    reason: Option<String>,
+    closed: bool,
+}
+
+/*
+    Client for OpenVPN's management interface. OpenVPN is started with `--management-hold`, so it
+    waits for us before doing anything: real-time state reports are switched on, then the hold is
+    released. A thread reads everything OpenVPN sends and keeps the latest `>STATE:` along with
+    the last reason it gave for failing (a `>FATAL:`, a rejected password, or the detail of a
+    RECONNECTING or EXITING). `connected` is called on every CONNECTED, before anyone waiting
+    for it hears of it.
+*/
+pub(crate) struct Management {
+    stream: UnixStream,
+    tracker: Arc<(Mutex<Tracker>, Condvar)>,
+}
+
+impl Management {
+    pub(crate) fn new(
+        stream: UnixStream,
+        connected: impl Fn() + Send + 'static,
+    ) -> Result<Self, io::Error> {
+        let tracker = Arc::new((Mutex::new(Tracker::default()), Condvar::new()));
+        let reader = BufReader::new(stream.try_clone()?);
+        let shared = Arc::clone(&tracker);
+        std::thread::Builder::new()
+            .name("openvpn-management".to_string())
+            .spawn(move || {
+                for line in reader.lines() {
+                    let Ok(line) = line else { break };
+                    let (tracker, changed) = &*shared;
+                    let mut tracker = tracker.lock().unwrap_or_else(|e| e.into_inner());
+                    if track(&mut tracker, &line) {
+                        connected();
+                    }
+                    drop(tracker);
+                    changed.notify_all();
+                }
+                let (tracker, changed) = &*shared;
+                tracker.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+                changed.notify_all();
+            })?;
+
+        let mut management = Self { stream, tracker };
+        management.send("state on")?;
+        management.send("hold release")?;
+        Ok(management)
+    }
+
+    pub(crate) fn send(&mut self, command: &str) -> Result<(), io::Error> {
+        self.stream.write_all(format!("{}\n", command).as_bytes())
+    }
+
+    pub(crate) fn get_state(&self) -> Option<StateChange> {
+        self.lock().state.clone()
+    }
+
+    /*
+        Waits for CONNECTED. Fails right away when OpenVPN exits or closes the interface, with
+        TimedOut once `timeout` passed (naming the state it got stuck in) and with Interrupted
+        when the token is cancelled. RECONNECTING is OpenVPN retrying on its own, the reason is
+        kept for the error in case it never gets further.
+    */
+    pub(crate) fn wait_connected(
+        &self,
+        timeout: Duration,
+        token: &CancelToken,
+    ) -> Result<(), io::Error> {
+        let started = Instant::now();
+        let (_, changed) = &*self.tracker;
+        let mut tracker = self.lock();
+        loop {
+            match &tracker.state {
+                Some(change) if change.state == OpenVpnState::Connected => return Ok(()),
+                Some(change) if change.state == OpenVpnState::Exiting => {
+                    return Err(io::Error::other(format!(
+                        "OpenVPN gave up: {}",
+                        tracker.reason.as_deref().unwrap_or(&change.detail)
+                    )));
+                }
+                _ if tracker.closed => {
+                    return Err(io::Error::other(format!(
+                        "OpenVPN closed its management interface{}",
+                        tracker
+                            .reason
+                            .as_ref()
+                            .map(|reason| format!(": {}", reason))
+                            .unwrap_or_default()
+                    )));
+                }
+                _ => {}
+            }
+            if token.is_cancelled() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "Cut short by a stop",
+                ));
+            }
+            let Some(left) = timeout.checked_sub(started.elapsed()) else {
+                let state = tracker
+                    .state
+                    .as_ref()
+                    .map_or("nothing reported".to_string(), StateChange::describe);
+                let mut msg = format!(
+                    "Not connected after {}s, last state {}",
+                    timeout.as_secs(),
+                    state
+                );
+                if let Some(reason) = &tracker.reason {
+                    msg.push_str(&format!(", last failure {}", reason));
+                }
+                return Err(io::Error::new(io::ErrorKind::TimedOut, msg));
+            };
+            tracker = changed
+                .wait_timeout(tracker, left.min(WAIT_SLICE))
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tracker> {
+        self.tracker.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Also ends the reading thread, which holds the other half of the stream
+impl Drop for Management {
+    fn drop(&mut self) {
+        self.stream.shutdown(std::net::Shutdown::Both).ok();
+    }
+}
+
+// True when `line` reported CONNECTED
+fn track(tracker: &mut Tracker, line: &str) -> bool {
+    if let Some(change) = StateChange::parse(line) {
+        if matches!(
+            change.state,
+            OpenVpnState::Reconnecting | OpenVpnState::Exiting
+        ) && !change.detail.is_empty()
+        {
+            tracker.reason.get_or_insert_with(|| change.detail.clone());
+        }
+        // Whatever went wrong before was got over
+        let connected = change.state == OpenVpnState::Connected;
+        if connected {
+            tracker.reason = None;
+        }
+        tracker.state = Some(change);
+        return connected;
+    } else if let Some(message) = line.strip_prefix(">FATAL:") {
+        tracker.reason = Some(message.trim().to_string());
+    } else if let Some(message) = line.strip_prefix(">PASSWORD:Verification Failed") {
+        tracker.reason = Some(format!(
+            "credentials rejected{}",
+            message.trim_start_matches(':').trim_end()
+        ));
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread::JoinHandle;
+
+    /*
+        Stands in for OpenVPN: accepts one client, checks it asks for state reports and releases
+        the hold, then plays `lines` and hangs up unless told to stay.
+    */
+    fn fake_openvpn(
+        path: &Path,
+        lines: &'static [&'static str],
+        stay: Duration,
+    ) -> JoinHandle<Vec<String>> {
+        let listener = UnixListener::bind(path).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(b">INFO:OpenVPN Management Interface Version 5 -- type 'help'\n")
+                .unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut commands = Vec::new();
+            for _ in 0..2 {
+                let mut command = String::new();
+                reader.read_line(&mut command).unwrap();
+                commands.push(command.trim().to_string());
+            }
+            for line in lines {
+                stream.write_all(format!("{}\n", line).as_bytes()).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            if stay.is_zero() {
+                return commands;
+            }
+            // Anything sent later, like a `signal SIGTERM`, until the client hangs up
+            stream.set_read_timeout(Some(stay)).unwrap();
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).ok();
+            commands.extend(rest.lines().map(str::to_string));
+            commands
+        })
+    }
+
+    fn client(path: &Path) -> Management {
+        Management::new(UnixStream::connect(path).unwrap(), || {}).unwrap()
+    }
+
+    #[test]
+    fn test_parse_state() {
+        let change =
+            StateChange::parse(">STATE:1714557600,CONNECTED,SUCCESS,10.8.0.2,185.65.134.1,1194,,")
+                .unwrap();
+        assert_eq!(change.state, OpenVpnState::Connected);
+        assert_eq!(change.detail, "SUCCESS");
+        assert_eq!(change.local_ip.as_deref(), Some("10.8.0.2"));
+        assert_eq!(change.remote_ip.as_deref(), Some("185.65.134.1"));
+
+        let change = StateChange::parse(">STATE:1714557600,WAIT,,,,,,").unwrap();
+        assert_eq!(change.state, OpenVpnState::Wait);
+        assert_eq!(change.local_ip, None);
+        assert_eq!(
+            StateChange::parse(">STATE:1,DCO_WAIT,,").unwrap().state,
+            OpenVpnState::Other("DCO_WAIT".to_string())
+        );
+        assert_eq!(StateChange::parse(">INFO:hello"), None);
+        assert_eq!(StateChange::parse(">STATE:soon,CONNECTED"), None);
+    }
+
+    #[test]
+    fn test_connected() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("management");
+        let server = fake_openvpn(
+            &path,
+            &[
+                ">HOLD:Waiting for hold release:0",
+                "SUCCESS: real-time state notification set to ON",
+                "SUCCESS: hold release succeeded",
+                ">STATE:1714557600,RESOLVE,,,,,,",
+                ">STATE:1714557600,WAIT,,,,,,",
+                ">STATE:1714557601,AUTH,,,,,,",
+                ">STATE:1714557601,GET_CONFIG,,,,,,",
+                ">STATE:1714557602,ASSIGN_IP,,10.8.0.2,,,,",
+                ">STATE:1714557602,CONNECTED,SUCCESS,10.8.0.2,185.65.134.1,1194,,",
+            ],
+            Duration::ZERO,
+        );
+
+        let management = client(&path);
+        let token = CancelToken::new();
+        management
+            .wait_connected(Duration::from_secs(5), &token)
+            .unwrap();
+        assert_eq!(
+            management.get_state().unwrap().local_ip.as_deref(),
+            Some("10.8.0.2")
+        );
+        assert_eq!(server.join().unwrap(), vec!["state on", "hold release"]);
+    }
+
+    #[test]
+    fn test_failures() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let token = CancelToken::new();
+
+        // Rejected credentials end with OpenVPN exiting
+        let path = dir.path().join("auth");
+        let server = fake_openvpn(
+            &path,
+            &[
+                ">STATE:1714557600,AUTH,,,,,,",
+                ">PASSWORD:Verification Failed: 'Auth'",
+                ">STATE:1714557601,EXITING,auth-failure,,,,,",
+            ],
+            Duration::ZERO,
+        );
+        let error = client(&path)
+            .wait_connected(Duration::from_secs(5), &token)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "OpenVPN gave up: credentials rejected 'Auth'"
+        );
+        server.join().unwrap();
+
+        // Stuck retrying a server that does not answer
+        let path = dir.path().join("stuck");
+        let server = fake_openvpn(
+            &path,
+            &[
+                ">STATE:1714557600,WAIT,,,,,,",
+                ">STATE:1714557660,RECONNECTING,tls-error,,,,,",
+                ">STATE:1714557665,WAIT,,,,,,",
+            ],
+            Duration::from_secs(5),
+        );
+        let management = client(&path);
+        let error = management
+            .wait_connected(Duration::from_secs(1), &token)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            error.to_string(),
+            "Not connected after 1s, last state WAIT, last failure tls-error"
+        );
+
+        token.cancel();
+        let error = management
+            .wait_connected(Duration::from_secs(5), &token)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+        drop(management);
+        server.join().unwrap();
+
+        // Gone without a word
+        let path = dir.path().join("gone");
+        let server = fake_openvpn(&path, &[">FATAL:Cannot open TUN/TAP dev"], Duration::ZERO);
+        let error = client(&path)
+            .wait_connected(Duration::from_secs(5), &CancelToken::new())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "OpenVPN closed its management interface: Cannot open TUN/TAP dev"
+        );
+        server.join().unwrap();
+    }
+}
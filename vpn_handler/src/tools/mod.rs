@@ -1,4 +1,29 @@
+pub(crate) mod access;
+pub(crate) mod backend;
 pub(crate) mod config;
+pub(crate) mod control;
+pub(crate) mod credentials;
+pub(crate) mod daemon;
+pub(crate) mod events;
 pub(crate) mod handler;
+pub(crate) mod lint;
 pub(crate) mod logger;
+pub(crate) mod management;
 pub(crate) mod notifier;
+pub(crate) mod output;
+pub(crate) mod overrides;
+pub(crate) mod profile;
+pub(crate) mod protocol;
+pub(crate) mod quarantine;
+pub(crate) mod reconnect;
+pub(crate) mod runner;
+pub(crate) mod scan;
+pub(crate) mod selection;
+pub(crate) mod settings;
+pub(crate) mod severity;
+pub(crate) mod shutdown;
+pub(crate) mod state;
+pub(crate) mod status;
+pub(crate) mod supervisor;
+pub(crate) mod systemd;
+pub(crate) mod watcher;
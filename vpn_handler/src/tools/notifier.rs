@@ -4,12 +4,24 @@ use std::os::unix::net::UnixStream;
 
 pub(crate) struct Notifier {
     socket: UnixStream,
+    path: String,
 }
 
 impl Notifier {
-    pub(crate) fn new() -> Result<Self, io::Error> {
-        let socket = Self::connect()?;
-        Ok(Self { socket })
+    pub(crate) fn new(path: &str) -> Result<Self, io::Error> {
+        let socket = Self::connect(path)?;
+        Ok(Self {
+            socket,
+            path: path.to_string(),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_stream(socket: UnixStream) -> Self {
+        Self {
+            socket,
+            path: String::new(),
+        }
     }
 
     pub(crate) fn send_message(&mut self, message: &str) -> Result<(), io::Error> {
@@ -18,7 +30,7 @@ impl Notifier {
             if result.is_ok() {
                 return Ok(());
             } else {
-                self.socket = Self::connect()?;
+                self.socket = Self::connect(&self.path)?;
                 std::thread::sleep(std::time::Duration::from_millis(250));
             }
         }
@@ -28,9 +40,9 @@ impl Notifier {
         ))
     }
 
-    fn connect() -> Result<UnixStream, io::Error> {
+    fn connect(path: &str) -> Result<UnixStream, io::Error> {
         for _ in 0..10 {
-            let socket = UnixStream::connect("/tmp/vpn-status.sock");
+            let socket = UnixStream::connect(path);
             if socket.is_ok() {
                 return socket;
             } else {
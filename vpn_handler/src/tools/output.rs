@@ -0,0 +1,194 @@
+use crate::tools::severity::Severity;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+// The failures OpenVPN only tells about in its output, worth more than "did not connect"
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Failure {
+    // The server turned the username or password down
+    Auth,
+    // No answer to the TLS handshake in time, usually a wrong port, protocol or a firewall
+    TlsHandshake,
+    // The server name in the profile's `remote`, as OpenVPN printed it
+    Resolve(String),
+}
+
+impl Failure {
+    // `AUTH: Received control message: AUTH_FAILED`, `TLS Error: TLS handshake failed` and
+    // `RESOLVE: Cannot resolve host address: vpn.example.com:1194 (Name or service not known)`
+    pub(crate) fn classify(line: &str) -> Option<Self> {
+        if line.contains("AUTH_FAILED") {
+            return Some(Self::Auth);
+        }
+        if line.contains("TLS handshake failed") {
+            return Some(Self::TlsHandshake);
+        }
+        let (_, host) = line.split_once("Cannot resolve host address:")?;
+        let host = host.split(" (").next().unwrap_or_default().trim();
+        Some(Self::Resolve(host.to_string()))
+    }
+
+    // What `status` and subscribers see as the kind of the last error
+    pub(crate) fn get_name(&self) -> &'static str {
+        match self {
+            Self::Auth => "auth_failed",
+            Self::TlsHandshake => "tls_handshake_failed",
+            Self::Resolve(_) => "resolve_failed",
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Auth => write!(f, "The server rejected the credentials (AUTH_FAILED)"),
+            Self::TlsHandshake => write!(f, "The TLS handshake with the server failed"),
+            Self::Resolve(host) if host.is_empty() => {
+                write!(f, "Cannot resolve the server address")
+            }
+            Self::Resolve(host) => write!(f, "Cannot resolve the server address {}", host),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+// OpenVPN only marks its warnings and errors, anything else on stderr is taken as a warning
+pub(crate) fn severity(line: &str, stderr: bool) -> Severity {
+    let lower = line.to_lowercase();
+    if Failure::classify(line).is_some() || lower.contains("error") || lower.contains("fatal") {
+        Severity::Error
+    } else if stderr || lower.contains("warning") {
+        Severity::Warning
+    } else {
+        Severity::Info
+    }
+}
+
+/*
+    Reads `source`, OpenVPN's stdout or stderr, line by line until it is closed, which happens
+    when OpenVPN exits. Every line goes to `log` with its severity, the last failure recognized
+    in them is kept in `failure`. Output that is not UTF-8 is passed on lossily.
+*/
+pub(crate) fn capture(
+    source: impl Read + Send + 'static,
+    stderr: bool,
+    failure: Arc<Mutex<Option<Failure>>>,
+    log: impl Fn(Severity, &str) + Send + 'static,
+) -> Result<JoinHandle<()>, io::Error> {
+    let name = match stderr {
+        true => "openvpn-stderr",
+        false => "openvpn-stdout",
+    };
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let mut reader = BufReader::new(source);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end();
+                if line.is_empty() {
+                    continue;
+                }
+                if let Some(found) = Failure::classify(line) {
+                    *failure.lock().unwrap_or_else(|e| e.into_inner()) = Some(found);
+                }
+                log(severity(line, stderr), line);
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            Failure::classify("AUTH: Received control message: AUTH_FAILED"),
+            Some(Failure::Auth)
+        );
+        assert_eq!(
+            Failure::classify(
+                "TLS Error: TLS key negotiation failed to occur within 60 seconds (check your network connectivity)"
+            ),
+            None
+        );
+        assert_eq!(
+            Failure::classify("TLS Error: TLS handshake failed"),
+            Some(Failure::TlsHandshake)
+        );
+        let failure = Failure::classify(
+            "RESOLVE: Cannot resolve host address: se.example.com:1194 (Name or service not known)",
+        )
+        .unwrap();
+        assert_eq!(failure.get_name(), "resolve_failed");
+        assert_eq!(
+            failure.to_string(),
+            "Cannot resolve the server address se.example.com:1194"
+        );
+        assert_eq!(Failure::classify("Initialization Sequence Completed"), None);
+
+        assert_eq!(
+            severity("Initialization Sequence Completed", false),
+            Severity::Info
+        );
+        assert_eq!(
+            severity(
+                "WARNING: file 'auth.txt' is group or others accessible",
+                false
+            ),
+            Severity::Warning
+        );
+        assert_eq!(
+            severity("Options error: Unrecognized option", true),
+            Severity::Error
+        );
+        assert_eq!(severity("Unexpected banner", true), Severity::Warning);
+    }
+
+    #[test]
+    fn test_capture() {
+        let output: &[u8] = b"OpenVPN 2.6.9 x86_64-pc-linux-gnu\n\
+            TLS Error: TLS handshake failed\n\
+            \n\
+            SIGUSR1[soft,tls-error] received, process restarting\n\
+            AUTH: Received control message: AUTH_FAILED\n\
+            bad \xff byte";
+        let failure = Arc::new(Mutex::new(None));
+        let (sender, lines) = mpsc::channel();
+        capture(
+            output,
+            false,
+            Arc::clone(&failure),
+            move |severity, line| {
+                sender.send((severity, line.to_string())).unwrap();
+            },
+        )
+        .unwrap()
+        .join()
+        .unwrap();
+
+        let lines: Vec<(Severity, String)> = lines.iter().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0].0, Severity::Info);
+        assert_eq!(
+            lines[1],
+            (
+                Severity::Error,
+                "TLS Error: TLS handshake failed".to_string()
+            )
+        );
+        assert_eq!(lines[4].1, "bad \u{fffd} byte");
+        // The latest one counts
+        assert_eq!(*failure.lock().unwrap(), Some(Failure::Auth));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::tools::events::{Event, EventBus};
+use crate::tools::logger::Logger;
+use chrono::{Local, NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum OverrideMode {
+    FollowSwitch,
+    ForceOn,
+    ForceOff,
+}
+
+impl OverrideMode {
+    pub(crate) fn get_name(&self) -> &'static str {
+        match self {
+            OverrideMode::FollowSwitch => "follow-switch",
+            OverrideMode::ForceOn => "force-on",
+            OverrideMode::ForceOff => "force-off",
+        }
+    }
+}
+
+/*
+    Lets software overrule the hardware switch for a while, e.g. to keep the VPN up during
+    maintenance with the switch off. Shared between the daemon, which sets it, and the runner,
+    which consults it after every read of the serial port.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Override {
+    mode: OverrideMode,
+    set_at: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+}
+
+impl Default for Override {
+    fn default() -> Self {
+        Self {
+            mode: OverrideMode::FollowSwitch,
+            set_at: None,
+            until: None,
+        }
+    }
+}
+
+impl Override {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    // Following the switch never expires, so a duration only applies to the forced modes
+    pub(crate) fn set(&mut self, mode: OverrideMode, duration: Option<Duration>) {
+        let now = Local::now().naive_local();
+        self.mode = mode;
+        self.set_at = Some(now);
+        self.until = match mode {
+            OverrideMode::FollowSwitch => None,
+            _ => duration
+                .and_then(|duration| TimeDelta::from_std(duration).ok())
+                .and_then(|delta| now.checked_add_signed(delta)),
+        };
+    }
+
+    pub(crate) fn get_mode(&self) -> OverrideMode {
+        self.mode
+    }
+
+    pub(crate) fn get_until(&self) -> Option<NaiveDateTime> {
+        self.until
+    }
+
+    // Falls back to following the switch once the expiry has passed, returns the mode that ran out
+    pub(crate) fn expire(&mut self, now: NaiveDateTime) -> Option<OverrideMode> {
+        match self.until {
+            Some(until) if now >= until => {
+                let expired = self.mode;
+                *self = Self {
+                    mode: OverrideMode::FollowSwitch,
+                    set_at: Some(now),
+                    until: None,
+                };
+                Some(expired)
+            }
+            _ => None,
+        }
+    }
+
+    // Whether the VPN should be up, None while following a switch that has not reported yet
+    pub(crate) fn wanted(&self, switch_on: Option<bool>) -> Option<bool> {
+        match self.mode {
+            OverrideMode::FollowSwitch => switch_on,
+            OverrideMode::ForceOn => Some(true),
+            OverrideMode::ForceOff => Some(false),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "mode": self.mode,
+            "set_at": self.set_at.map(|time| time.format(TIME_FORMAT).to_string()),
+            "until": self.until.map(|time| time.format(TIME_FORMAT).to_string()),
+        })
+    }
+}
+
+// Current override after letting an expired one lapse, which is logged and announced once
+pub(crate) fn current(
+    overrides: &Mutex<Override>,
+    logger: &Mutex<Logger>,
+    events: &EventBus,
+) -> Override {
+    let mut state = overrides.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(expired) = state.expire(Local::now().naive_local()) {
+        if let Ok(logger) = logger.lock() {
+            let msg = format!(
+                "Override {} expired, following the switch again",
+                expired.get_name()
+            );
+            logger.log(&msg).ok();
+        }
+        events.publish(Event::OverrideChanged {
+            mode: state.get_mode(),
+            until: None,
+        });
+    }
+    state.clone()
+}
+
+// Accepts plain seconds or a number with an s/m/h/d suffix, like `90`, `30m` or `2h`
+pub(crate) fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let number = number.parse::<u64>().ok()?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number.checked_mul(60)?,
+        "h" => number.checked_mul(60 * 60)?,
+        "d" => number.checked_mul(24 * 60 * 60)?,
+        _ => return None,
+    };
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wanted_state() {
+        let mut state = Override::new();
+        assert_eq!(state.wanted(None), None);
+        assert_eq!(state.wanted(Some(false)), Some(false));
+
+        state.set(OverrideMode::ForceOn, None);
+        assert_eq!(state.wanted(Some(false)), Some(true));
+        assert_eq!(state.get_until(), None);
+
+        state.set(OverrideMode::ForceOff, None);
+        assert_eq!(state.wanted(Some(true)), Some(false));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut state = Override::new();
+        state.set(OverrideMode::ForceOn, Some(Duration::from_secs(60)));
+        let until = state.get_until().unwrap();
+
+        // Nothing happens before the expiry
+        assert_eq!(state.expire(until - TimeDelta::seconds(1)), None);
+        assert_eq!(state.get_mode(), OverrideMode::ForceOn);
+
+        assert_eq!(state.expire(until), Some(OverrideMode::ForceOn));
+        assert_eq!(state.get_mode(), OverrideMode::FollowSwitch);
+        assert_eq!(state.get_until(), None);
+        assert_eq!(state.wanted(Some(false)), Some(false));
+
+        // Following the switch ignores any duration it is given
+        state.set(OverrideMode::FollowSwitch, Some(Duration::from_secs(60)));
+        assert_eq!(state.get_until(), None);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut state = Override::new();
+        assert_eq!(state.to_json()["mode"], json!("follow-switch"));
+        assert_eq!(state.to_json()["until"], Value::Null);
+
+        state.set(OverrideMode::ForceOff, Some(Duration::from_secs(3600)));
+        let report = state.to_json();
+        assert_eq!(report["mode"], json!("force-off"));
+        assert!(report["until"].is_string());
+        assert!(report["set_at"].is_string());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("2w"), None);
+        assert_eq!(parse_duration("h"), None);
+    }
+}
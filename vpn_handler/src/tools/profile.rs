@@ -0,0 +1,541 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+
+// Blocks holding certificates and keys inline, their contents are not directives
+pub(crate) const INLINE_BLOCKS: &[&str] = &[
+    "ca",
+    "cert",
+    "key",
+    "tls-auth",
+    "tls-crypt",
+    "tls-crypt-v2",
+    "pkcs12",
+    "secret",
+    "dh",
+    "extra-certs",
+    "crl-verify",
+    "auth-user-pass",
+    "http-proxy-user-pass",
+];
+
+// Providers recognised in file names when the profile is not filed in a directory of its own
+const PROVIDERS: &[&str] = &[
+    "nordvpn",
+    "protonvpn",
+    "mullvad",
+    "surfshark",
+    "expressvpn",
+    "ipvanish",
+    "pia",
+    "privateinternetaccess",
+    "windscribe",
+    "cyberghost",
+    "airvpn",
+    "ivpn",
+    "vyprvpn",
+];
+
+// Parts of profile names that are never a city
+const NOISE: &[&str] = &[
+    "udp", "tcp", "ovpn", "conf", "com", "net", "org", "vpn", "openvpn",
+];
+
+// ISO 3166 codes and names of the countries VPN providers commonly have servers in
+const COUNTRIES: &[(&str, &str)] = &[
+    ("ae", "united arab emirates"),
+    ("al", "albania"),
+    ("ar", "argentina"),
+    ("at", "austria"),
+    ("au", "australia"),
+    ("ba", "bosnia and herzegovina"),
+    ("be", "belgium"),
+    ("bg", "bulgaria"),
+    ("br", "brazil"),
+    ("ca", "canada"),
+    ("ch", "switzerland"),
+    ("cl", "chile"),
+    ("co", "colombia"),
+    ("cr", "costa rica"),
+    ("cy", "cyprus"),
+    ("cz", "czech republic"),
+    ("de", "germany"),
+    ("dk", "denmark"),
+    ("ee", "estonia"),
+    ("es", "spain"),
+    ("fi", "finland"),
+    ("fr", "france"),
+    ("gb", "united kingdom"),
+    ("ge", "georgia"),
+    ("gr", "greece"),
+    ("hk", "hong kong"),
+    ("hr", "croatia"),
+    ("hu", "hungary"),
+    ("id", "indonesia"),
+    ("ie", "ireland"),
+    ("il", "israel"),
+    ("in", "india"),
+    ("is", "iceland"),
+    ("it", "italy"),
+    ("jp", "japan"),
+    ("kr", "south korea"),
+    ("lt", "lithuania"),
+    ("lu", "luxembourg"),
+    ("lv", "latvia"),
+    ("md", "moldova"),
+    ("mk", "north macedonia"),
+    ("mx", "mexico"),
+    ("my", "malaysia"),
+    ("nl", "netherlands"),
+    ("no", "norway"),
+    ("nz", "new zealand"),
+    ("pl", "poland"),
+    ("pt", "portugal"),
+    ("ro", "romania"),
+    ("rs", "serbia"),
+    ("se", "sweden"),
+    ("sg", "singapore"),
+    ("si", "slovenia"),
+    ("sk", "slovakia"),
+    ("th", "thailand"),
+    ("tr", "turkey"),
+    ("tw", "taiwan"),
+    ("ua", "ukraine"),
+    ("us", "united states"),
+    ("vn", "vietnam"),
+    ("za", "south africa"),
+];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Remote {
+    pub(crate) host: String,
+    pub(crate) port: Option<u16>,
+    pub(crate) proto: Option<String>,
+}
+
+// What brings the profile up, told apart by the contents since both may be called .conf
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ProfileKind {
+    #[default]
+    OpenVpn,
+    WireGuard,
+}
+
+impl ProfileKind {
+    // WireGuard configs start with an INI style section, OpenVPN ones never do
+    pub(crate) fn of(contents: &str) -> Self {
+        let first = contents
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'));
+        match first {
+            Some(line)
+                if line.eq_ignore_ascii_case("[interface]")
+                    || line.eq_ignore_ascii_case("[peer]") =>
+            {
+                Self::WireGuard
+            }
+            _ => Self::OpenVpn,
+        }
+    }
+
+    pub(crate) fn get_name(&self) -> &'static str {
+        match self {
+            Self::OpenVpn => "openvpn",
+            Self::WireGuard => "wireguard",
+        }
+    }
+}
+
+/*
+    What a profile connects to and where it is, read from the .ovpn file and its name. Only the
+    handful of directives the daemon cares about are picked out, everything else is left to
+    OpenVPN. Location is a best guess: a leading country code (`se-sto-01`, `us123.nordvpn.com`)
+    or a country name (`Sweden - Stockholm`) in the file name or its directories, the token after
+    it as the city, and the first directory below the profile directory or a well-known name as
+    the provider.
+*/
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub(crate) struct Profile {
+    pub(crate) path: String,
+    pub(crate) name: String,
+    pub(crate) kind: ProfileKind,
+    pub(crate) remotes: Vec<Remote>,
+    pub(crate) proto: Option<String>,
+    pub(crate) port: Option<u16>,
+    pub(crate) cipher: Option<String>,
+    pub(crate) auth_user_pass: bool,
+    pub(crate) provider: Option<String>,
+    pub(crate) country: Option<String>,
+    pub(crate) city: Option<String>,
+}
+
+impl Profile {
+    pub(crate) fn parse(path: &str, contents: &str, main_dir: &str) -> Self {
+        let mut profile = Self {
+            path: path.to_string(),
+            name: Path::new(path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            kind: ProfileKind::of(contents),
+            ..Self::default()
+        };
+        match profile.kind {
+            ProfileKind::OpenVpn => profile.parse_openvpn(contents),
+            ProfileKind::WireGuard => profile.parse_wireguard(contents),
+        }
+
+        let (provider, country, city) = infer_location(path, main_dir);
+        profile.provider = provider;
+        profile.country = country;
+        profile.city = city;
+        profile
+    }
+
+    fn parse_openvpn(&mut self, contents: &str) {
+        let mut inline: Option<String> = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if let Some(block) = &inline {
+                if line == format!("</{}>", block) {
+                    inline = None;
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(block) = line.strip_prefix('<').and_then(|l| l.strip_suffix('>')) {
+                if INLINE_BLOCKS.contains(&block) {
+                    inline = Some(block.to_string());
+                }
+                // `<connection>` wraps ordinary directives, so it is read through
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let Some(directive) = words.next() else {
+                continue;
+            };
+            let args: Vec<&str> = words.collect();
+            match directive {
+                "remote" if !args.is_empty() => self.remotes.push(Remote {
+                    host: args[0].to_string(),
+                    port: args.get(1).and_then(|port| port.parse().ok()),
+                    proto: args.get(2).map(|proto| proto.to_string()),
+                }),
+                "proto" => self.proto = args.first().map(|proto| proto.to_string()),
+                "port" | "rport" => self.port = args.first().and_then(|port| port.parse().ok()),
+                "cipher" => self.cipher = args.first().map(|cipher| cipher.to_string()),
+                // The negotiated list, the first entry is what a current server will use
+                "data-ciphers" if self.cipher.is_none() => {
+                    self.cipher = args
+                        .first()
+                        .and_then(|ciphers| ciphers.split(':').next())
+                        .map(|cipher| cipher.to_string())
+                }
+                "auth-user-pass" => self.auth_user_pass = true,
+                _ => {}
+            }
+        }
+    }
+
+    // Only the peers' endpoints matter here, WireGuard always runs over UDP
+    fn parse_wireguard(&mut self, contents: &str) {
+        self.proto = Some("udp".to_string());
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if !key.trim().eq_ignore_ascii_case("endpoint") {
+                continue;
+            }
+            // `host:port`, or `[v6 address]:port`
+            let value = value.trim();
+            let (host, port) = match value.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().ok()),
+                None => (value, None),
+            };
+            self.remotes.push(Remote {
+                host: host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port,
+                proto: None,
+            });
+        }
+    }
+
+    // Whether a `key:value` filter such as `country:se` or `provider:mullvad` fits the profile
+    pub(crate) fn matches_filter(&self, key: &str, value: &str) -> bool {
+        let field = match key {
+            "country" => self.country.as_deref(),
+            "city" => self.city.as_deref(),
+            "provider" => self.provider.as_deref(),
+            "proto" => self.get_proto(),
+            "kind" => Some(self.kind.get_name()),
+            _ => return false,
+        };
+        let value = value.to_lowercase();
+        match field {
+            Some(field) => {
+                let field = field.to_lowercase();
+                // Country names work as well as codes
+                field == value
+                    || (key == "country"
+                        && COUNTRIES
+                            .iter()
+                            .any(|(code, name)| *code == field && *name == value))
+            }
+            None => false,
+        }
+    }
+
+    // The protocol of the first remote, falling back to the profile wide `proto`
+    pub(crate) fn get_proto(&self) -> Option<&str> {
+        self.remotes
+            .first()
+            .and_then(|remote| remote.proto.as_deref())
+            .or(self.proto.as_deref())
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+// Splits names like `se123.nordvpn.com.udp` or `Sweden - Stockholm` into lowercase words
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in text.chars() {
+        let boundary = match current.chars().last() {
+            Some(last) => last.is_ascii_digit() != c.is_ascii_digit(),
+            None => false,
+        };
+        if (!c.is_alphanumeric() || boundary) && !current.is_empty() {
+            tokens.push(std::mem::take(&mut current));
+        }
+        if c.is_alphanumeric() {
+            current.extend(c.to_lowercase());
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn country_code(token: &str) -> Option<&'static str> {
+    let token = if token == "uk" { "gb" } else { token };
+    COUNTRIES
+        .iter()
+        .find(|(code, _)| *code == token)
+        .map(|(code, _)| *code)
+}
+
+// A country spelled out anywhere in the words, with the index of the word after it
+fn country_by_name(words: &[String]) -> Option<(&'static str, usize)> {
+    let text = words.join(" ");
+    COUNTRIES.iter().find_map(|(code, name)| {
+        let start = format!(" {} ", text).find(&format!(" {} ", name))?;
+        let after = text[..start].split_whitespace().count() + name.split(' ').count();
+        Some((*code, after))
+    })
+}
+
+fn infer_location(path: &str, main_dir: &str) -> (Option<String>, Option<String>, Option<String>) {
+    let relative = path
+        .strip_prefix(main_dir)
+        .unwrap_or(path)
+        .trim_start_matches('/');
+    let mut parts: Vec<&str> = relative.split('/').collect();
+    let file = parts.pop().unwrap_or_default();
+    let stem = file.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(file);
+
+    let name_tokens = tokens(stem);
+    let is_city = |token: &String| {
+        token.len() >= 3
+            && token.chars().all(|c| c.is_alphabetic())
+            && !NOISE.contains(&token.as_str())
+            && !PROVIDERS.contains(&token.as_str())
+    };
+
+    // The file name knows best, a directory named after a country comes next
+    let mut country = None;
+    let mut city = None;
+    if let Some(code) = name_tokens.first().and_then(|token| country_code(token)) {
+        country = Some(code);
+        city = name_tokens
+            .iter()
+            .skip(1)
+            .find(|token| !token.chars().all(|c| c.is_ascii_digit()));
+    } else if let Some((code, after)) = country_by_name(&name_tokens) {
+        country = Some(code);
+        city = name_tokens.get(after);
+    }
+    let mut country_dir = None;
+    if country.is_none() {
+        for (index, part) in parts.iter().enumerate() {
+            let words = tokens(part);
+            let code = match words.as_slice() {
+                [word] => country_code(word),
+                _ => None,
+            }
+            .or_else(|| country_by_name(&words).map(|(code, _)| code));
+            if code.is_some() {
+                country = code;
+                country_dir = Some(index);
+                city = name_tokens.iter().find(|token| is_city(token));
+                break;
+            }
+        }
+    }
+    let city = city.filter(|token| is_city(token)).cloned();
+
+    let provider = parts
+        .iter()
+        .enumerate()
+        .find(|(index, _)| Some(*index) != country_dir)
+        .map(|(_, part)| part.to_lowercase())
+        .or_else(|| {
+            name_tokens
+                .iter()
+                .find(|token| PROVIDERS.contains(&token.as_str()))
+                .cloned()
+        });
+
+    (provider, country.map(|code| code.to_uppercase()), city)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = "\
+client
+dev tun
+# remote commented.example.com 1194
+proto udp
+remote se-sto-01.example.com 1194
+remote 10.0.0.1 443 tcp
+data-ciphers AES-256-GCM:AES-128-GCM
+auth-user-pass
+<ca>
+-----BEGIN CERTIFICATE-----
+remote not-a-directive.example.com
+-----END CERTIFICATE-----
+</ca>
+";
+
+    #[test]
+    fn test_parse_directives() {
+        let profile = Profile::parse("/vpn/nordvpn/se-sto-01.ovpn", PROFILE, "/vpn");
+        assert_eq!(profile.name, "se-sto-01");
+        assert_eq!(
+            profile.remotes,
+            vec![
+                Remote {
+                    host: "se-sto-01.example.com".to_string(),
+                    port: Some(1194),
+                    proto: None,
+                },
+                Remote {
+                    host: "10.0.0.1".to_string(),
+                    port: Some(443),
+                    proto: Some("tcp".to_string()),
+                },
+            ]
+        );
+        assert_eq!(profile.proto.as_deref(), Some("udp"));
+        assert_eq!(profile.get_proto(), Some("udp"));
+        assert_eq!(profile.cipher.as_deref(), Some("AES-256-GCM"));
+        assert!(profile.auth_user_pass);
+
+        let profile = Profile::parse(
+            "/vpn/a.ovpn",
+            "remote a.example.com\ncipher AES-256-CBC\n",
+            "/vpn",
+        );
+        assert!(!profile.auth_user_pass);
+        assert_eq!(profile.cipher.as_deref(), Some("AES-256-CBC"));
+        assert_eq!(profile.remotes[0].port, None);
+    }
+
+    #[test]
+    fn test_parse_wireguard() {
+        let contents = "# Mullvad\n[Interface]\nPrivateKey = abc=\nAddress = 10.64.0.2/32\n\n\
+            [Peer]\nPublicKey = def=\nEndpoint = 185.65.134.66:51820\n\n\
+            [Peer]\nPublicKey = ghi=\nEndpoint = [2a03:1b20::1]:51820\n";
+        let profile = Profile::parse("/vpn/mullvad/se-sto-wg-001.conf", contents, "/vpn");
+        assert_eq!(profile.kind, ProfileKind::WireGuard);
+        assert_eq!(profile.get_proto(), Some("udp"));
+        assert_eq!(profile.remotes.len(), 2);
+        assert_eq!(profile.remotes[0].port, Some(51820));
+        assert_eq!(profile.remotes[1].host, "2a03:1b20::1");
+        assert_eq!(profile.provider.as_deref(), Some("mullvad"));
+        assert!(profile.matches_filter("kind", "wireguard"));
+
+        // An OpenVPN profile may be called .conf too
+        let profile = Profile::parse("/vpn/office.conf", PROFILE, "/vpn");
+        assert_eq!(profile.kind, ProfileKind::OpenVpn);
+    }
+
+    #[test]
+    fn test_infer_location() {
+        let cases = [
+            (
+                "/vpn/nordvpn/se-sto-01.ovpn",
+                Some("nordvpn"),
+                Some("SE"),
+                Some("sto"),
+            ),
+            (
+                "/vpn/us123.nordvpn.com.udp.ovpn",
+                Some("nordvpn"),
+                Some("US"),
+                None,
+            ),
+            (
+                "/vpn/pia/Switzerland - Zurich.ovpn",
+                Some("pia"),
+                Some("CH"),
+                Some("zurich"),
+            ),
+            (
+                "/vpn/mullvad/uk-lon-002.ovpn",
+                Some("mullvad"),
+                Some("GB"),
+                Some("lon"),
+            ),
+            (
+                "/vpn/proton/germany/frankfurt-03.ovpn",
+                Some("proton"),
+                Some("DE"),
+                Some("frankfurt"),
+            ),
+            ("/vpn/work.ovpn", None, None, None),
+        ];
+        for (path, provider, country, city) in cases {
+            let (found_provider, found_country, found_city) = infer_location(path, "/vpn");
+            assert_eq!(found_provider.as_deref(), provider, "provider of {}", path);
+            assert_eq!(found_country.as_deref(), country, "country of {}", path);
+            assert_eq!(found_city.as_deref(), city, "city of {}", path);
+        }
+    }
+
+    #[test]
+    fn test_matches_filter() {
+        let profile = Profile::parse("/vpn/mullvad/se-got-01.ovpn", "proto tcp\n", "/vpn");
+        assert!(profile.matches_filter("country", "se"));
+        assert!(profile.matches_filter("country", "Sweden"));
+        assert!(profile.matches_filter("city", "GOT"));
+        assert!(profile.matches_filter("provider", "mullvad"));
+        assert!(profile.matches_filter("proto", "tcp"));
+        assert!(!profile.matches_filter("country", "us"));
+        assert!(!profile.matches_filter("colour", "blue"));
+    }
+}
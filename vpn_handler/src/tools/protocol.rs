@@ -0,0 +1,597 @@
+use crate::tools::events::EVENT_NAMES;
+use crate::tools::overrides::{self, OverrideMode};
+use crate::tools::quarantine::QuarantineAction;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+/*
+    Control protocol spoken on the control socket.
+
+    Every request and every response is one JSON object terminated by a newline:
+      -> {"v":1,"id":7,"cmd":"status","args":{}}
+      <- {"v":1,"id":7,"ok":true,"result":{"running":false}}
+      <- {"v":1,"id":8,"ok":false,"error":{"code":"unknown_command","message":"..."}}
+
+    Clients may open with a `hello` carrying the versions they speak, the daemon answers with
+    the version it picked. After a `subscribe` the connection only carries event lines like
+      <- {"v":1,"at":"2025-05-01 10:00:00","event":"vpn_connected","data":{...}}
+
+    Anything that does not start with `{` is treated as one of the old bare-word commands
+    (status/start/stop) sent by the `vpns` script and gets a free text reply.
+*/
+
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+pub(crate) const SUPPORTED_VERSIONS: &[u32] = &[1];
+pub(crate) const MAX_LINE_LENGTH: usize = 64 * 1024;
+pub(crate) const DEFAULT_LOG_LINES: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorCode {
+    ParseError,
+    InvalidRequest,
+    UnsupportedVersion,
+    UnknownCommand,
+    AlreadyRunning,
+    NotRunning,
+    Busy,
+    ProfileNotFound,
+    SwitchOff,
+    PermissionDenied,
+    Internal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ErrorBody {
+    pub(crate) code: ErrorCode,
+    pub(crate) message: String,
+}
+
+impl ErrorBody {
+    pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Request {
+    #[serde(default)]
+    pub(crate) v: Option<u32>,
+    #[serde(default)]
+    pub(crate) id: Value,
+    pub(crate) cmd: String,
+    #[serde(default)]
+    pub(crate) args: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Response {
+    pub(crate) v: u32,
+    pub(crate) id: Value,
+    pub(crate) ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<ErrorBody>,
+}
+
+impl Response {
+    pub(crate) fn ok(id: Value, result: Value) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            id,
+            ok: true,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub(crate) fn err(id: Value, error: ErrorBody) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            id,
+            ok: false,
+            result: None,
+            error: Some(error),
+        }
+    }
+
+    pub(crate) fn to_line(&self) -> String {
+        // Serializing plain structs and Values cannot fail
+        let mut line = serde_json::to_string(self).unwrap_or_default();
+        line.push('\n');
+        line
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Hello {
+        versions: Vec<u32>,
+    },
+    Status,
+    Start,
+    Stop,
+    Connect {
+        profile: String,
+    },
+    Reload,
+    Override {
+        mode: OverrideMode,
+        duration: Option<Duration>,
+    },
+    Logs {
+        lines: usize,
+    },
+    Profiles,
+    Quarantine {
+        action: QuarantineAction,
+    },
+    Subscribe {
+        events: Option<Vec<String>>,
+    },
+}
+
+impl Command {
+    pub(crate) const NAMES: &'static [&'static str] = &[
+        "hello",
+        "status",
+        "start",
+        "stop",
+        "connect",
+        "reload",
+        "override",
+        "logs",
+        "profiles",
+        "quarantine",
+        "subscribe",
+    ];
+
+    pub(crate) fn from_request(request: &Request) -> Result<Self, ErrorBody> {
+        if let Some(v) = request.v
+            && !SUPPORTED_VERSIONS.contains(&v)
+        {
+            return Err(ErrorBody::new(
+                ErrorCode::UnsupportedVersion,
+                format!("Protocol version {} is not supported", v),
+            ));
+        }
+
+        match request.cmd.as_str() {
+            "hello" => {
+                let versions = match request.args.get("versions") {
+                    Some(versions) => serde_json::from_value(versions.clone()).map_err(|_| {
+                        ErrorBody::new(
+                            ErrorCode::InvalidRequest,
+                            "`versions` must be a list of integers",
+                        )
+                    })?,
+                    None => vec![PROTOCOL_VERSION],
+                };
+                Ok(Command::Hello { versions })
+            }
+            "status" => Ok(Command::Status),
+            "start" => Ok(Command::Start),
+            "stop" => Ok(Command::Stop),
+            "connect" => match request.args.get("profile").and_then(Value::as_str) {
+                Some(profile) if !profile.trim().is_empty() => Ok(Command::Connect {
+                    profile: profile.trim().to_string(),
+                }),
+                _ => Err(ErrorBody::new(
+                    ErrorCode::InvalidRequest,
+                    "`profile` must be a profile name, glob or substring",
+                )),
+            },
+            "reload" => Ok(Command::Reload),
+            "override" => {
+                let mode = request
+                    .args
+                    .get("mode")
+                    .and_then(|mode| serde_json::from_value(mode.clone()).ok())
+                    .ok_or_else(|| {
+                        ErrorBody::new(
+                            ErrorCode::InvalidRequest,
+                            "`mode` must be force-on, force-off or follow-switch",
+                        )
+                    })?;
+                // Seconds, or a string like "30m" or "2h"
+                let duration = match request.args.get("duration") {
+                    None | Some(Value::Null) => None,
+                    Some(Value::String(text)) => overrides::parse_duration(text),
+                    Some(value) => value
+                        .as_u64()
+                        .filter(|secs| *secs > 0)
+                        .map(Duration::from_secs),
+                };
+                if duration.is_none() && request.args.get("duration").is_some_and(|v| !v.is_null())
+                {
+                    return Err(ErrorBody::new(
+                        ErrorCode::InvalidRequest,
+                        "`duration` must be seconds or a number with s, m, h or d",
+                    ));
+                }
+                Ok(Command::Override { mode, duration })
+            }
+            "logs" => {
+                let lines = match request.args.get("lines") {
+                    Some(lines) => lines.as_u64().ok_or_else(|| {
+                        ErrorBody::new(
+                            ErrorCode::InvalidRequest,
+                            "`lines` must be a positive integer",
+                        )
+                    })? as usize,
+                    None => DEFAULT_LOG_LINES,
+                };
+                Ok(Command::Logs { lines })
+            }
+            "profiles" => Ok(Command::Profiles),
+            "quarantine" => {
+                let profile = match request.args.get("profile") {
+                    None | Some(Value::Null) => None,
+                    Some(Value::String(profile)) if !profile.trim().is_empty() => {
+                        Some(profile.trim().to_string())
+                    }
+                    Some(_) => {
+                        return Err(ErrorBody::new(
+                            ErrorCode::InvalidRequest,
+                            "`profile` must be a profile name, glob or substring",
+                        ));
+                    }
+                };
+                let action = match (request.args.get("action").and_then(Value::as_str), profile) {
+                    (None | Some("list"), _) => QuarantineAction::List,
+                    (Some("clear"), profile) => QuarantineAction::Clear(profile),
+                    (Some("pin"), Some(profile)) => QuarantineAction::Pin(profile),
+                    (Some("pin"), None) => {
+                        return Err(ErrorBody::new(
+                            ErrorCode::InvalidRequest,
+                            "pin needs a `profile`",
+                        ));
+                    }
+                    (Some(_), _) => {
+                        return Err(ErrorBody::new(
+                            ErrorCode::InvalidRequest,
+                            "`action` must be list, clear or pin",
+                        ));
+                    }
+                };
+                Ok(Command::Quarantine { action })
+            }
+            "subscribe" => {
+                let events = match request.args.get("events") {
+                    Some(events) => {
+                        let events: Vec<String> =
+                            serde_json::from_value(events.clone()).map_err(|_| {
+                                ErrorBody::new(
+                                    ErrorCode::InvalidRequest,
+                                    "`events` must be a list of event names",
+                                )
+                            })?;
+                        if let Some(unknown) = events
+                            .iter()
+                            .find(|event| !EVENT_NAMES.contains(&event.as_str()))
+                        {
+                            return Err(ErrorBody::new(
+                                ErrorCode::InvalidRequest,
+                                format!("Unknown event: {}", unknown),
+                            ));
+                        }
+                        Some(events)
+                    }
+                    None => None,
+                };
+                Ok(Command::Subscribe { events })
+            }
+            other => Err(ErrorBody::new(
+                ErrorCode::UnknownCommand,
+                format!("Unknown command: {}", other),
+            )),
+        }
+    }
+
+    // Commands that change what the daemon is doing need full access, the rest only read
+    pub(crate) fn is_mutating(&self) -> bool {
+        match self {
+            Command::Start
+            | Command::Stop
+            | Command::Connect { .. }
+            | Command::Reload
+            | Command::Override { .. } => true,
+            // Anyone may look, letting profiles out or pinning them takes full access
+            Command::Quarantine { action } => *action != QuarantineAction::List,
+            Command::Hello { .. }
+            | Command::Status
+            | Command::Logs { .. }
+            | Command::Profiles
+            | Command::Subscribe { .. } => false,
+        }
+    }
+
+    pub(crate) fn get_name(&self) -> &'static str {
+        match self {
+            Command::Hello { .. } => "hello",
+            Command::Status => "status",
+            Command::Start => "start",
+            Command::Stop => "stop",
+            Command::Connect { .. } => "connect",
+            Command::Reload => "reload",
+            Command::Override { .. } => "override",
+            Command::Logs { .. } => "logs",
+            Command::Profiles => "profiles",
+            Command::Quarantine { .. } => "quarantine",
+            Command::Subscribe { .. } => "subscribe",
+        }
+    }
+
+    pub(crate) fn from_legacy(word: &str) -> Option<Self> {
+        match word {
+            "status" => Some(Command::Status),
+            "start" => Some(Command::Start),
+            "stop" => Some(Command::Stop),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Incoming {
+    Json(Request),
+    Legacy(String),
+}
+
+pub(crate) fn parse_line(line: &str) -> Result<Incoming, ErrorBody> {
+    let line = line.trim();
+    if line.starts_with('{') {
+        serde_json::from_str::<Request>(line)
+            .map(Incoming::Json)
+            .map_err(|e| ErrorBody::new(ErrorCode::ParseError, format!("Invalid request: {}", e)))
+    } else {
+        Ok(Incoming::Legacy(line.to_string()))
+    }
+}
+
+pub(crate) fn negotiate(versions: &[u32]) -> Result<Value, ErrorBody> {
+    match versions
+        .iter()
+        .filter(|v| SUPPORTED_VERSIONS.contains(v))
+        .max()
+    {
+        Some(version) => Ok(json!({
+            "version": version,
+            "server": format!("vpn_handler/{}", env!("CARGO_PKG_VERSION")),
+            "commands": Command::NAMES,
+        })),
+        None => Err(ErrorBody::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "No common protocol version, daemon supports {:?}",
+                SUPPORTED_VERSIONS
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(line: &str) -> Request {
+        match parse_line(line) {
+            Ok(Incoming::Json(request)) => request,
+            other => panic!("Expected a JSON request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_json_request() {
+        let request = request(r#"{"v":1,"id":"abc","cmd":"status"}"#);
+        assert_eq!(request.id, json!("abc"));
+        assert_eq!(request.v, Some(1));
+        assert_eq!(Command::from_request(&request).unwrap(), Command::Status);
+    }
+
+    #[test]
+    fn test_parse_legacy_word() {
+        let incoming = parse_line("stop\n");
+        assert!(matches!(incoming, Ok(Incoming::Legacy(ref word)) if word == "stop"));
+        assert_eq!(Command::from_legacy("stop"), Some(Command::Stop));
+        assert_eq!(Command::from_legacy("restart"), None);
+    }
+
+    #[test]
+    fn test_parse_malformed_json() {
+        let result = parse_line(r#"{"cmd": "status""#);
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().code, ErrorCode::ParseError);
+    }
+
+    #[test]
+    fn test_unknown_command_and_version() {
+        let result = Command::from_request(&request(r#"{"id":1,"cmd":"reboot"}"#));
+        assert_eq!(result.unwrap_err().code, ErrorCode::UnknownCommand);
+
+        let result = Command::from_request(&request(r#"{"v":99,"id":1,"cmd":"status"}"#));
+        assert_eq!(result.unwrap_err().code, ErrorCode::UnsupportedVersion);
+    }
+
+    #[test]
+    fn test_connect_arguments() {
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"connect","args":{"profile":"se-*"}}"#,
+        ));
+        assert_eq!(
+            command.unwrap(),
+            Command::Connect {
+                profile: "se-*".to_string()
+            }
+        );
+
+        // A profile is required and must say something
+        let command = Command::from_request(&request(r#"{"id":1,"cmd":"connect"}"#));
+        assert_eq!(command.unwrap_err().code, ErrorCode::InvalidRequest);
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"connect","args":{"profile":" "}}"#,
+        ));
+        assert_eq!(command.unwrap_err().code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn test_override_arguments() {
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"override","args":{"mode":"force-on","duration":"2h"}}"#,
+        ));
+        assert_eq!(
+            command.unwrap(),
+            Command::Override {
+                mode: OverrideMode::ForceOn,
+                duration: Some(Duration::from_secs(7200))
+            }
+        );
+
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"override","args":{"mode":"follow-switch"}}"#,
+        ));
+        assert_eq!(
+            command.unwrap(),
+            Command::Override {
+                mode: OverrideMode::FollowSwitch,
+                duration: None
+            }
+        );
+
+        for args in [
+            r#"{"mode":"sideways"}"#,
+            r#"{"mode":"force-off","duration":"soon"}"#,
+            r#"{"mode":"force-off","duration":0}"#,
+        ] {
+            let line = format!(r#"{{"id":1,"cmd":"override","args":{}}}"#, args);
+            let command = Command::from_request(&request(&line));
+            assert_eq!(command.unwrap_err().code, ErrorCode::InvalidRequest);
+        }
+    }
+
+    #[test]
+    fn test_quarantine_arguments() {
+        let command = Command::from_request(&request(r#"{"id":1,"cmd":"quarantine"}"#)).unwrap();
+        assert_eq!(
+            command,
+            Command::Quarantine {
+                action: QuarantineAction::List
+            }
+        );
+        assert!(!command.is_mutating());
+
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"quarantine","args":{"action":"pin","profile":"se-*"}}"#,
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            Command::Quarantine {
+                action: QuarantineAction::Pin("se-*".to_string())
+            }
+        );
+        assert!(command.is_mutating());
+
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"quarantine","args":{"action":"clear"}}"#,
+        ));
+        assert_eq!(
+            command.unwrap(),
+            Command::Quarantine {
+                action: QuarantineAction::Clear(None)
+            }
+        );
+
+        for args in [
+            r#"{"action":"pin"}"#,
+            r#"{"action":"release"}"#,
+            r#"{"action":"clear","profile":5}"#,
+        ] {
+            let line = format!(r#"{{"id":1,"cmd":"quarantine","args":{}}}"#, args);
+            let command = Command::from_request(&request(&line));
+            assert_eq!(command.unwrap_err().code, ErrorCode::InvalidRequest);
+        }
+    }
+
+    #[test]
+    fn test_logs_arguments() {
+        let command =
+            Command::from_request(&request(r#"{"id":1,"cmd":"logs","args":{"lines":5}}"#));
+        assert_eq!(command.unwrap(), Command::Logs { lines: 5 });
+
+        let command = Command::from_request(&request(r#"{"id":1,"cmd":"logs"}"#));
+        assert_eq!(
+            command.unwrap(),
+            Command::Logs {
+                lines: DEFAULT_LOG_LINES
+            }
+        );
+
+        let command =
+            Command::from_request(&request(r#"{"id":1,"cmd":"logs","args":{"lines":"all"}}"#));
+        assert_eq!(command.unwrap_err().code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn test_subscribe_filter() {
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"subscribe","args":{"events":["vpn_connected","error"]}}"#,
+        ));
+        assert_eq!(
+            command.unwrap(),
+            Command::Subscribe {
+                events: Some(vec!["vpn_connected".to_string(), "error".to_string()])
+            }
+        );
+
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"subscribe","args":{"events":["lunch"]}}"#,
+        ));
+        assert_eq!(command.unwrap_err().code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn test_hello_negotiation() {
+        let command = Command::from_request(&request(
+            r#"{"id":1,"cmd":"hello","args":{"versions":[3,1,2]}}"#,
+        ));
+        let versions = match command {
+            Ok(Command::Hello { versions }) => versions,
+            other => panic!("Expected hello, got {:?}", other),
+        };
+        let result = negotiate(&versions);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap()["version"], json!(1));
+
+        let result = negotiate(&[2, 3]);
+        assert_eq!(result.unwrap_err().code, ErrorCode::UnsupportedVersion);
+    }
+
+    #[test]
+    fn test_response_line() {
+        let line = Response::err(
+            json!(4),
+            ErrorBody::new(ErrorCode::NotRunning, "Daemon is not running"),
+        )
+        .to_line();
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.matches('\n').count(), 1);
+
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["ok"], json!(false));
+        assert_eq!(parsed["id"], json!(4));
+        assert_eq!(parsed["error"]["code"], json!("not_running"));
+        assert!(parsed.get("result").is_none());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::tools::state;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+pub(crate) const DEFAULT_COOL_DOWN: Duration = Duration::from_secs(300);
+pub(crate) const DEFAULT_MAX_COOL_DOWN: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum QuarantineAction {
+    List,
+    // Everything when no profile is given
+    Clear(Option<String>),
+    Pin(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+struct Entry {
+    // Failures since the profile last connected, each one doubles the cool-down
+    failures: u32,
+    // Unix time the profile may be picked again
+    until: Option<i64>,
+    // Kept out until cleared by hand
+    pinned: bool,
+}
+
+/*
+    Keeps profiles that failed to connect out of the switch's picks for a while. The first
+    failure costs the cool-down (5 minutes by default), every further one in a row doubles it up
+    to the maximum (a day). A profile that connects is forgiven, and one that stayed out of
+    trouble for the maximum cool-down after its last quarantine starts from scratch. Pinned
+    profiles stay out until cleared. Times are Unix seconds, passed in so tests can move the
+    clock.
+*/
+pub(crate) struct Quarantine {
+    entries: BTreeMap<String, Entry>,
+    cool_down: Duration,
+    max_cool_down: Duration,
+    state_file: Option<PathBuf>,
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            cool_down: DEFAULT_COOL_DOWN,
+            max_cool_down: DEFAULT_MAX_COOL_DOWN,
+            state_file: None,
+        }
+    }
+}
+
+impl Quarantine {
+    // A missing state file is a first start, an unreadable one is reported and starts over
+    pub(crate) fn load(state_file: &Path) -> (Self, Option<io::Error>) {
+        let mut quarantine = Self {
+            state_file: Some(state_file.to_path_buf()),
+            ..Self::default()
+        };
+        let error = match std::fs::read_to_string(state_file) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(entries) => {
+                    quarantine.entries = entries;
+                    None
+                }
+                Err(e) => Some(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", state_file.display(), e),
+                )),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => Some(io::Error::new(
+                e.kind(),
+                format!("{}: {}", state_file.display(), e),
+            )),
+        };
+        (quarantine, error)
+    }
+
+    // A cool-down of zero turns quarantine off, pins still hold
+    pub(crate) fn configure(&mut self, cool_down: Duration, max_cool_down: Duration) {
+        self.cool_down = cool_down;
+        self.max_cool_down = max_cool_down;
+    }
+
+    pub(crate) fn is_held(&self, profile: &str, now: i64) -> bool {
+        match self.entries.get(profile) {
+            Some(entry) => entry.pinned || entry.until.is_some_and(|until| until > now),
+            None => false,
+        }
+    }
+
+    pub(crate) fn is_pinned(&self, profile: &str) -> bool {
+        self.entries.get(profile).is_some_and(|entry| entry.pinned)
+    }
+
+    // The cool-down the failure earned, None while quarantine is off
+    pub(crate) fn record_failure(
+        &mut self,
+        profile: &str,
+        now: i64,
+    ) -> Result<Option<Duration>, io::Error> {
+        if self.cool_down.is_zero() {
+            return Ok(None);
+        }
+        let max = self.max_cool_down.max(self.cool_down);
+        let entry = self.entries.entry(profile.to_string()).or_default();
+        if entry
+            .until
+            .is_some_and(|until| until.saturating_add(max.as_secs() as i64) < now)
+        {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+
+        let factor = 2u32.saturating_pow(entry.failures - 1);
+        let cool_down = self.cool_down.saturating_mul(factor).min(max);
+        entry.until = Some(now.saturating_add(cool_down.as_secs() as i64));
+        self.save()?;
+        Ok(Some(cool_down))
+    }
+
+    // A connection that came up wipes the slate, unless someone pinned the profile
+    pub(crate) fn record_success(&mut self, profile: &str) -> Result<(), io::Error> {
+        match self.entries.get(profile) {
+            Some(entry) if !entry.pinned => {
+                self.entries.remove(profile);
+                self.save()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn pin(&mut self, profiles: &[String]) -> Result<(), io::Error> {
+        for profile in profiles {
+            self.entries.entry(profile.to_string()).or_default().pinned = true;
+        }
+        self.save()
+    }
+
+    // Returns what was let out, all of it when `profiles` is None
+    pub(crate) fn clear(&mut self, profiles: Option<&[String]>) -> Result<Vec<String>, io::Error> {
+        let cleared: Vec<String> = match profiles {
+            Some(profiles) => profiles
+                .iter()
+                .filter(|profile| self.entries.contains_key(*profile))
+                .cloned()
+                .collect(),
+            None => self.entries.keys().cloned().collect(),
+        };
+        for profile in &cleared {
+            self.entries.remove(profile);
+        }
+        if !cleared.is_empty() {
+            self.save()?;
+        }
+        Ok(cleared)
+    }
+
+    // Profiles held right now, expired entries only linger to remember their failures
+    pub(crate) fn to_json(&self, now: i64) -> Value {
+        let held: Vec<Value> = self
+            .entries
+            .iter()
+            .filter(|(profile, _)| self.is_held(profile, now))
+            .map(|(profile, entry)| {
+                json!({
+                    "profile": profile,
+                    "failures": entry.failures,
+                    "pinned": entry.pinned,
+                    "until": entry.until.filter(|_| !entry.pinned).and_then(format_time),
+                })
+            })
+            .collect();
+        json!({
+            "count": held.len(),
+            "profiles": held,
+            "cool_down_secs": self.cool_down.as_secs(),
+            "max_cool_down_secs": self.max_cool_down.as_secs(),
+        })
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        match &self.state_file {
+            Some(path) => state::write_atomically(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn now() -> i64 {
+    Local::now().timestamp()
+}
+
+fn format_time(secs: i64) -> Option<String> {
+    DateTime::from_timestamp(secs, 0)
+        .map(|time| time.with_timezone(&Local).format(TIME_FORMAT).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = "/vpn/se.ovpn";
+
+    #[test]
+    fn test_cool_down_doubles() {
+        let mut quarantine = Quarantine::default();
+        quarantine.configure(Duration::from_secs(60), Duration::from_secs(200));
+
+        let cool_down = quarantine.record_failure(PROFILE, 1000).unwrap();
+        assert_eq!(cool_down, Some(Duration::from_secs(60)));
+        assert!(quarantine.is_held(PROFILE, 1059));
+        assert!(!quarantine.is_held(PROFILE, 1060));
+        assert!(!quarantine.is_held("/vpn/us.ovpn", 1000));
+
+        let cool_down = quarantine.record_failure(PROFILE, 1100).unwrap();
+        assert_eq!(cool_down, Some(Duration::from_secs(120)));
+        let cool_down = quarantine.record_failure(PROFILE, 1300).unwrap();
+        assert_eq!(cool_down, Some(Duration::from_secs(200)));
+
+        // Out of trouble for long enough, the next failure starts over
+        let cool_down = quarantine.record_failure(PROFILE, 2000).unwrap();
+        assert_eq!(cool_down, Some(Duration::from_secs(60)));
+
+        quarantine.record_success(PROFILE).unwrap();
+        assert_eq!(quarantine.to_json(2000)["count"], json!(0));
+        let cool_down = quarantine.record_failure(PROFILE, 2100).unwrap();
+        assert_eq!(cool_down, Some(Duration::from_secs(60)));
+
+        quarantine.configure(Duration::ZERO, Duration::ZERO);
+        assert_eq!(quarantine.record_failure(PROFILE, 2200).unwrap(), None);
+    }
+
+    #[test]
+    fn test_pin_and_clear() {
+        let mut quarantine = Quarantine::default();
+        let profiles = [PROFILE.to_string(), "/vpn/us.ovpn".to_string()];
+        quarantine.pin(&profiles[..1]).unwrap();
+        quarantine.record_failure(&profiles[1], 0).unwrap();
+
+        // Pinned profiles outlast any cool-down and a good connection
+        assert!(quarantine.is_held(PROFILE, i64::MAX));
+        quarantine.record_success(PROFILE).unwrap();
+        assert!(quarantine.is_pinned(PROFILE));
+
+        let report = quarantine.to_json(0);
+        assert_eq!(report["count"], json!(2));
+        assert_eq!(report["profiles"][0]["pinned"], json!(true));
+        assert_eq!(report["profiles"][0]["until"], Value::Null);
+        assert_eq!(report["profiles"][1]["failures"], json!(1));
+        assert!(report["profiles"][1]["until"].is_string());
+
+        let cleared = quarantine.clear(Some(&profiles[..1])).unwrap();
+        assert_eq!(cleared, vec![PROFILE.to_string()]);
+        assert!(!quarantine.is_held(PROFILE, 0));
+        assert_eq!(quarantine.clear(None).unwrap(), vec![profiles[1].clone()]);
+        assert_eq!(quarantine.to_json(0)["count"], json!(0));
+    }
+
+    #[test]
+    fn test_survives_restart() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state/quarantine.json");
+
+        let (mut quarantine, error) = Quarantine::load(&path);
+        assert!(error.is_none());
+        quarantine.record_failure(PROFILE, 1000).unwrap();
+        quarantine.pin(&["/vpn/us.ovpn".to_string()]).unwrap();
+
+        let (quarantine, error) = Quarantine::load(&path);
+        assert!(error.is_none());
+        assert!(quarantine.is_held(PROFILE, 1000));
+        assert!(quarantine.is_pinned("/vpn/us.ovpn"));
+
+        std::fs::write(&path, "not json").unwrap();
+        let (quarantine, error) = Quarantine::load(&path);
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(!quarantine.is_held(PROFILE, 1000));
+    }
+}
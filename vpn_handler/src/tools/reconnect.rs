@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+pub(crate) const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+pub(crate) const DEFAULT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+pub(crate) const DEFAULT_RECONNECT_ATTEMPTS: u32 = 8;
+pub(crate) const DEFAULT_FAILOVER_AFTER: u32 = 2;
+
+// How the runner gets a tunnel back, the reconnect settings under [runner]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ReconnectPolicy {
+    pub(crate) delay: Duration,
+    pub(crate) max_delay: Duration,
+    // Tries before giving up, 0 for no limit
+    pub(crate) attempts: u32,
+    // Failures in a row on one profile before another one is tried, 0 to stay on it
+    pub(crate) failover_after: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            delay: DEFAULT_RECONNECT_DELAY,
+            max_delay: DEFAULT_MAX_RECONNECT_DELAY,
+            attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            failover_after: DEFAULT_FAILOVER_AFTER,
+        }
+    }
+}
+
+// What the next attempt connects to
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Target {
+    Same(String),
+    // Whatever the selection strategy picks, except for the profiles that were given up on
+    Other(Vec<String>),
+}
+
+/*
+    Keeps track of a tunnel that went down or never came up while the VPN should be on. Every
+    failure in a row doubles the wait before the next attempt, from the delay up to the maximum.
+    The same profile is tried again until it failed `failover_after` times, then the selection
+    strategy picks one of the others. Once the attempts are used up nothing is due until the
+    streak is reset. Instants are passed in so tests can move the clock.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct Reconnect {
+    policy: ReconnectPolicy,
+    failures: u32,
+    profile: Option<String>,
+    on_profile: u32,
+    avoid: Vec<String>,
+    due: Option<Instant>,
+}
+
+impl Reconnect {
+    pub(crate) fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    // Takes effect from the next failure on
+    pub(crate) fn configure(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy;
+    }
+
+    /*
+        Counts a failure of `profile`, or of whatever was tried when no tunnel got as far as
+        having one. Returns the wait before the next attempt, None once the attempts are used up.
+    */
+    pub(crate) fn record_failure(
+        &mut self,
+        profile: Option<&str>,
+        now: Instant,
+    ) -> Option<Duration> {
+        if let Some(profile) = profile
+            && self.profile.as_deref() != Some(profile)
+        {
+            self.profile = Some(profile.to_string());
+            self.on_profile = 0;
+        }
+        self.failures += 1;
+        self.on_profile += 1;
+        if let Some(profile) = &self.profile
+            && self.policy.failover_after > 0
+            && self.on_profile >= self.policy.failover_after
+            && !self.avoid.contains(profile)
+        {
+            self.avoid.push(profile.clone());
+        }
+
+        if self.policy.attempts > 0 && self.failures > self.policy.attempts {
+            self.due = None;
+            return None;
+        }
+        let factor = 2u32.saturating_pow(self.failures - 1);
+        let delay = self
+            .policy
+            .delay
+            .saturating_mul(factor)
+            .min(self.policy.max_delay.max(self.policy.delay));
+        self.due = Some(now + delay);
+        Some(delay)
+    }
+
+    pub(crate) fn is_due(&self, now: Instant) -> bool {
+        self.due.is_some_and(|due| due <= now)
+    }
+
+    // The attempt about to be made, counting from 1
+    pub(crate) fn get_attempt(&self) -> u32 {
+        self.failures
+    }
+
+    pub(crate) fn next_target(&self) -> Target {
+        match &self.profile {
+            Some(profile) if !self.avoid.contains(profile) => Target::Same(profile.clone()),
+            _ => Target::Other(self.avoid.clone()),
+        }
+    }
+
+    // A tunnel came up, or the VPN was turned off: the next failure starts a fresh streak
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.policy.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SE: &str = "/vpn/se.ovpn";
+    const US: &str = "/vpn/us.ovpn";
+
+    fn policy(attempts: u32, failover_after: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+            attempts,
+            failover_after,
+        }
+    }
+
+    #[test]
+    fn test_backoff_and_give_up() {
+        let now = Instant::now();
+        let mut reconnect = Reconnect::new(policy(4, 0));
+        assert!(!reconnect.is_due(now));
+
+        let delays: Vec<Option<u64>> = (0..5)
+            .map(|_| {
+                reconnect
+                    .record_failure(Some(SE), now)
+                    .map(|delay| delay.as_secs())
+            })
+            .collect();
+        assert_eq!(delays, vec![Some(2), Some(4), Some(8), Some(10), None]);
+        assert!(!reconnect.is_due(now + Duration::from_secs(3600)));
+
+        reconnect.reset();
+        assert_eq!(
+            reconnect.record_failure(Some(SE), now),
+            Some(Duration::from_secs(2))
+        );
+        assert!(!reconnect.is_due(now + Duration::from_secs(1)));
+        assert!(reconnect.is_due(now + Duration::from_secs(2)));
+        assert_eq!(reconnect.get_attempt(), 1);
+        // Staying on the profile for good
+        assert_eq!(reconnect.next_target(), Target::Same(SE.to_string()));
+    }
+
+    #[test]
+    fn test_failover() {
+        let now = Instant::now();
+        let mut reconnect = Reconnect::new(policy(0, 2));
+        reconnect.record_failure(Some(SE), now);
+        assert_eq!(reconnect.next_target(), Target::Same(SE.to_string()));
+        // A start that never got as far as a tunnel counts against the profile tried
+        reconnect.record_failure(None, now);
+        assert_eq!(reconnect.next_target(), Target::Other(vec![SE.to_string()]));
+
+        reconnect.record_failure(Some(US), now);
+        assert_eq!(reconnect.next_target(), Target::Same(US.to_string()));
+        reconnect.record_failure(Some(US), now);
+        assert_eq!(
+            reconnect.next_target(),
+            Target::Other(vec![SE.to_string(), US.to_string()])
+        );
+
+        // No limit, the wait just stays at the maximum
+        assert_eq!(
+            reconnect.record_failure(None, now),
+            Some(Duration::from_secs(10))
+        );
+    }
+}
@@ -0,0 +1,662 @@
+use crate::tools::backend::{Backends, Health};
+use crate::tools::config;
+use crate::tools::events::{Event, EventBus};
+use crate::tools::handler;
+use crate::tools::logger::Logger;
+use crate::tools::notifier::Notifier;
+use crate::tools::output::Failure;
+use crate::tools::overrides::{self, Override};
+use crate::tools::protocol::{ErrorBody, ErrorCode};
+use crate::tools::reconnect::{Reconnect, Target};
+use crate::tools::settings::Settings;
+use crate::tools::status::Status;
+use crate::tools::supervisor::CancelToken;
+use serde_json::{Value, json};
+use std::io;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Short reads keep a stop or a command waiting for at most this long
+const READ_TIMEOUT: Duration = Duration::from_millis(250);
+// Slack on top of the waits a pass is allowed, for logging, events and the odd slow lock
+const HEARTBEAT_GRACE: Duration = Duration::from_secs(30);
+
+// Stamped by the runner on every pass, so the watchdog can tell a busy runner from a stuck one
+pub(crate) struct Heartbeat {
+    last: Mutex<Instant>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) fn beat(&self) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    pub(crate) fn age(&self) -> Duration {
+        self.last
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed()
+    }
+}
+
+// The longest one pass may take: bringing a tunnel down, starting another and waiting on it
+pub(crate) fn pass_budget(settings: &Settings) -> Duration {
+    settings.disconnect_wait
+        + Duration::from_secs(settings.start_attempts.into())
+        + settings.connect_wait
+        + READ_TIMEOUT
+        + HEARTBEAT_GRACE
+}
+
+// Everything a run of the runner shares with the daemon
+#[derive(Clone)]
+pub(crate) struct RunnerContext {
+    pub(crate) logger: Arc<Mutex<Logger>>,
+    pub(crate) notifier: Arc<Mutex<Notifier>>,
+    pub(crate) status: Arc<Mutex<Status>>,
+    pub(crate) events: Arc<EventBus>,
+    pub(crate) config: Arc<config::File>,
+    pub(crate) overrides: Arc<Mutex<Override>>,
+    pub(crate) settings: Arc<Mutex<Settings>>,
+    pub(crate) heartbeat: Arc<Heartbeat>,
+}
+
+impl RunnerContext {
+    // A copy, so a reload in the middle of a step cannot mix old and new values
+    fn settings(&self) -> Settings {
+        self.settings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+// Requests from control clients, picked up between two reads of the serial port
+pub(crate) enum RunnerCommand {
+    Connect {
+        query: String,
+        reply: Sender<Result<Value, ErrorBody>>,
+    },
+}
+
+// Returns cleanly only once the token is cancelled, and takes the tunnel down however it returns
+pub(crate) fn run(
+    context: &RunnerContext,
+    token: &CancelToken,
+    commands: &Receiver<RunnerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let RunnerContext {
+        logger,
+        status,
+        events,
+        config,
+        ..
+    } = context;
+    // The daemon may have sat idle for a long time, that says nothing about this run
+    context.heartbeat.beat();
+    // The port is picked up when the runner starts, the timings on every use
+    let settings = context.settings();
+    let mut port = serialport::new(&settings.serial_port, settings.baud_rate)
+        .timeout(READ_TIMEOUT)
+        .open()?;
+    status
+        .lock()
+        .unwrap()
+        .set_serial_port(Some(&settings.serial_port));
+
+    // The daemon scans on startup and on reload, a directory that was missing then gets another go
+    if config.get_file_paths()?.is_empty() {
+        config.reload()?;
+    }
+    let mut handler = handler::Handler::with_config(Arc::clone(config))
+        .with_attempts(settings.start_attempts)
+        .with_backends(
+            Backends::new(settings.backend)
+                .with_logger(Arc::clone(logger))
+                .with_stop_timeout(settings.disconnect_wait),
+        )
+        .with_stop_timeout(settings.disconnect_wait);
+
+    // However the loop ends, the tunnel does not outlive it
+    let result = listen(
+        &mut *port,
+        &mut handler,
+        &settings,
+        context,
+        token,
+        commands,
+    );
+    let down = take_down(&mut handler, context);
+    events.publish(Event::VpnDisconnected);
+    {
+        let mut status = status.lock().unwrap();
+        status.record_disconnected();
+        status.set_serial_port(None);
+    }
+    result?;
+    Ok(down?)
+}
+
+// Follows the switch and the commands until the token is cancelled or something fails
+fn listen(
+    port: &mut dyn serialport::SerialPort,
+    handler: &mut handler::Handler,
+    settings: &Settings,
+    context: &RunnerContext,
+    token: &CancelToken,
+    commands: &Receiver<RunnerCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let RunnerContext {
+        logger,
+        status,
+        events,
+        overrides,
+        ..
+    } = context;
+    // What the switch last said, and whether the VPN was last brought up or down
+    let mut switch_on: Option<bool> = None;
+    let mut vpn_on = false;
+    let mut last_heard = Instant::now();
+    let mut reconnect = Reconnect::new(settings.reconnect_policy());
+    loop {
+        context.heartbeat.beat();
+        if token.is_cancelled() {
+            return Ok(());
+        }
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                RunnerCommand::Connect { query, reply } => {
+                    let result = if !vpn_on {
+                        Err(ErrorBody::new(
+                            ErrorCode::SwitchOff,
+                            "The VPN is off, turn the switch on or force it on first",
+                        ))
+                    } else {
+                        let result = connect(&query, handler, &mut reconnect, context, token);
+                        if result.is_err() && handler.get_profile().is_none() {
+                            // Lost the old connection too, the next pass starts a fresh one
+                            vpn_on = false;
+                        }
+                        result
+                    };
+                    reply.send(result).ok();
+                }
+            }
+        }
+
+        let mut buffer = [0; 9];
+        match port.read(&mut buffer) {
+            Ok(bytes_read) if bytes_read > 0 => {
+                last_heard = Instant::now();
+                let message = String::from_utf8_lossy(&buffer[0..bytes_read])
+                    .trim()
+                    .to_string();
+
+                let position = match message.as_str() {
+                    "Turn On" => Some(true),
+                    "Turn Off" => Some(false),
+                    _ => None,
+                };
+                if let Some(position) = position {
+                    status.lock().unwrap().record_message(&message);
+                    if switch_on != Some(position) {
+                        switch_on = Some(position);
+                        events.publish(Event::SwitchToggled {
+                            position: if position { "on" } else { "off" }.to_string(),
+                        });
+                    }
+                }
+            }
+            // Nothing to read yet, which is only a problem once it has gone on for too long
+            Err(e)
+                if e.kind() == io::ErrorKind::TimedOut
+                    && last_heard.elapsed() < settings.silence_timeout => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "No word from the switch for {} seconds",
+                        settings.silence_timeout.as_secs()
+                    ),
+                )));
+            }
+            Err(e) => return Err(Box::new(e)),
+            _ => {}
+        }
+
+        // The switch decides unless an override says otherwise
+        match overrides::current(overrides, logger, events).wanted(switch_on) {
+            Some(true) if !vpn_on => {
+                vpn_on = true;
+                turn_on(handler, &mut reconnect, context, token)?;
+            }
+            Some(false) if vpn_on => {
+                vpn_on = false;
+                reconnect.reset();
+                status.lock().unwrap().set_reconnect(None);
+                turn_off(handler, context)?;
+            }
+            Some(true) => supervise(handler, &mut reconnect, context, token)?,
+            _ => {}
+        }
+    }
+}
+
+fn turn_on(
+    handler: &mut handler::Handler,
+    reconnect: &mut Reconnect,
+    context: &RunnerContext,
+    token: &CancelToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Turning VPN On");
+    context.events.publish(Event::VpnConnecting);
+    reconnect.reset();
+    // Not being able to start is retried like a reconnect, rather than ending the runner
+    if let Err(e) = handler.start() {
+        let msg = format!("Failed to connect: {}", e);
+        context.logger.lock().unwrap().log(&msg).ok();
+        context.status.lock().unwrap().record_error(&msg);
+        schedule_reconnect(reconnect, None, context);
+        return Ok(());
+    }
+    settle(handler, reconnect, context, token)
+}
+
+/*
+    Runs on every pass while the VPN should be on, so a tunnel that went down on its own is
+    noticed within a read timeout. It is reported and brought back once the reconnect is due.
+*/
+fn supervise(
+    handler: &mut handler::Handler,
+    reconnect: &mut Reconnect,
+    context: &RunnerContext,
+    token: &CancelToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if handler.get_profile().is_some() {
+        if let Health::Down(reason) = handler.health() {
+            let profile = handler.get_profile().cloned();
+            tunnel_lost(handler, context, &reason);
+            schedule_reconnect(reconnect, profile.as_deref(), context);
+        }
+    } else if reconnect.is_due(Instant::now()) {
+        context.events.publish(Event::VpnConnecting);
+        let result = match reconnect.next_target() {
+            Target::Same(profile) => handler.start_profile(&profile),
+            Target::Other(avoid) => handler.start_other(&avoid),
+        };
+        match result {
+            Ok(_) => settle(handler, reconnect, context, token)?,
+            Err(e) => {
+                let msg = format!("Failed to reconnect: {}", e);
+                context.logger.lock().unwrap().log(&msg).ok();
+                context.status.lock().unwrap().record_error(&msg);
+                schedule_reconnect(reconnect, None, context);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Waits for the tunnel that was just started, announces it once it is up and retries when not
+fn settle(
+    handler: &mut handler::Handler,
+    reconnect: &mut Reconnect,
+    context: &RunnerContext,
+    token: &CancelToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let RunnerContext {
+        logger,
+        notifier,
+        status,
+        events,
+        ..
+    } = context;
+    record_used(handler, context);
+    match handler.wait_connected(context.settings().connect_wait, token) {
+        Ok(_) => {}
+        // Cut short by a stop, which takes the VPN down again on the next pass
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+        Err(e) => {
+            let profile = handler.get_profile().cloned();
+            connect_failed(handler, context, &e);
+            schedule_reconnect(reconnect, profile.as_deref(), context);
+            return Ok(());
+        }
+    }
+    reconnect.reset();
+    record_outcome(handler, context, true);
+    {
+        let mut status = status.lock().unwrap();
+        status.record_connected(handler.get_profile().cloned(), handler.get_pid());
+        status.set_backend(handler.get_status());
+        status.set_reconnect(None);
+    }
+
+    notifier.lock().unwrap().send_message("STATUS Connected")?;
+    events.publish(Event::VpnConnected {
+        profile: handler.get_profile().cloned(),
+        pid: handler.get_pid(),
+    });
+    let msg = "VPN STATUS CHANGE: Connected".to_string();
+    logger.lock().unwrap().log(&msg).ok();
+    Ok(())
+}
+
+fn turn_off(
+    handler: &mut handler::Handler,
+    context: &RunnerContext,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let RunnerContext {
+        logger,
+        notifier,
+        status,
+        events,
+        ..
+    } = context;
+    println!("Turning VPN Off");
+    take_down(handler, context)?;
+    status.lock().unwrap().record_disconnected();
+
+    notifier
+        .lock()
+        .unwrap()
+        .send_message("STATUS Disconnected")?;
+    events.publish(Event::VpnDisconnected);
+    let msg = "VPN STATUS CHANGE: Disconnected".to_string();
+    logger.lock().unwrap().log(&msg).ok();
+    Ok(())
+}
+
+fn connect(
+    query: &str,
+    handler: &mut handler::Handler,
+    reconnect: &mut Reconnect,
+    context: &RunnerContext,
+    token: &CancelToken,
+) -> Result<Value, ErrorBody> {
+    let RunnerContext {
+        logger,
+        notifier,
+        status,
+        events,
+        ..
+    } = context;
+    // Resolved up front so a typo never takes down the connection that is up
+    let profile = handler
+        .find_profile(query)
+        .map_err(|e| ErrorBody::new(ErrorCode::ProfileNotFound, e.to_string()))?;
+
+    events.publish(Event::VpnConnecting);
+    let result = match handler.get_profile() {
+        Some(_) => handler.switch_profile(&profile),
+        None => handler.start_profile(&profile),
+    };
+    let result = result.and_then(|_| {
+        record_used(handler, context);
+        handler.wait_connected(context.settings().connect_wait, token)
+    });
+
+    match result {
+        Ok(_) => {
+            reconnect.reset();
+            record_outcome(handler, context, true);
+            let profile = handler.get_profile().cloned();
+            let pid = handler.get_pid();
+            {
+                let mut status = status.lock().unwrap();
+                status.record_connected(profile.clone(), pid);
+                status.set_backend(handler.get_status());
+                status.set_reconnect(None);
+            }
+            if let Err(e) = notifier.lock().unwrap().send_message("STATUS Connected") {
+                logger
+                    .lock()
+                    .unwrap()
+                    .log(&format!("Failed to notify: {:?}", e))
+                    .ok();
+            }
+            events.publish(Event::VpnConnected {
+                profile: profile.clone(),
+                pid,
+            });
+            let msg = format!(
+                "VPN STATUS CHANGE: Connected to {}",
+                profile.as_deref().unwrap_or(query)
+            );
+            logger.lock().unwrap().log(&msg).ok();
+            Ok(json!({ "connected": true, "profile": profile, "pid": pid }))
+        }
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => Err(ErrorBody::new(
+            ErrorCode::NotRunning,
+            "The runner is stopping",
+        )),
+        // Started but never came up, gone like the old connection
+        Err(e) if handler.get_profile().is_some() => {
+            let msg = connect_failed(handler, context, &e);
+            Err(ErrorBody::new(ErrorCode::Internal, msg))
+        }
+        Err(e) => {
+            let msg = format!("Failed to connect to {}: {}", profile, e);
+            {
+                let mut status = status.lock().unwrap();
+                status.record_error(&msg);
+                if handler.get_profile().is_none() {
+                    status.record_disconnected();
+                }
+            }
+            events.publish(Event::Error {
+                message: msg.to_string(),
+            });
+            if handler.get_profile().is_none() {
+                events.publish(Event::VpnDisconnected);
+            }
+            logger.lock().unwrap().log(&msg).ok();
+            Err(ErrorBody::new(ErrorCode::Internal, msg))
+        }
+    }
+}
+
+// Feeds the selection strategy, a history that cannot be saved costs fairness, not the connection
+fn record_used(handler: &handler::Handler, context: &RunnerContext) {
+    if let Some(profile) = handler.get_profile() {
+        remember(context, context.config.record_used(profile));
+    }
+}
+
+/*
+    A tunnel that was started and never came up: taken down again, its profile quarantined, and
+    the reason goes to the status, the log, subscribers and the notifier. Returns the message.
+*/
+fn connect_failed(
+    handler: &mut handler::Handler,
+    context: &RunnerContext,
+    error: &io::Error,
+) -> String {
+    let RunnerContext {
+        logger,
+        notifier,
+        status,
+        events,
+        ..
+    } = context;
+    record_outcome(handler, context, false);
+    let profile = handler.get_profile().cloned();
+    let failure = handler.get_failure();
+    let msg = format!(
+        "Failed to connect to {}: {}",
+        profile.as_deref().unwrap_or("the VPN"),
+        error
+    );
+    logger.lock().unwrap().log(&msg).ok();
+    if let Err(e) = take_down(handler, context) {
+        let msg = format!("Failed to take down the tunnel: {}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+    {
+        let mut status = status.lock().unwrap();
+        status.record_failure(&msg, failure.as_ref());
+        status.record_disconnected();
+    }
+    events.publish(Event::VpnFailed {
+        profile,
+        reason: error.to_string(),
+        kind: failure.as_ref().map(Failure::get_name),
+    });
+    if let Err(e) = notifier
+        .lock()
+        .unwrap()
+        .send_message(&format!("FAIL - {}", msg))
+    {
+        let msg = format!("Failed to notify: {:?}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+    msg
+}
+
+/*
+    Stops the tunnel, which OpenVPN is given `disconnect_wait` for before it is killed, and logs
+    how it ended. Having to kill it also ends up in the status, its routes may be left behind.
+*/
+fn take_down(handler: &mut handler::Handler, context: &RunnerContext) -> Result<(), io::Error> {
+    let profile = handler.get_profile().cloned();
+    let Some(teardown) = handler.stop()? else {
+        return Ok(());
+    };
+    let msg = format!(
+        "Tunnel to {} went down: {}",
+        profile.as_deref().unwrap_or("the VPN"),
+        teardown
+    );
+    context.logger.lock().unwrap().log(&msg).ok();
+    if teardown.forced {
+        context.status.lock().unwrap().record_error(&msg);
+    }
+    Ok(())
+}
+
+// A tunnel that was up and went away on its own, e.g. OpenVPN exiting after a server restart
+fn tunnel_lost(handler: &mut handler::Handler, context: &RunnerContext, reason: &str) {
+    let RunnerContext {
+        logger,
+        notifier,
+        status,
+        events,
+        ..
+    } = context;
+    let msg = format!(
+        "Lost the tunnel to {}: {}",
+        handler
+            .get_profile()
+            .map_or("the VPN", |profile| profile.as_str()),
+        reason
+    );
+    let failure = handler.get_failure();
+    logger.lock().unwrap().log(&msg).ok();
+    if let Err(e) = take_down(handler, context) {
+        let msg = format!("Failed to take down the tunnel: {}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+    {
+        let mut status = status.lock().unwrap();
+        status.record_failure(&msg, failure.as_ref());
+        status.record_disconnected();
+    }
+    events.publish(Event::Error {
+        message: msg.clone(),
+    });
+    events.publish(Event::VpnDisconnected);
+    // Something to fix, like an expired password, says more than the status, and only one fits
+    let message = match failure {
+        Some(_) => format!("FAIL - {}", msg),
+        None => "STATUS Disconnected".to_string(),
+    };
+    if let Err(e) = notifier.lock().unwrap().send_message(&message) {
+        let msg = format!("Failed to notify: {:?}", e);
+        logger.lock().unwrap().log(&msg).ok();
+    }
+}
+
+/*
+    Counts a failure of `profile` (None when no tunnel got as far as having one) and sets up the
+    next attempt, or gives up and says so once the reconnect attempts are used up.
+*/
+fn schedule_reconnect(reconnect: &mut Reconnect, profile: Option<&str>, context: &RunnerContext) {
+    let RunnerContext {
+        logger,
+        notifier,
+        status,
+        events,
+        ..
+    } = context;
+    let settings = context.settings();
+    reconnect.configure(settings.reconnect_policy());
+    match reconnect.record_failure(profile, Instant::now()) {
+        Some(delay) => {
+            let attempt = reconnect.get_attempt();
+            let msg = format!("Reconnecting in {}s (attempt {})", delay.as_secs(), attempt);
+            logger.lock().unwrap().log(&msg).ok();
+            status.lock().unwrap().set_reconnect(Some((attempt, delay)));
+            events.publish(Event::VpnReconnecting {
+                attempt,
+                delay_secs: delay.as_secs(),
+            });
+        }
+        None => {
+            let msg = format!(
+                "Gave up reconnecting after {} attempts, the VPN stays down until it is turned on again or a profile is connected",
+                settings.reconnect_attempts
+            );
+            logger.lock().unwrap().log(&msg).ok();
+            {
+                let mut status = status.lock().unwrap();
+                status.record_error(&msg);
+                status.set_reconnect(None);
+            }
+            events.publish(Event::Error {
+                message: msg.clone(),
+            });
+            if let Err(e) = notifier
+                .lock()
+                .unwrap()
+                .send_message(&format!("FAIL - {}", msg))
+            {
+                let msg = format!("Failed to notify: {:?}", e);
+                logger.lock().unwrap().log(&msg).ok();
+            }
+        }
+    }
+}
+
+// Only a tunnel that reached its connected state counts as good
+fn record_outcome(handler: &handler::Handler, context: &RunnerContext, connected: bool) {
+    if let Some(profile) = handler.get_profile() {
+        let result = match connected {
+            true => context.config.record_good(profile),
+            false => context.config.record_failed(profile).map(|cool_down| {
+                if let Some(cool_down) = cool_down {
+                    let msg = format!(
+                        "Quarantined {} for {}s after it failed to connect",
+                        profile,
+                        cool_down.as_secs()
+                    );
+                    context.logger.lock().unwrap().log(&msg).ok();
+                }
+            }),
+        };
+        remember(context, result);
+    }
+}
+
+fn remember(context: &RunnerContext, result: Result<(), io::Error>) {
+    if let Err(e) = result {
+        let msg = format!("Failed to save profile history: {}", e);
+        context.logger.lock().unwrap().log(&msg).ok();
+    }
+}
@@ -0,0 +1,362 @@
+use crate::tools::severity::Severity;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 16;
+pub(crate) const DEFAULT_MAX_FILES: usize = 20_000;
+// More threads than this only queue up on the same disk
+const MAX_THREADS: usize = 8;
+
+// How far a scan goes, the settings under [profiles]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ScanLimits {
+    // Directory levels below the profile directory that are entered
+    pub(crate) max_depth: usize,
+    pub(crate) max_files: usize,
+    // Lower case and without the dot, every file is taken when empty
+    pub(crate) extensions: Vec<String>,
+}
+
+impl Default for ScanLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_files: DEFAULT_MAX_FILES,
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl ScanLimits {
+    fn accepts(&self, path: &Path) -> bool {
+        self.extensions.is_empty()
+            || path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    self.extensions
+                        .iter()
+                        .any(|wanted| wanted.eq_ignore_ascii_case(extension))
+                })
+    }
+}
+
+// Something the scan could not take in, reported next to the lint findings
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Issue {
+    pub(crate) path: String,
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Scan {
+    // Sorted, so picks and duplicate checks do not depend on which thread finished first
+    pub(crate) files: Vec<String>,
+    pub(crate) issues: Vec<Issue>,
+}
+
+struct Dir {
+    path: PathBuf,
+    depth: usize,
+    // Device and inode of every directory on the way down, a link back to one is a loop
+    ancestors: Vec<(u64, u64)>,
+}
+
+#[derive(Default)]
+struct Listing {
+    files: Vec<String>,
+    dirs: Vec<Dir>,
+    issues: Vec<Issue>,
+}
+
+/*
+    Lists the files below `root`, one directory level at a time with the directories of a level
+    spread over a few threads. Symlinks are followed, except back into a directory they are
+    already inside of. Whatever cannot be read (a directory without permission, a dangling link,
+    a name that is not UTF-8) becomes an issue and the scan carries on; only an unreadable
+    `root` fails it, so a broken disk never empties the profile list. `skip` is left out, it is
+    where the credentials live.
+*/
+pub(crate) fn scan(root: &Path, limits: &ScanLimits, skip: &Path) -> Result<Scan, std::io::Error> {
+    let metadata = std::fs::metadata(root)?;
+    // Fail here rather than as an issue, see above
+    std::fs::read_dir(root)?;
+
+    let mut scan = Scan::default();
+    let mut level = vec![Dir {
+        path: root.to_path_buf(),
+        depth: 0,
+        ancestors: vec![(metadata.dev(), metadata.ino())],
+    }];
+    // Levels are only cut off whole, so what is kept never depends on which thread was faster
+    let mut truncated = false;
+    while !level.is_empty() {
+        if scan.files.len() >= limits.max_files {
+            truncated = true;
+            break;
+        }
+        let listings = parallel_map(&level, |dir| list(dir, limits, skip));
+        level = Vec::new();
+        for listing in listings {
+            scan.files.extend(listing.files);
+            scan.issues.extend(listing.issues);
+            level.extend(listing.dirs);
+        }
+    }
+    scan.files.sort();
+    if scan.files.len() > limits.max_files {
+        scan.files.truncate(limits.max_files);
+        truncated = true;
+    }
+
+    if truncated {
+        scan.issues.push(Issue {
+            path: root.to_string_lossy().to_string(),
+            severity: Severity::Warning,
+            message: format!(
+                "Stopped at {} files, the rest was not scanned (raise `max_files`)",
+                limits.max_files
+            ),
+        });
+    }
+    scan.issues.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(scan)
+}
+
+fn list(dir: &Dir, limits: &ScanLimits, skip: &Path) -> Listing {
+    let mut listing = Listing::default();
+    let mut issue = |path: &Path, severity: Severity, message: String| {
+        listing.issues.push(Issue {
+            path: path.to_string_lossy().to_string(),
+            severity,
+            message,
+        })
+    };
+
+    let entries = match std::fs::read_dir(&dir.path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            issue(&dir.path, Severity::Error, format!("Cannot read: {}", e));
+            return listing;
+        }
+    };
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                issue(&dir.path, Severity::Error, format!("Cannot read: {}", e));
+                continue;
+            }
+        };
+        if path == skip {
+            continue;
+        }
+        // Follows symlinks, a dangling one ends up here
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                issue(&path, Severity::Error, format!("Cannot read: {}", e));
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            let id = (metadata.dev(), metadata.ino());
+            if dir.ancestors.contains(&id) {
+                issue(
+                    &path,
+                    Severity::Warning,
+                    "Links back to a directory it is in, not scanned again".to_string(),
+                );
+            } else if dir.depth >= limits.max_depth {
+                issue(
+                    &path,
+                    Severity::Warning,
+                    format!(
+                        "More than {} directories deep, not scanned (raise `max_depth`)",
+                        limits.max_depth
+                    ),
+                );
+            } else {
+                let mut ancestors = dir.ancestors.clone();
+                ancestors.push(id);
+                dirs.push(Dir {
+                    path,
+                    depth: dir.depth + 1,
+                    ancestors,
+                });
+            }
+        } else if limits.accepts(&path) {
+            match path.to_str() {
+                Some(name) => files.push(name.to_string()),
+                None => issue(
+                    &path,
+                    Severity::Error,
+                    "Name is not valid UTF-8, rename it to use it".to_string(),
+                ),
+            }
+        }
+    }
+
+    listing.files = files;
+    listing.dirs = dirs;
+    listing
+}
+
+/*
+    `f` applied to every item on a few threads, results in the order of the items. Threads take
+    the next item as they finish one, so one huge directory does not hold up a whole share.
+*/
+pub(crate) fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = thread::available_parallelism()
+        .map_or(1, |threads| threads.get())
+        .min(MAX_THREADS)
+        .min(items.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break done;
+                        };
+                        done.push((index, f(item)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+
+    fn names(scan: &Scan, root: &Path) -> Vec<String> {
+        scan.files
+            .iter()
+            .map(|file| file.trim_start_matches(root.to_str().unwrap()).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("nordvpn/udp")).unwrap();
+        std::fs::write(root.join("auth.txt"), "user").unwrap();
+        std::fs::write(root.join("se.ovpn"), "").unwrap();
+        std::fs::write(root.join("nordvpn/udp/us.OVPN"), "").unwrap();
+        std::fs::write(root.join("nordvpn/README"), "").unwrap();
+        // A name OpenVPN could open but the profile list cannot hold
+        std::fs::write(root.join(OsStr::from_bytes(b"bad\xff.ovpn")), "").unwrap();
+
+        let scan = scan(root, &ScanLimits::default(), &root.join("auth.txt")).unwrap();
+        assert_eq!(
+            names(&scan, root),
+            vec!["/nordvpn/README", "/nordvpn/udp/us.OVPN", "/se.ovpn"]
+        );
+        assert_eq!(scan.issues.len(), 1);
+        assert_eq!(scan.issues[0].severity, Severity::Error);
+
+        let limits = ScanLimits {
+            max_depth: 1,
+            max_files: 10,
+            extensions: vec!["ovpn".to_string()],
+        };
+        let scan = super::scan(root, &limits, Path::new("/nonexistent")).unwrap();
+        assert_eq!(names(&scan, root), vec!["/se.ovpn"]);
+        assert!(scan.issues.iter().any(
+            |issue| issue.path.ends_with("nordvpn/udp") && issue.severity == Severity::Warning
+        ));
+
+        assert!(super::scan(&root.join("missing"), &limits, Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn test_symlinks() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::write(root.join("a/b/se.ovpn"), "").unwrap();
+        symlink(root.join("a"), root.join("a/b/loop")).unwrap();
+        symlink(root.join("a/b"), root.join("shared")).unwrap();
+        symlink(root.join("gone.ovpn"), root.join("dangling.ovpn")).unwrap();
+
+        let scan = scan(root, &ScanLimits::default(), Path::new("/")).unwrap();
+        // Linked directories are scanned, just never into themselves
+        assert_eq!(names(&scan, root), vec!["/a/b/se.ovpn", "/shared/se.ovpn"]);
+        let issues: Vec<(&str, Severity)> = scan
+            .issues
+            .iter()
+            .map(|issue| {
+                (
+                    issue.path.trim_start_matches(root.to_str().unwrap()),
+                    issue.severity,
+                )
+            })
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                ("/a/b/loop", Severity::Warning),
+                ("/dangling.ovpn", Severity::Error),
+                ("/shared/loop/b", Severity::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_limit() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for i in 0..50 {
+            let sub = dir.path().join(format!("{:02}", i % 5));
+            std::fs::create_dir_all(&sub).unwrap();
+            std::fs::write(sub.join(format!("{}.ovpn", i)), "").unwrap();
+        }
+        let limits = ScanLimits {
+            max_files: 20,
+            ..ScanLimits::default()
+        };
+        let scan = scan(dir.path(), &limits, Path::new("/")).unwrap();
+        assert_eq!(scan.files.len(), 20);
+        assert_eq!(scan.issues.len(), 1);
+        assert!(scan.issues[0].message.starts_with("Stopped at 20 files"));
+        // The first 20 by path, however the threads got through the directories
+        let mut expected: Vec<String> = (0..50)
+            .map(|i| format!("{}/{:02}/{}.ovpn", dir.path().display(), i % 5, i))
+            .collect();
+        expected.sort();
+        expected.truncate(20);
+        assert_eq!(scan.files, expected);
+
+        let squares = parallel_map(&(0..100).collect::<Vec<u32>>(), |n| n * n);
+        assert_eq!(squares[..4], [0, 1, 4, 9]);
+        assert_eq!(squares.len(), 100);
+    }
+}
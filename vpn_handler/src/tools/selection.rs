@@ -0,0 +1,467 @@
+use crate::tools::state;
+use glob::{MatchOptions, Pattern};
+use rand::Rng;
+use rand::distr::{Distribution, weighted::WeightedIndex};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Profiles remembered as recently used, enough for any sensible "avoid last N"
+const RECENT_LIMIT: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Strategy {
+    Random,
+    RoundRobin,
+    LeastRecentlyUsed,
+    Weighted,
+    // Random, but never one of the last N profiles used while there is another choice
+    AvoidLast(usize),
+    // The profile that last connected, until it fails
+    Sticky,
+}
+
+impl Strategy {
+    pub(crate) fn parse(name: &str, avoid_last: usize) -> Option<Self> {
+        match name.trim() {
+            "random" => Some(Strategy::Random),
+            "round-robin" => Some(Strategy::RoundRobin),
+            "least-recently-used" | "lru" => Some(Strategy::LeastRecentlyUsed),
+            "weighted" => Some(Strategy::Weighted),
+            "avoid-last" => Some(Strategy::AvoidLast(avoid_last)),
+            "sticky" => Some(Strategy::Sticky),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Strategy::Random => write!(f, "random"),
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::LeastRecentlyUsed => write!(f, "least-recently-used"),
+            Strategy::Weighted => write!(f, "weighted"),
+            Strategy::AvoidLast(count) => write!(f, "avoid-last {}", count),
+            Strategy::Sticky => write!(f, "sticky"),
+        }
+    }
+}
+
+// What the strategies go by, saved after every change so a restart does not reset the rotation
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+struct History {
+    // Profile to the sequence number of its last use, higher is more recent
+    last_used: HashMap<String, u64>,
+    sequence: u64,
+    // Most recent first
+    recent: VecDeque<String>,
+    last_good: Option<String>,
+}
+
+/*
+    Decides which profile the switch (or a `connect` matching several) gets. Weights are globs
+    on the file name or the path below the profile directory, the longest matching pattern wins
+    and unmatched profiles weigh 1. A weight of 0 keeps a profile out of weighted picks, which
+    fail rather than fall back to one when nothing else is left.
+*/
+pub(crate) struct Selection {
+    strategy: Strategy,
+    weights: Vec<(Pattern, u32)>,
+    history: History,
+    state_file: Option<PathBuf>,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::Random,
+            weights: Vec::new(),
+            history: History::default(),
+            state_file: None,
+        }
+    }
+}
+
+impl Selection {
+    // A missing state file is a first start, an unreadable one is reported and starts over
+    pub(crate) fn load(state_file: &Path) -> (Self, Option<io::Error>) {
+        let mut selection = Self {
+            state_file: Some(state_file.to_path_buf()),
+            ..Self::default()
+        };
+        let error = match std::fs::read_to_string(state_file) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(history) => {
+                    selection.history = history;
+                    None
+                }
+                Err(e) => Some(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", state_file.display(), e),
+                )),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => Some(io::Error::new(
+                e.kind(),
+                format!("{}: {}", state_file.display(), e),
+            )),
+        };
+        (selection, error)
+    }
+
+    pub(crate) fn get_strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    pub(crate) fn configure(
+        &mut self,
+        strategy: Strategy,
+        weights: &[(String, u32)],
+    ) -> Result<(), io::Error> {
+        let weights = compile_weights(weights)?;
+        self.set_strategy(strategy, weights);
+        Ok(())
+    }
+
+    // Takes weights compiled ahead of time, so nothing can fail halfway through a reload
+    pub(crate) fn set_strategy(&mut self, strategy: Strategy, weights: Vec<(Pattern, u32)>) {
+        self.strategy = strategy;
+        self.weights = weights;
+    }
+
+    pub(crate) fn pick<'a>(
+        &self,
+        candidates: &[&'a String],
+        main_dir: &str,
+    ) -> Result<&'a String, io::Error> {
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No profiles to pick from",
+            ));
+        }
+        let history = &self.history;
+        let pick = match self.strategy {
+            Strategy::Random => None,
+            Strategy::RoundRobin => {
+                // The one after the newest use, in the order the candidates come in
+                let newest = candidates
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, file)| {
+                        history.last_used.get(*file).map(|used| (used, index))
+                    })
+                    .max()
+                    .map(|(_, index)| index);
+                Some(match newest {
+                    Some(index) => candidates[(index + 1) % candidates.len()],
+                    None => candidates[0],
+                })
+            }
+            Strategy::LeastRecentlyUsed => candidates
+                .iter()
+                .min_by_key(|file| history.last_used.get(**file).copied().unwrap_or(0))
+                .copied(),
+            Strategy::Weighted => {
+                // Weight 0 is out for good, chance only decides among the rest
+                let (weighted, weights): (Vec<&'a String>, Vec<u32>) = candidates
+                    .iter()
+                    .map(|file| (*file, self.weight_of(file, main_dir)))
+                    .filter(|(_, weight)| *weight > 0)
+                    .unzip();
+                if weighted.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Every profile to pick from weighs 0",
+                    ));
+                }
+                match WeightedIndex::new(&weights) {
+                    Ok(index) => Some(weighted[index.sample(&mut rand::rng())]),
+                    // Only fails when the weights add up past u32::MAX
+                    Err(_) => random(&weighted),
+                }
+            }
+            Strategy::AvoidLast(count) => {
+                let recent: Vec<&String> = history.recent.iter().take(count).collect();
+                let fresh: Vec<&'a String> = candidates
+                    .iter()
+                    .filter(|file| !recent.contains(file))
+                    .copied()
+                    .collect();
+                random(&fresh)
+            }
+            Strategy::Sticky => history
+                .last_good
+                .as_ref()
+                .and_then(|good| candidates.iter().find(|file| **file == good))
+                .copied(),
+        };
+        // Every strategy falls back to chance when it has nothing to go by
+        Ok(pick
+            .or_else(|| random(candidates))
+            .expect("candidates is not empty"))
+    }
+
+    pub(crate) fn record_used(&mut self, profile: &str) -> Result<(), io::Error> {
+        let history = &mut self.history;
+        history.sequence += 1;
+        history
+            .last_used
+            .insert(profile.to_string(), history.sequence);
+        history.recent.retain(|file| file != profile);
+        history.recent.push_front(profile.to_string());
+        history.recent.truncate(RECENT_LIMIT);
+        self.save()
+    }
+
+    pub(crate) fn record_good(&mut self, profile: &str) -> Result<(), io::Error> {
+        if self.history.last_good.as_deref() == Some(profile) {
+            return Ok(());
+        }
+        self.history.last_good = Some(profile.to_string());
+        self.save()
+    }
+
+    // Sticky moves on from a profile that failed it
+    pub(crate) fn record_failed(&mut self, profile: &str) -> Result<(), io::Error> {
+        if self.history.last_good.as_deref() != Some(profile) {
+            return Ok(());
+        }
+        self.history.last_good = None;
+        self.save()
+    }
+
+    fn weight_of(&self, file: &str, main_dir: &str) -> u32 {
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+        let name = Path::new(file)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let relative = file
+            .strip_prefix(main_dir)
+            .unwrap_or(file)
+            .trim_start_matches('/')
+            .to_lowercase();
+        self.weights
+            .iter()
+            .filter(|(pattern, _)| {
+                pattern.matches_with(&name, options) || pattern.matches_with(&relative, options)
+            })
+            .max_by_key(|(pattern, _)| pattern.as_str().len())
+            .map(|(_, weight)| *weight)
+            .unwrap_or(1)
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        match &self.state_file {
+            Some(path) => state::write_atomically(path, &self.history),
+            None => Ok(()),
+        }
+    }
+}
+
+fn random<'a>(candidates: &[&'a String]) -> Option<&'a String> {
+    match candidates.len() {
+        0 => None,
+        len => Some(candidates[rand::rng().random_range(0..len)]),
+    }
+}
+
+pub(crate) fn compile_weights(weights: &[(String, u32)]) -> Result<Vec<(Pattern, u32)>, io::Error> {
+    weights
+        .iter()
+        .map(|(pattern, weight)| {
+            Pattern::new(&pattern.to_lowercase())
+                .map(|compiled| (compiled, *weight))
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid weight pattern {:?}: {}", pattern, e),
+                    )
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles() -> Vec<String> {
+        ["/vpn/se-01.ovpn", "/vpn/se-02.ovpn", "/vpn/us-01.ovpn"]
+            .iter()
+            .map(|path| path.to_string())
+            .collect()
+    }
+
+    fn with_strategy(strategy: Strategy) -> Selection {
+        let mut selection = Selection::default();
+        assert!(selection.configure(strategy, &[]).is_ok());
+        selection
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let files = profiles();
+        let candidates: Vec<&String> = files.iter().collect();
+        let mut selection = with_strategy(Strategy::RoundRobin);
+
+        let mut picks = Vec::new();
+        for _ in 0..4 {
+            let pick = selection.pick(&candidates, "/vpn").unwrap().clone();
+            selection.record_used(&pick).unwrap();
+            picks.push(pick);
+        }
+        assert_eq!(
+            picks,
+            vec![
+                "/vpn/se-01.ovpn",
+                "/vpn/se-02.ovpn",
+                "/vpn/us-01.ovpn",
+                "/vpn/se-01.ovpn"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let files = profiles();
+        let candidates: Vec<&String> = files.iter().collect();
+        let mut selection = with_strategy(Strategy::LeastRecentlyUsed);
+
+        selection.record_used("/vpn/se-01.ovpn").unwrap();
+        selection.record_used("/vpn/us-01.ovpn").unwrap();
+        assert_eq!(
+            selection.pick(&candidates, "/vpn").unwrap(),
+            "/vpn/se-02.ovpn"
+        );
+        selection.record_used("/vpn/se-02.ovpn").unwrap();
+        assert_eq!(
+            selection.pick(&candidates, "/vpn").unwrap(),
+            "/vpn/se-01.ovpn"
+        );
+    }
+
+    #[test]
+    fn test_avoid_last() {
+        let files = profiles();
+        let candidates: Vec<&String> = files.iter().collect();
+        let mut selection = with_strategy(Strategy::AvoidLast(2));
+
+        selection.record_used("/vpn/se-01.ovpn").unwrap();
+        selection.record_used("/vpn/us-01.ovpn").unwrap();
+        for _ in 0..20 {
+            assert_eq!(
+                selection.pick(&candidates, "/vpn").unwrap(),
+                "/vpn/se-02.ovpn"
+            );
+        }
+
+        // With nothing fresh left it still picks something
+        let recent: Vec<&String> = candidates
+            .iter()
+            .filter(|file| file.as_str() != "/vpn/se-02.ovpn")
+            .copied()
+            .collect();
+        assert!(selection.pick(&recent, "/vpn").is_ok());
+    }
+
+    #[test]
+    fn test_weighted() {
+        let files = profiles();
+        let candidates: Vec<&String> = files.iter().collect();
+        let mut selection = Selection::default();
+        let weights = vec![("se-*".to_string(), 0), ("se-02*".to_string(), 5)];
+        assert!(selection.configure(Strategy::Weighted, &weights).is_ok());
+
+        assert_eq!(selection.weight_of("/vpn/se-01.ovpn", "/vpn"), 0);
+        // The longer pattern is the more specific one
+        assert_eq!(selection.weight_of("/vpn/se-02.ovpn", "/vpn"), 5);
+        assert_eq!(selection.weight_of("/vpn/us-01.ovpn", "/vpn"), 1);
+        for _ in 0..20 {
+            assert_ne!(
+                selection.pick(&candidates, "/vpn").unwrap(),
+                "/vpn/se-01.ovpn"
+            );
+        }
+
+        let invalid = vec![("se-[".to_string(), 1)];
+        assert!(selection.configure(Strategy::Weighted, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_weighted_all_zero() {
+        let files = profiles();
+        let candidates: Vec<&String> = files.iter().collect();
+        let mut selection = Selection::default();
+        let weights = vec![("*".to_string(), 0)];
+        assert!(selection.configure(Strategy::Weighted, &weights).is_ok());
+
+        // Nothing is left to pick rather than a profile that weighs 0
+        let result = selection.pick(&candidates, "/vpn");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_sticky() {
+        let files = profiles();
+        let candidates: Vec<&String> = files.iter().collect();
+        let mut selection = with_strategy(Strategy::Sticky);
+
+        selection.record_good("/vpn/us-01.ovpn").unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                selection.pick(&candidates, "/vpn").unwrap(),
+                "/vpn/us-01.ovpn"
+            );
+        }
+
+        // Failing something else does not matter, failing the sticky one does
+        selection.record_failed("/vpn/se-01.ovpn").unwrap();
+        assert_eq!(
+            selection.history.last_good.as_deref(),
+            Some("/vpn/us-01.ovpn")
+        );
+        selection.record_failed("/vpn/us-01.ovpn").unwrap();
+        assert_eq!(selection.history.last_good, None);
+        assert!(selection.pick(&candidates, "/vpn").is_ok());
+    }
+
+    #[test]
+    fn test_history_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state").join("selection.json");
+
+        let (mut selection, error) = Selection::load(&state_file);
+        assert!(error.is_none());
+        selection.record_used("/vpn/se-01.ovpn").unwrap();
+        selection.record_good("/vpn/se-01.ovpn").unwrap();
+        assert!(state_file.exists());
+
+        let (reloaded, error) = Selection::load(&state_file);
+        assert!(error.is_none());
+        assert_eq!(reloaded.history, selection.history);
+
+        std::fs::write(&state_file, "not json").unwrap();
+        let (reloaded, error) = Selection::load(&state_file);
+        assert!(error.is_some());
+        assert_eq!(reloaded.history, History::default());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Strategy::parse("lru", 3), Some(Strategy::LeastRecentlyUsed));
+        assert_eq!(
+            Strategy::parse("avoid-last", 3),
+            Some(Strategy::AvoidLast(3))
+        );
+        assert_eq!(Strategy::parse("fastest", 3), None);
+        assert_eq!(Strategy::AvoidLast(2).to_string(), "avoid-last 2");
+    }
+}
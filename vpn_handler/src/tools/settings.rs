@@ -0,0 +1,1043 @@
+use crate::tools::backend::BackendKind;
+use crate::tools::credentials::Rule;
+use crate::tools::logger::Logger;
+use crate::tools::quarantine::{DEFAULT_COOL_DOWN, DEFAULT_MAX_COOL_DOWN};
+use crate::tools::reconnect::{
+    DEFAULT_FAILOVER_AFTER, DEFAULT_MAX_RECONNECT_DELAY, DEFAULT_RECONNECT_ATTEMPTS,
+    DEFAULT_RECONNECT_DELAY, ReconnectPolicy,
+};
+use crate::tools::scan::{DEFAULT_MAX_DEPTH, DEFAULT_MAX_FILES, ScanLimits};
+use crate::tools::selection::Strategy;
+use crate::tools::supervisor::RestartPolicy;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fs, io};
+
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "/etc/vpn_handler/config.toml";
+// Restarts in a row, after a failing runner, before giving up on it
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+// Profiles the avoid-last strategy steers clear of unless told otherwise
+const DEFAULT_AVOID_LAST: usize = 3;
+
+/*
+    Daemon settings that are not baked into the binary. Defaults are overridden by the config
+    file, which is overridden by the environment. The file is the one given with `--config`, else
+    $VPN_HANDLER_CONFIG, else /etc/vpn_handler/config.toml when it exists; see
+    config.example.toml for its layout. The environment variables are:
+      VPN_HANDLER_PROFILE_DIR / VPN_HANDLER_AUTH_FILE        profiles and the auth-user-pass file
+      VPN_HANDLER_WATCH_PROFILES                              rescan when profiles change on disk
+      VPN_HANDLER_MAX_DEPTH / VPN_HANDLER_MAX_FILES           how far a profile scan goes
+      VPN_HANDLER_EXTENSIONS                                  comma separated, like ovpn,conf
+      VPN_HANDLER_SERIAL_PORT / VPN_HANDLER_BAUD_RATE         where the Arduino is attached
+      VPN_HANDLER_LOG_PATH                                    the daemon log
+      VPN_CONTROL_SOCKET / VPN_HANDLER_STATUS_SOCKET          control and notifier sockets
+      VPN_HANDLER_ALLOWED_UIDS / VPN_HANDLER_ALLOWED_GIDS    full control (start, stop, ...)
+      VPN_HANDLER_READONLY_UIDS / VPN_HANDLER_READONLY_GIDS  status, logs, profiles, subscribe
+      VPN_HANDLER_SOCKET_MODE                                 octal mode of the control socket
+      VPN_HANDLER_RESTART                                     never or on-failure (the default)
+      VPN_HANDLER_MAX_RESTARTS                                restarts in a row, 0 for no limit
+      VPN_HANDLER_CONNECT_WAIT / VPN_HANDLER_DISCONNECT_WAIT  seconds a tunnel gets to come up / go down
+      VPN_HANDLER_SILENCE_TIMEOUT                             seconds the switch may stay quiet
+      VPN_HANDLER_START_ATTEMPTS                              tries at bringing a tunnel up
+      VPN_HANDLER_BACKEND                                     auto, openvpn, wireguard or mock
+      VPN_HANDLER_RECONNECT_DELAY / ..._MAX_RECONNECT_DELAY   seconds between reconnects
+      VPN_HANDLER_RECONNECT_ATTEMPTS                          reconnects in a row, 0 for no limit
+      VPN_HANDLER_FAILOVER_AFTER                              failures before another profile
+      VPN_HANDLER_STRATEGY / VPN_HANDLER_AVOID_LAST           how the switch picks a profile
+      VPN_HANDLER_WEIGHTS                                     pattern=weight pairs for weighted
+      VPN_HANDLER_SELECTION_STATE                             where the pick history is kept
+      VPN_HANDLER_COOL_DOWN / VPN_HANDLER_MAX_COOL_DOWN       seconds a failed profile sits out
+      VPN_HANDLER_QUARANTINE_STATE                            where quarantined profiles are kept
+    Id lists are comma separated. Root and the user running the daemon always have full control.
+    A relative auth file is looked up in the profile directory.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Settings {
+    pub(crate) profile_dir: String,
+    pub(crate) auth_file: String,
+    pub(crate) watch_profiles: bool,
+    pub(crate) max_depth: usize,
+    pub(crate) max_files: usize,
+    pub(crate) extensions: Vec<String>,
+    pub(crate) serial_port: String,
+    pub(crate) baud_rate: u32,
+    pub(crate) log_path: String,
+    pub(crate) control_socket: String,
+    pub(crate) status_socket: String,
+    pub(crate) allowed_uids: Vec<u32>,
+    pub(crate) allowed_gids: Vec<u32>,
+    pub(crate) readonly_uids: Vec<u32>,
+    pub(crate) readonly_gids: Vec<u32>,
+    pub(crate) socket_mode: u32,
+    pub(crate) restart: RestartPolicy,
+    pub(crate) connect_wait: Duration,
+    pub(crate) disconnect_wait: Duration,
+    pub(crate) silence_timeout: Duration,
+    pub(crate) start_attempts: u32,
+    pub(crate) backend: BackendKind,
+    pub(crate) reconnect_delay: Duration,
+    pub(crate) max_reconnect_delay: Duration,
+    pub(crate) reconnect_attempts: u32,
+    pub(crate) failover_after: u32,
+    pub(crate) strategy: Strategy,
+    pub(crate) weights: Vec<(String, u32)>,
+    pub(crate) selection_state: String,
+    pub(crate) cool_down: Duration,
+    pub(crate) max_cool_down: Duration,
+    pub(crate) quarantine_state: String,
+    pub(crate) credentials: Vec<Rule>,
+    // The file named with --config, which reloads read again
+    pub(crate) config_path: Option<PathBuf>,
+    // The file the settings were actually read from, if any
+    pub(crate) loaded_from: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            profile_dir: "/home/kwunch/VPN".to_string(),
+            auth_file: "/home/kwunch/VPN/auth.txt".to_string(),
+            watch_profiles: true,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_files: DEFAULT_MAX_FILES,
+            extensions: Vec::new(),
+            serial_port: "/dev/ttyACM0".to_string(),
+            baud_rate: 57600,
+            log_path: Logger::LOG_PATH.to_string(),
+            control_socket: "/tmp/vpn-control.sock".to_string(),
+            status_socket: "/tmp/vpn-status.sock".to_string(),
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+            readonly_uids: Vec::new(),
+            // Members of the daemon's group may look but not touch
+            readonly_gids: vec![unsafe { libc::getegid() }],
+            socket_mode: 0o660,
+            restart: RestartPolicy::OnFailure {
+                max_restarts: DEFAULT_MAX_RESTARTS,
+            },
+            connect_wait: Duration::from_secs(30),
+            disconnect_wait: Duration::from_secs(10),
+            silence_timeout: Duration::from_secs(10),
+            start_attempts: 10,
+            backend: BackendKind::Auto,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            max_reconnect_delay: DEFAULT_MAX_RECONNECT_DELAY,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            failover_after: DEFAULT_FAILOVER_AFTER,
+            strategy: Strategy::Random,
+            weights: Vec::new(),
+            selection_state: "/var/lib/vpn_handler/selection.json".to_string(),
+            cool_down: DEFAULT_COOL_DOWN,
+            max_cool_down: DEFAULT_MAX_COOL_DOWN,
+            quarantine_state: "/var/lib/vpn_handler/quarantine.json".to_string(),
+            credentials: Vec::new(),
+            config_path: None,
+            loaded_from: None,
+        }
+    }
+}
+
+// Layout of the config file, every key is optional
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileSettings {
+    profiles: ProfilesSection,
+    serial: SerialSection,
+    log: LogSection,
+    sockets: SocketsSection,
+    access: AccessSection,
+    runner: RunnerSection,
+    selection: SelectionSection,
+    quarantine: QuarantineSection,
+    credentials: Vec<Rule>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ProfilesSection {
+    dir: Option<String>,
+    auth_file: Option<String>,
+    watch: Option<bool>,
+    max_depth: Option<usize>,
+    max_files: Option<usize>,
+    extensions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SerialSection {
+    port: Option<String>,
+    baud_rate: Option<u32>,
+    silence_timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    path: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SocketsSection {
+    control: Option<String>,
+    status: Option<String>,
+    mode: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AccessSection {
+    allowed_uids: Option<Vec<u32>>,
+    allowed_gids: Option<Vec<u32>>,
+    readonly_uids: Option<Vec<u32>>,
+    readonly_gids: Option<Vec<u32>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RunnerSection {
+    restart: Option<String>,
+    max_restarts: Option<u32>,
+    connect_wait_secs: Option<u64>,
+    disconnect_wait_secs: Option<u64>,
+    start_attempts: Option<u32>,
+    backend: Option<String>,
+    reconnect_delay_secs: Option<u64>,
+    max_reconnect_delay_secs: Option<u64>,
+    reconnect_attempts: Option<u32>,
+    failover_after: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SelectionSection {
+    strategy: Option<String>,
+    avoid_last: Option<usize>,
+    weights: Option<BTreeMap<String, u32>>,
+    state_file: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct QuarantineSection {
+    cool_down_secs: Option<u64>,
+    max_cool_down_secs: Option<u64>,
+    state_file: Option<String>,
+}
+
+impl Settings {
+    // `config_path` is the file given on the command line, which has to exist
+    pub(crate) fn load(config_path: Option<&Path>) -> Result<Self, io::Error> {
+        let lookup = |key: &str| std::env::var(key).ok();
+        let (path, required) = match config_path
+            .map(Path::to_path_buf)
+            .or_else(|| lookup("VPN_HANDLER_CONFIG").map(PathBuf::from))
+        {
+            Some(path) => (path, true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => None,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("Failed to read {}: {}", path.display(), e),
+                ));
+            }
+        };
+
+        // Only a file that does not parse gets its name in front, the rest name their setting
+        let mut settings =
+            Self::from_sources(contents.as_deref(), lookup).map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData => {
+                    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+                }
+                _ => e,
+            })?;
+        settings.config_path = config_path.map(Path::to_path_buf);
+        settings.loaded_from = contents.map(|_| path);
+        Ok(settings)
+    }
+
+    // Names of the settings that differ, for reporting what a reload changed
+    pub(crate) fn changed_fields(&self, other: &Settings) -> Vec<&'static str> {
+        let fields = [
+            ("profile_dir", self.profile_dir != other.profile_dir),
+            ("auth_file", self.auth_file != other.auth_file),
+            (
+                "watch_profiles",
+                self.watch_profiles != other.watch_profiles,
+            ),
+            ("max_depth", self.max_depth != other.max_depth),
+            ("max_files", self.max_files != other.max_files),
+            ("extensions", self.extensions != other.extensions),
+            ("serial_port", self.serial_port != other.serial_port),
+            ("baud_rate", self.baud_rate != other.baud_rate),
+            ("log_path", self.log_path != other.log_path),
+            (
+                "control_socket",
+                self.control_socket != other.control_socket,
+            ),
+            ("status_socket", self.status_socket != other.status_socket),
+            ("allowed_uids", self.allowed_uids != other.allowed_uids),
+            ("allowed_gids", self.allowed_gids != other.allowed_gids),
+            ("readonly_uids", self.readonly_uids != other.readonly_uids),
+            ("readonly_gids", self.readonly_gids != other.readonly_gids),
+            ("socket_mode", self.socket_mode != other.socket_mode),
+            ("restart", self.restart != other.restart),
+            ("connect_wait", self.connect_wait != other.connect_wait),
+            (
+                "disconnect_wait",
+                self.disconnect_wait != other.disconnect_wait,
+            ),
+            (
+                "silence_timeout",
+                self.silence_timeout != other.silence_timeout,
+            ),
+            (
+                "start_attempts",
+                self.start_attempts != other.start_attempts,
+            ),
+            ("backend", self.backend != other.backend),
+            (
+                "reconnect_delay",
+                self.reconnect_delay != other.reconnect_delay,
+            ),
+            (
+                "max_reconnect_delay",
+                self.max_reconnect_delay != other.max_reconnect_delay,
+            ),
+            (
+                "reconnect_attempts",
+                self.reconnect_attempts != other.reconnect_attempts,
+            ),
+            (
+                "failover_after",
+                self.failover_after != other.failover_after,
+            ),
+            ("strategy", self.strategy != other.strategy),
+            ("weights", self.weights != other.weights),
+            (
+                "selection_state",
+                self.selection_state != other.selection_state,
+            ),
+            ("cool_down", self.cool_down != other.cool_down),
+            ("max_cool_down", self.max_cool_down != other.max_cool_down),
+            (
+                "quarantine_state",
+                self.quarantine_state != other.quarantine_state,
+            ),
+            ("credentials", self.credentials != other.credentials),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    pub(crate) fn scan_limits(&self) -> ScanLimits {
+        ScanLimits {
+            max_depth: self.max_depth,
+            max_files: self.max_files,
+            extensions: self.extensions.clone(),
+        }
+    }
+
+    pub(crate) fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            delay: self.reconnect_delay,
+            max_delay: self.max_reconnect_delay,
+            attempts: self.reconnect_attempts,
+            failover_after: self.failover_after,
+        }
+    }
+
+    // Settings that only take effect when the daemon starts, everything else a reload applies
+    pub(crate) fn needs_restart(field: &str) -> bool {
+        matches!(
+            field,
+            "profile_dir"
+                | "auth_file"
+                | "watch_profiles"
+                | "log_path"
+                | "control_socket"
+                | "status_socket"
+                | "socket_mode"
+                | "selection_state"
+                | "quarantine_state"
+        )
+    }
+
+    // Carries over what only a restart can change, so the settings always describe the daemon
+    pub(crate) fn keep_startup_values(&mut self, running: &Settings) {
+        self.profile_dir = running.profile_dir.clone();
+        self.auth_file = running.auth_file.clone();
+        self.watch_profiles = running.watch_profiles;
+        self.log_path = running.log_path.clone();
+        self.control_socket = running.control_socket.clone();
+        self.status_socket = running.status_socket.clone();
+        self.socket_mode = running.socket_mode;
+        self.selection_state = running.selection_state.clone();
+        self.quarantine_state = running.quarantine_state.clone();
+    }
+
+    /*
+        Problems with the machine rather than with the file, reported by --check-config. A
+        missing serial port is not one of them, the Arduino may just be unplugged right now.
+    */
+    pub(crate) fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !Path::new(&self.profile_dir).is_dir() {
+            problems.push(format!(
+                "Profile directory {} does not exist",
+                self.profile_dir
+            ));
+        }
+        match fs::metadata(&self.auth_file) {
+            Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => problems.push(format!(
+                "Auth file {} can be read by others, chmod 600 it",
+                self.auth_file
+            )),
+            Ok(metadata) if metadata.is_file() => {}
+            _ => problems.push(format!("Auth file {} does not exist", self.auth_file)),
+        }
+        for (what, path) in [
+            ("log file", &self.log_path),
+            ("control socket", &self.control_socket),
+            ("status socket", &self.status_socket),
+        ] {
+            match Path::new(path).parent() {
+                Some(parent) if parent.as_os_str().is_empty() || parent.is_dir() => {}
+                _ => problems.push(format!(
+                    "Directory for the {} {} does not exist",
+                    what, path
+                )),
+            }
+        }
+        problems
+    }
+
+    fn from_sources(
+        contents: Option<&str>,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, io::Error> {
+        let mut settings = Self::default();
+        let mut auth_file = "auth.txt".to_string();
+        let mut restart = None;
+        let mut max_restarts = DEFAULT_MAX_RESTARTS;
+        let mut strategy = None;
+        let mut backend = None;
+        let mut avoid_last = DEFAULT_AVOID_LAST;
+
+        if let Some(contents) = contents {
+            let file: FileSettings = toml::from_str(contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.message().to_string()))?;
+            let FileSettings {
+                profiles,
+                serial,
+                log,
+                sockets,
+                access,
+                runner,
+                selection,
+                quarantine,
+                credentials,
+            } = file;
+
+            replace(&mut settings.profile_dir, profiles.dir);
+            replace(&mut auth_file, profiles.auth_file);
+            replace(&mut settings.watch_profiles, profiles.watch);
+            replace(&mut settings.max_depth, profiles.max_depth);
+            replace(&mut settings.max_files, profiles.max_files);
+            replace(&mut settings.extensions, profiles.extensions);
+            replace(&mut settings.serial_port, serial.port);
+            replace(&mut settings.baud_rate, serial.baud_rate);
+            replace(
+                &mut settings.silence_timeout,
+                serial.silence_timeout_secs.map(Duration::from_secs),
+            );
+            replace(&mut settings.log_path, log.path);
+            replace(&mut settings.control_socket, sockets.control);
+            replace(&mut settings.status_socket, sockets.status);
+            replace(&mut settings.socket_mode, sockets.mode);
+            replace(&mut settings.allowed_uids, access.allowed_uids);
+            replace(&mut settings.allowed_gids, access.allowed_gids);
+            replace(&mut settings.readonly_uids, access.readonly_uids);
+            replace(&mut settings.readonly_gids, access.readonly_gids);
+            restart = runner.restart;
+            replace(&mut max_restarts, runner.max_restarts);
+            replace(
+                &mut settings.connect_wait,
+                runner.connect_wait_secs.map(Duration::from_secs),
+            );
+            replace(
+                &mut settings.disconnect_wait,
+                runner.disconnect_wait_secs.map(Duration::from_secs),
+            );
+            replace(&mut settings.start_attempts, runner.start_attempts);
+            backend = runner.backend;
+            replace(
+                &mut settings.reconnect_delay,
+                runner.reconnect_delay_secs.map(Duration::from_secs),
+            );
+            replace(
+                &mut settings.max_reconnect_delay,
+                runner.max_reconnect_delay_secs.map(Duration::from_secs),
+            );
+            replace(&mut settings.reconnect_attempts, runner.reconnect_attempts);
+            replace(&mut settings.failover_after, runner.failover_after);
+            strategy = selection.strategy;
+            replace(&mut avoid_last, selection.avoid_last);
+            replace(
+                &mut settings.weights,
+                selection
+                    .weights
+                    .map(|weights| weights.into_iter().collect()),
+            );
+            replace(&mut settings.selection_state, selection.state_file);
+            replace(
+                &mut settings.cool_down,
+                quarantine.cool_down_secs.map(Duration::from_secs),
+            );
+            replace(
+                &mut settings.max_cool_down,
+                quarantine.max_cool_down_secs.map(Duration::from_secs),
+            );
+            replace(&mut settings.quarantine_state, quarantine.state_file);
+            settings.credentials = credentials;
+        }
+
+        replace(&mut settings.profile_dir, lookup("VPN_HANDLER_PROFILE_DIR"));
+        replace(&mut auth_file, lookup("VPN_HANDLER_AUTH_FILE"));
+        if let Some(value) = lookup("VPN_HANDLER_WATCH_PROFILES") {
+            settings.watch_profiles = parse_number("VPN_HANDLER_WATCH_PROFILES", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_MAX_DEPTH") {
+            settings.max_depth = parse_number("VPN_HANDLER_MAX_DEPTH", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_MAX_FILES") {
+            settings.max_files = parse_number("VPN_HANDLER_MAX_FILES", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_EXTENSIONS") {
+            settings.extensions = value.split(',').map(str::to_string).collect();
+        }
+        replace(&mut settings.serial_port, lookup("VPN_HANDLER_SERIAL_PORT"));
+        replace(&mut settings.log_path, lookup("VPN_HANDLER_LOG_PATH"));
+        replace(&mut settings.control_socket, lookup("VPN_CONTROL_SOCKET"));
+        replace(
+            &mut settings.status_socket,
+            lookup("VPN_HANDLER_STATUS_SOCKET"),
+        );
+        if let Some(value) = lookup("VPN_HANDLER_BAUD_RATE") {
+            settings.baud_rate = parse_number("VPN_HANDLER_BAUD_RATE", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_ALLOWED_UIDS") {
+            settings.allowed_uids = parse_ids("VPN_HANDLER_ALLOWED_UIDS", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_ALLOWED_GIDS") {
+            settings.allowed_gids = parse_ids("VPN_HANDLER_ALLOWED_GIDS", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_READONLY_UIDS") {
+            settings.readonly_uids = parse_ids("VPN_HANDLER_READONLY_UIDS", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_READONLY_GIDS") {
+            settings.readonly_gids = parse_ids("VPN_HANDLER_READONLY_GIDS", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_SOCKET_MODE") {
+            settings.socket_mode = u32::from_str_radix(value.trim(), 8)
+                .map_err(|_| invalid("VPN_HANDLER_SOCKET_MODE", &value))?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_MAX_RESTARTS") {
+            max_restarts = parse_number("VPN_HANDLER_MAX_RESTARTS", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_RESTART") {
+            restart = Some(value);
+        }
+        if let Some(value) = lookup("VPN_HANDLER_CONNECT_WAIT") {
+            settings.connect_wait = parse_secs("VPN_HANDLER_CONNECT_WAIT", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_DISCONNECT_WAIT") {
+            settings.disconnect_wait = parse_secs("VPN_HANDLER_DISCONNECT_WAIT", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_SILENCE_TIMEOUT") {
+            settings.silence_timeout = parse_secs("VPN_HANDLER_SILENCE_TIMEOUT", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_START_ATTEMPTS") {
+            settings.start_attempts = parse_number("VPN_HANDLER_START_ATTEMPTS", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_BACKEND") {
+            backend = Some(value);
+        }
+        if let Some(value) = lookup("VPN_HANDLER_RECONNECT_DELAY") {
+            settings.reconnect_delay = parse_secs("VPN_HANDLER_RECONNECT_DELAY", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_MAX_RECONNECT_DELAY") {
+            settings.max_reconnect_delay = parse_secs("VPN_HANDLER_MAX_RECONNECT_DELAY", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_RECONNECT_ATTEMPTS") {
+            settings.reconnect_attempts = parse_number("VPN_HANDLER_RECONNECT_ATTEMPTS", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_FAILOVER_AFTER") {
+            settings.failover_after = parse_number("VPN_HANDLER_FAILOVER_AFTER", &value)?;
+        }
+
+        if let Some(value) = lookup("VPN_HANDLER_STRATEGY") {
+            strategy = Some(value);
+        }
+        if let Some(value) = lookup("VPN_HANDLER_AVOID_LAST") {
+            avoid_last = parse_number("VPN_HANDLER_AVOID_LAST", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_WEIGHTS") {
+            settings.weights = parse_weights("VPN_HANDLER_WEIGHTS", &value)?;
+        }
+        replace(
+            &mut settings.selection_state,
+            lookup("VPN_HANDLER_SELECTION_STATE"),
+        );
+        if let Some(value) = lookup("VPN_HANDLER_COOL_DOWN") {
+            settings.cool_down = parse_secs("VPN_HANDLER_COOL_DOWN", &value)?;
+        }
+        if let Some(value) = lookup("VPN_HANDLER_MAX_COOL_DOWN") {
+            settings.max_cool_down = parse_secs("VPN_HANDLER_MAX_COOL_DOWN", &value)?;
+        }
+        replace(
+            &mut settings.quarantine_state,
+            lookup("VPN_HANDLER_QUARANTINE_STATE"),
+        );
+
+        if let Some(name) = backend {
+            settings.backend =
+                BackendKind::parse(&name).ok_or_else(|| invalid("backend", &name))?;
+        }
+        settings.strategy = match strategy {
+            Some(name) => {
+                Strategy::parse(&name, avoid_last).ok_or_else(|| invalid("strategy", &name))?
+            }
+            None => Strategy::Random,
+        };
+        if let Some((pattern, _)) = settings
+            .weights
+            .iter()
+            .find(|(pattern, _)| glob::Pattern::new(pattern).is_err())
+        {
+            return Err(invalid("weight pattern", pattern));
+        }
+        settings.restart = match restart.as_deref().map(str::trim) {
+            Some("never") => RestartPolicy::Never,
+            Some("on-failure") | None => RestartPolicy::OnFailure { max_restarts },
+            Some(value) => return Err(invalid("restart", value)),
+        };
+        settings.auth_file = if Path::new(&auth_file).is_relative() {
+            format!(
+                "{}/{}",
+                settings.profile_dir.trim_end_matches('/'),
+                auth_file
+            )
+        } else {
+            auth_file
+        };
+        // Credential files are found the same way as the auth file
+        for (index, rule) in settings.credentials.iter_mut().enumerate() {
+            rule.validate()
+                .map_err(|e| invalid(&format!("credentials #{}", index + 1), &e))?;
+            if let Some(file) = rule
+                .file
+                .as_mut()
+                .filter(|file| Path::new(file).is_relative())
+            {
+                *file = format!("{}/{}", settings.profile_dir.trim_end_matches('/'), file);
+            }
+        }
+
+        if settings.socket_mode > 0o777 {
+            return Err(invalid(
+                "socket mode",
+                &format!("{:o}", settings.socket_mode),
+            ));
+        }
+        if settings.baud_rate == 0 {
+            return Err(invalid("baud rate", "0"));
+        }
+        if settings.start_attempts == 0 {
+            return Err(invalid("start attempts", "0"));
+        }
+        if settings.silence_timeout.is_zero() {
+            return Err(invalid("silence timeout", "0"));
+        }
+        // `.ovpn`, ` OVPN` and `ovpn` all mean the same
+        settings.extensions = settings
+            .extensions
+            .iter()
+            .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+            .filter(|extension| !extension.is_empty())
+            .collect();
+        if settings.max_files == 0 {
+            return Err(invalid("max files", "0"));
+        }
+        if settings.max_cool_down < settings.cool_down {
+            return Err(invalid(
+                "max cool-down",
+                &settings.max_cool_down.as_secs().to_string(),
+            ));
+        }
+        // Without a pause a profile that fails right away would be retried in a tight loop
+        if settings.reconnect_delay.is_zero() {
+            return Err(invalid("reconnect delay", "0"));
+        }
+
+        Ok(settings)
+    }
+}
+
+fn replace<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+fn parse_ids(key: &str, value: &str) -> Result<Vec<u32>, io::Error> {
+    value
+        .split(',')
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse::<u32>().map_err(|_| invalid(key, value)))
+        .collect()
+}
+
+// Comma separated `pattern=weight` pairs, like `se-*=3,us-*=0`
+fn parse_weights(key: &str, value: &str) -> Result<Vec<(String, u32)>, io::Error> {
+    value
+        .split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.rsplit_once('=') {
+            Some((pattern, weight)) => Ok((pattern.trim().to_string(), parse_number(key, weight)?)),
+            None => Err(invalid(key, value)),
+        })
+        .collect()
+}
+
+fn parse_number<T: FromStr>(key: &str, value: &str) -> Result<T, io::Error> {
+    value.trim().parse::<T>().map_err(|_| invalid(key, value))
+}
+
+fn parse_secs(key: &str, value: &str) -> Result<Duration, io::Error> {
+    parse_number::<u64>(key, value).map(Duration::from_secs)
+}
+
+fn invalid(key: &str, value: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid value for {}: {:?}", key, value),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn settings_from(vars: &[(&str, &str)]) -> Result<Settings, io::Error> {
+        settings_with_file(None, vars)
+    }
+
+    fn settings_with_file(
+        contents: Option<&str>,
+        vars: &[(&str, &str)],
+    ) -> Result<Settings, io::Error> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Settings::from_sources(contents, |key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let settings = settings_from(&[]);
+        assert!(settings.is_ok());
+        assert_eq!(settings.unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn test_id_lists_and_mode() {
+        let settings = settings_from(&[
+            ("VPN_HANDLER_ALLOWED_UIDS", "1000, 1001"),
+            ("VPN_HANDLER_READONLY_GIDS", ""),
+            ("VPN_HANDLER_SOCKET_MODE", "600"),
+        ])
+        .unwrap();
+        assert_eq!(settings.allowed_uids, vec![1000, 1001]);
+        assert!(settings.readonly_gids.is_empty());
+        assert_eq!(settings.socket_mode, 0o600);
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let settings = settings_from(&[("VPN_HANDLER_MAX_RESTARTS", "0")]).unwrap();
+        assert_eq!(
+            settings.restart,
+            RestartPolicy::OnFailure { max_restarts: 0 }
+        );
+
+        let settings = settings_from(&[("VPN_HANDLER_RESTART", "never")]).unwrap();
+        assert_eq!(settings.restart, RestartPolicy::Never);
+
+        let settings = settings_from(&[("VPN_HANDLER_BACKEND", "mock")]).unwrap();
+        assert_eq!(settings.backend, BackendKind::Mock);
+        assert!(settings_from(&[("VPN_HANDLER_BACKEND", "ipsec")]).is_err());
+
+        assert!(settings_from(&[("VPN_HANDLER_RESTART", "always")]).is_err());
+        assert!(settings_from(&[("VPN_HANDLER_MAX_RESTARTS", "-1")]).is_err());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let contents = r#"
+            [profiles]
+            dir = "/srv/vpn/"
+            watch = false
+            extensions = [".OVPN", "conf"]
+
+            [serial]
+            port = "/dev/ttyUSB0"
+            baud_rate = 9600
+
+            [sockets]
+            mode = 0o600
+
+            [access]
+            allowed_uids = [1000]
+
+            [runner]
+            restart = "never"
+            connect_wait_secs = 20
+            reconnect_attempts = 0
+            failover_after = 3
+        "#;
+        let settings = settings_with_file(Some(contents), &[]).unwrap();
+        assert_eq!(settings.profile_dir, "/srv/vpn/");
+        // A relative auth file lives next to the profiles
+        assert_eq!(settings.auth_file, "/srv/vpn/auth.txt");
+        assert!(!settings.watch_profiles);
+        assert_eq!(settings.extensions, vec!["ovpn", "conf"]);
+        assert_eq!(settings.serial_port, "/dev/ttyUSB0");
+        assert_eq!(settings.baud_rate, 9600);
+        assert_eq!(settings.socket_mode, 0o600);
+        assert_eq!(settings.allowed_uids, vec![1000]);
+        assert_eq!(settings.restart, RestartPolicy::Never);
+        assert_eq!(settings.connect_wait, Duration::from_secs(20));
+        assert_eq!(settings.disconnect_wait, Duration::from_secs(10));
+        assert_eq!(settings.reconnect_policy().attempts, 0);
+        assert_eq!(settings.reconnect_policy().failover_after, 3);
+        assert_eq!(settings.reconnect_delay, DEFAULT_RECONNECT_DELAY);
+        assert_eq!(settings.log_path, Logger::LOG_PATH);
+        assert!(settings_from(&[("VPN_HANDLER_RECONNECT_DELAY", "0")]).is_err());
+    }
+
+    #[test]
+    fn test_selection() {
+        let contents = r#"
+            [selection]
+            strategy = "avoid-last"
+            avoid_last = 5
+            weights = { "se-*" = 3, "us-*" = 0 }
+        "#;
+        let settings = settings_with_file(Some(contents), &[]).unwrap();
+        assert_eq!(settings.strategy, Strategy::AvoidLast(5));
+        assert_eq!(
+            settings.weights,
+            vec![("se-*".to_string(), 3), ("us-*".to_string(), 0)]
+        );
+
+        let settings = settings_from(&[
+            ("VPN_HANDLER_STRATEGY", "weighted"),
+            ("VPN_HANDLER_WEIGHTS", "nl-*=2, de-*=1"),
+        ])
+        .unwrap();
+        assert_eq!(settings.strategy, Strategy::Weighted);
+        assert_eq!(
+            settings.weights,
+            vec![("nl-*".to_string(), 2), ("de-*".to_string(), 1)]
+        );
+
+        assert!(settings_from(&[("VPN_HANDLER_STRATEGY", "fastest")]).is_err());
+        assert!(settings_from(&[("VPN_HANDLER_WEIGHTS", "se-*")]).is_err());
+        assert!(settings_from(&[("VPN_HANDLER_WEIGHTS", "se-[=1")]).is_err());
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let contents = r#"
+            [quarantine]
+            cool_down_secs = 60
+            max_cool_down_secs = 3600
+            state_file = "/tmp/quarantine.json"
+        "#;
+        let settings = settings_with_file(Some(contents), &[]).unwrap();
+        assert_eq!(settings.cool_down, Duration::from_secs(60));
+        assert_eq!(settings.max_cool_down, Duration::from_secs(3600));
+        assert_eq!(settings.quarantine_state, "/tmp/quarantine.json");
+
+        // Zero turns quarantine off, a maximum below the cool-down makes no sense
+        let settings = settings_from(&[("VPN_HANDLER_COOL_DOWN", "0")]).unwrap();
+        assert!(settings.cool_down.is_zero());
+        assert!(settings_from(&[("VPN_HANDLER_MAX_COOL_DOWN", "60")]).is_err());
+    }
+
+    #[test]
+    fn test_credentials() {
+        let contents = r#"
+            [profiles]
+            dir = "/srv/vpn"
+
+            [[credentials]]
+            provider = "nordvpn"
+            file = "nordvpn/auth.txt"
+
+            [[credentials]]
+            profiles = "work/*"
+            username = "me"
+            command = ["pass", "show", "vpn/work"]
+        "#;
+        let settings = settings_with_file(Some(contents), &[]).unwrap();
+        assert_eq!(settings.credentials.len(), 2);
+        assert_eq!(
+            settings.credentials[0].file.as_deref(),
+            Some("/srv/vpn/nordvpn/auth.txt")
+        );
+        assert_eq!(settings.credentials[1].username.as_deref(), Some("me"));
+
+        // Every rule needs exactly one source
+        let contents = r#"
+            [[credentials]]
+            provider = "nordvpn"
+        "#;
+        assert!(settings_with_file(Some(contents), &[]).is_err());
+    }
+
+    #[test]
+    fn test_environment_beats_file() {
+        let contents = r#"
+            [profiles]
+            dir = "/srv/vpn"
+            auth_file = "/etc/vpn/auth.txt"
+
+            [serial]
+            baud_rate = 9600
+        "#;
+        let settings = settings_with_file(
+            Some(contents),
+            &[
+                ("VPN_HANDLER_BAUD_RATE", "115200"),
+                ("VPN_CONTROL_SOCKET", "/run/vpn/control.sock"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(settings.baud_rate, 115200);
+        assert_eq!(settings.auth_file, "/etc/vpn/auth.txt");
+        assert_eq!(settings.control_socket, "/run/vpn/control.sock");
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        // Misspelled keys are caught instead of silently ignored
+        let result = settings_with_file(Some("[serial]\nbaud = 9600\n"), &[]);
+        assert!(result.is_err());
+        let result = settings_with_file(Some("[serial]\nbaud_rate = \"fast\"\n"), &[]);
+        assert!(result.is_err());
+        let result = settings_with_file(Some("[serial]\nbaud_rate = 0\n"), &[]);
+        assert!(result.is_err());
+        let result = settings_with_file(Some("[sockets]\nmode = 0o1777\n"), &[]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile_dir = dir.path().to_str().unwrap().to_string();
+        let mut settings = Settings {
+            profile_dir: profile_dir.clone(),
+            auth_file: format!("{}/auth.txt", profile_dir),
+            log_path: format!("{}/log.txt", profile_dir),
+            control_socket: format!("{}/control.sock", profile_dir),
+            status_socket: format!("{}/status.sock", profile_dir),
+            ..Settings::default()
+        };
+        assert_eq!(settings.check().len(), 1);
+
+        fs::write(&settings.auth_file, "user\npass\n").unwrap();
+        fs::set_permissions(&settings.auth_file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(settings.check().len(), 1);
+        fs::set_permissions(&settings.auth_file, fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(settings.check().is_empty());
+
+        settings.log_path = format!("{}/missing/log.txt", profile_dir);
+        assert_eq!(settings.check().len(), 1);
+    }
+
+    #[test]
+    fn test_changed_fields() {
+        let old = Settings::default();
+        assert!(old.changed_fields(&Settings::default()).is_empty());
+
+        let new = settings_from(&[
+            ("VPN_HANDLER_READONLY_UIDS", "1002"),
+            ("VPN_HANDLER_SOCKET_MODE", "600"),
+        ])
+        .unwrap();
+        assert_eq!(
+            old.changed_fields(&new),
+            vec!["readonly_uids", "socket_mode"]
+        );
+        assert!(Settings::needs_restart("socket_mode"));
+        assert!(!Settings::needs_restart("readonly_uids"));
+
+        let mut reloaded = new.clone();
+        reloaded.keep_startup_values(&old);
+        assert_eq!(old.changed_fields(&reloaded), vec!["readonly_uids"]);
+
+        // A new strategy applies on reload, a new state file only after a restart
+        let new = settings_from(&[
+            ("VPN_HANDLER_STRATEGY", "round-robin"),
+            ("VPN_HANDLER_WEIGHTS", "se-*=2"),
+            ("VPN_HANDLER_SELECTION_STATE", "/tmp/selection.json"),
+        ])
+        .unwrap();
+        assert_eq!(
+            old.changed_fields(&new),
+            vec!["strategy", "weights", "selection_state"]
+        );
+        assert!(!Settings::needs_restart("strategy"));
+        assert!(Settings::needs_restart("selection_state"));
+
+        let new = settings_from(&[
+            ("VPN_HANDLER_COOL_DOWN", "120"),
+            ("VPN_HANDLER_QUARANTINE_STATE", "/tmp/quarantine.json"),
+        ])
+        .unwrap();
+        assert_eq!(
+            old.changed_fields(&new),
+            vec!["cool_down", "quarantine_state"]
+        );
+        assert!(Settings::needs_restart("quarantine_state"));
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let result = settings_from(&[("VPN_HANDLER_ALLOWED_GIDS", "wheel")]);
+        assert!(result.is_err());
+        let result = settings_from(&[("VPN_HANDLER_SOCKET_MODE", "1777")]);
+        assert!(result.is_err());
+        let result = settings_from(&[("VPN_HANDLER_MAX_FILES", "0")]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io;
+use std::os::raw::c_int;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+pub(crate) const SHUTDOWN_SIGNALS: &[c_int] = &[SIGTERM, SIGINT];
+
+/*
+    Set once the daemon has been asked to exit. Long running loops wait on it instead of
+    sleeping so they can be joined on the way out.
+*/
+pub(crate) struct Shutdown {
+    signal: Mutex<Option<c_int>>,
+    requested: Condvar,
+}
+
+impl Shutdown {
+    pub(crate) fn new() -> Self {
+        Self {
+            signal: Mutex::new(None),
+            requested: Condvar::new(),
+        }
+    }
+
+    // Returns false when shutdown was already underway
+    pub(crate) fn request(&self, signal: c_int) -> bool {
+        let mut current = self.lock_signal();
+        if current.is_some() {
+            return false;
+        }
+        *current = Some(signal);
+        self.requested.notify_all();
+        true
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.lock_signal().is_some()
+    }
+
+    pub(crate) fn get_signal_name(&self) -> &'static str {
+        match *self.lock_signal() {
+            Some(SIGTERM) => "SIGTERM",
+            Some(SIGINT) => "SIGINT",
+            Some(_) => "signal",
+            None => "nothing",
+        }
+    }
+
+    // Sleeps for up to `timeout`, returns true as soon as shutdown is requested
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let signal = self.lock_signal();
+        let (signal, _) = self
+            .requested
+            .wait_timeout_while(signal, timeout, |signal| signal.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        signal.is_some()
+    }
+
+    fn lock_signal(&self) -> MutexGuard<'_, Option<c_int>> {
+        self.signal.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/*
+    Turns the first of `signals` into a shutdown request and calls `on_request` so the caller
+    can wake whatever is blocked. A second signal means the clean shutdown is stuck and the
+    process exits right away.
+*/
+pub(crate) fn watch_signals(
+    shutdown: Arc<Shutdown>,
+    signals: &[c_int],
+    on_request: impl Fn() + Send + 'static,
+) -> Result<(), io::Error> {
+    let mut signals = Signals::new(signals)?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if shutdown.request(signal) {
+                on_request();
+            } else {
+                eprintln!("Received second signal, exiting without cleaning up");
+                std::process::exit(1);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signal_hook::consts::SIGUSR2;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_request_once() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_requested());
+        assert_eq!(shutdown.get_signal_name(), "nothing");
+
+        assert!(shutdown.request(SIGTERM));
+        assert!(!shutdown.request(SIGINT));
+        assert!(shutdown.is_requested());
+        // The first signal is the one that counts
+        assert_eq!(shutdown.get_signal_name(), "SIGTERM");
+    }
+
+    #[test]
+    fn test_wait_wakes_on_request() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(!shutdown.wait(Duration::from_millis(10)));
+
+        let waiter = Arc::clone(&shutdown);
+        let handle = thread::spawn(move || waiter.wait(Duration::from_secs(30)));
+        shutdown.request(SIGINT);
+        assert!(handle.join().unwrap(), "Waiter slept through the request");
+    }
+
+    #[test]
+    fn test_signal_requests_shutdown() {
+        let shutdown = Arc::new(Shutdown::new());
+        let (sender, receiver) = channel();
+        let result = watch_signals(Arc::clone(&shutdown), &[SIGUSR2], move || {
+            sender.send(()).ok();
+        });
+        assert!(result.is_ok());
+
+        unsafe { libc::raise(SIGUSR2) };
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(shutdown.is_requested());
+        assert_eq!(shutdown.get_signal_name(), "signal");
+    }
+}
@@ -0,0 +1,16 @@
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+/*
+    Saves what the daemon keeps across restarts, like the selection history and the quarantine.
+    Written to a temporary file and renamed, so a crash never leaves half a file behind.
+*/
+pub(crate) fn write_atomically(path: &Path, value: &impl Serialize) -> Result<(), io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_vec(value)?)?;
+    std::fs::rename(&temporary, path)
+}
@@ -0,0 +1,200 @@
+use crate::tools::backend::BackendStatus;
+use crate::tools::output::Failure;
+use chrono::{Local, NaiveDateTime};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/*
+    Snapshot of what the runner is doing, filled in by the runner as things happen and read by
+    the `status` command so problems can be diagnosed without digging through log.txt.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct Status {
+    serial_port: Option<String>,
+    last_message: Option<String>,
+    last_message_at: Option<NaiveDateTime>,
+    profile: Option<String>,
+    pid: Option<u32>,
+    backend: Option<BackendStatus>,
+    connected_since: Option<NaiveDateTime>,
+    last_error: Option<String>,
+    last_error_at: Option<NaiveDateTime>,
+    // What went wrong when it is known, like `auth_failed`
+    last_error_kind: Option<&'static str>,
+    // The next reconnect attempt and when it is due
+    reconnect: Option<(u32, NaiveDateTime)>,
+}
+
+impl Status {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn set_serial_port(&mut self, port: Option<&str>) {
+        self.serial_port = port.map(|port| port.to_string());
+    }
+
+    pub(crate) fn record_message(&mut self, message: &str) {
+        self.last_message = Some(message.to_string());
+        self.last_message_at = Some(Local::now().naive_local());
+    }
+
+    pub(crate) fn record_connected(&mut self, profile: Option<String>, pid: Option<u32>) {
+        self.profile = profile;
+        self.pid = pid;
+        self.connected_since = Some(Local::now().naive_local());
+    }
+
+    // What carries the tunnel, set once it is up
+    pub(crate) fn set_backend(&mut self, backend: Option<BackendStatus>) {
+        self.backend = backend;
+    }
+
+    pub(crate) fn record_disconnected(&mut self) {
+        self.profile = None;
+        self.pid = None;
+        self.backend = None;
+        self.connected_since = None;
+    }
+
+    pub(crate) fn set_reconnect(&mut self, reconnect: Option<(u32, Duration)>) {
+        self.reconnect = reconnect.and_then(|(attempt, delay)| {
+            let at = Local::now()
+                .naive_local()
+                .checked_add_signed(chrono::Duration::from_std(delay).ok()?)?;
+            Some((attempt, at))
+        });
+    }
+
+    pub(crate) fn record_error(&mut self, error: &str) {
+        self.record_failure(error, None);
+    }
+
+    // An error along with the failure behind it, see `output::Failure`
+    pub(crate) fn record_failure(&mut self, error: &str, failure: Option<&Failure>) {
+        self.last_error = Some(error.to_string());
+        self.last_error_at = Some(Local::now().naive_local());
+        self.last_error_kind = failure.map(Failure::get_name);
+    }
+
+    pub(crate) fn get_profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub(crate) fn get_switch_position(&self) -> Option<&'static str> {
+        match self.last_message.as_deref() {
+            Some("Turn On") => Some("on"),
+            Some("Turn Off") => Some("off"),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_uptime_secs(&self) -> Option<i64> {
+        self.connected_since.map(|since| {
+            Local::now()
+                .naive_local()
+                .signed_duration_since(since)
+                .num_seconds()
+                .max(0)
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "profile": self.profile,
+            "pid": self.pid,
+            "backend": self.backend.as_ref().map(|backend| backend.backend),
+            "interface": self.backend.as_ref().and_then(|backend| backend.interface.as_deref()),
+            "tunnel_state": self.backend.as_ref().and_then(|backend| backend.state.as_deref()),
+            "connected_since": format_time(self.connected_since),
+            "uptime_secs": self.get_uptime_secs(),
+            "switch": {
+                "position": self.get_switch_position(),
+                "last_message": self.last_message,
+                "at": format_time(self.last_message_at),
+            },
+            "serial_port": self.serial_port,
+            "reconnect": match &self.reconnect {
+                Some((attempt, at)) => json!({ "attempt": attempt, "at": format_time(Some(*at)) }),
+                None => Value::Null,
+            },
+            "last_error": match &self.last_error {
+                Some(message) => json!({
+                    "message": message,
+                    "at": format_time(self.last_error_at),
+                    "kind": self.last_error_kind,
+                }),
+                None => Value::Null,
+            },
+        })
+    }
+}
+
+fn format_time(time: Option<NaiveDateTime>) -> Option<String> {
+    time.map(|time| time.format(TIME_FORMAT).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_status() {
+        let status = Status::new();
+        let report = status.to_json();
+        assert_eq!(report["profile"], Value::Null);
+        assert_eq!(report["pid"], Value::Null);
+        assert_eq!(report["uptime_secs"], Value::Null);
+        assert_eq!(report["switch"]["position"], Value::Null);
+        assert_eq!(report["last_error"], Value::Null);
+    }
+
+    #[test]
+    fn test_connection_lifecycle() {
+        let mut status = Status::new();
+        status.set_serial_port(Some("/dev/ttyACM0"));
+        status.record_message("Turn On");
+        status.record_connected(Some("/vpn/se.ovpn".to_string()), Some(4242));
+
+        let report = status.to_json();
+        assert_eq!(report["profile"], json!("/vpn/se.ovpn"));
+        assert_eq!(report["pid"], json!(4242));
+        assert_eq!(report["uptime_secs"], json!(0));
+        assert_eq!(report["switch"]["position"], json!("on"));
+        assert_eq!(report["serial_port"], json!("/dev/ttyACM0"));
+
+        // Disconnecting clears the connection but keeps what the switch last said
+        status.record_message("Turn Off");
+        status.record_disconnected();
+        let report = status.to_json();
+        assert_eq!(report["profile"], Value::Null);
+        assert_eq!(report["connected_since"], Value::Null);
+        assert_eq!(report["switch"]["position"], json!("off"));
+    }
+
+    #[test]
+    fn test_record_error() {
+        let mut status = Status::new();
+        status.record_error("Serial port vanished");
+        let report = status.to_json();
+        assert_eq!(
+            report["last_error"]["message"],
+            json!("Serial port vanished")
+        );
+        assert!(report["last_error"]["at"].is_string());
+        assert_eq!(report["last_error"]["kind"], Value::Null);
+
+        status.record_failure(
+            "Failed to connect to /vpn/se.ovpn: The server rejected the credentials (AUTH_FAILED)",
+            Some(&Failure::Auth),
+        );
+        assert_eq!(status.to_json()["last_error"]["kind"], json!("auth_failed"));
+
+        status.set_reconnect(Some((2, Duration::from_secs(4))));
+        assert_eq!(status.to_json()["reconnect"]["attempt"], json!(2));
+        status.set_reconnect(None);
+        assert_eq!(status.to_json()["reconnect"], Value::Null);
+    }
+}
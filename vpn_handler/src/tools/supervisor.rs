@@ -0,0 +1,514 @@
+use crate::tools::events::{Event, EventBus};
+use crate::tools::logger::Logger;
+use crate::tools::protocol::{ErrorBody, ErrorCode};
+use crate::tools::runner::RunnerCommand;
+use chrono::{Local, NaiveDateTime};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// Waits between restarts double from the first to the last
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// A run that lasted this long counts as healthy and resets the restart count
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RunnerState {
+    Stopped,
+    Running,
+    Restarting,
+    Stopping,
+    Failed,
+}
+
+impl RunnerState {
+    // Running or about to run again, as opposed to stopped one way or another
+    pub(crate) fn is_active(&self) -> bool {
+        matches!(self, RunnerState::Running | RunnerState::Restarting)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RestartPolicy {
+    Never,
+    // Zero restarts means no limit
+    OnFailure { max_restarts: u32 },
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::OnFailure { max_restarts: 0 } => write!(f, "on-failure, no limit"),
+            RestartPolicy::OnFailure { max_restarts } => {
+                write!(f, "on-failure, at most {} in a row", max_restarts)
+            }
+        }
+    }
+}
+
+impl RestartPolicy {
+    fn allows(&self, restarts: u32) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure { max_restarts } => {
+                *max_restarts == 0 || restarts < *max_restarts
+            }
+        }
+    }
+}
+
+/*
+    Handed to one start of the runner and cancelled by the matching stop. A stop can therefore
+    never reach a runner started after it, and anything waiting on the token wakes right away.
+*/
+#[derive(Clone)]
+pub(crate) struct CancelToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new((Mutex::new(false), Condvar::new())),
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        let (cancelled, changed) = &*self.inner;
+        *cancelled.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        changed.notify_all();
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Sleeps like `thread::sleep` but returns early, with true, once cancelled
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let (cancelled, changed) = &*self.inner;
+        let guard = cancelled.lock().unwrap_or_else(|e| e.into_inner());
+        let (guard, _) = changed
+            .wait_timeout_while(guard, duration, |cancelled| !*cancelled)
+            .unwrap_or_else(|e| e.into_inner());
+        *guard
+    }
+}
+
+pub(crate) type RunFn =
+    dyn Fn(&CancelToken, &Receiver<RunnerCommand>) -> Result<(), String> + Send + Sync;
+
+struct Slot {
+    state: RunnerState,
+    token: Option<CancelToken>,
+    handle: Option<JoinHandle<()>>,
+    commands: Option<Sender<RunnerCommand>>,
+    restarts: u32,
+    started_at: Option<NaiveDateTime>,
+    last_exit: Option<String>,
+}
+
+/*
+    Owns the runner's lifecycle:
+      stopped -> running -> stopping -> stopped
+                 running -> restarting -> running     (runner failed, policy allows another go)
+                 running -> failed                    (runner failed, policy says give up)
+    One supervisor thread per start runs the runner again after failures until it is stopped
+    or the restart policy runs out. A panicking runner counts as a failure.
+*/
+pub(crate) struct Supervisor {
+    run: Arc<RunFn>,
+    policy: Mutex<RestartPolicy>,
+    backoff: (Duration, Duration),
+    logger: Arc<Mutex<Logger>>,
+    events: Arc<EventBus>,
+    slot: Mutex<Slot>,
+    changed: Condvar,
+}
+
+impl Supervisor {
+    pub(crate) fn new(
+        run: Arc<RunFn>,
+        policy: RestartPolicy,
+        logger: Arc<Mutex<Logger>>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        Self {
+            run,
+            policy: Mutex::new(policy),
+            backoff: (INITIAL_BACKOFF, MAX_BACKOFF),
+            logger,
+            events,
+            slot: Mutex::new(Slot {
+                state: RunnerState::Stopped,
+                token: None,
+                handle: None,
+                commands: None,
+                restarts: 0,
+                started_at: None,
+                last_exit: None,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = (initial, max);
+        self
+    }
+
+    pub(crate) fn set_policy(&self, policy: RestartPolicy) {
+        *self.policy.lock().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    pub(crate) fn state(&self) -> RunnerState {
+        self.lock_slot().state
+    }
+
+    pub(crate) fn commands(&self) -> Option<Sender<RunnerCommand>> {
+        let slot = self.lock_slot();
+        match slot.state {
+            RunnerState::Running => slot.commands.clone(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let slot = self.lock_slot();
+        json!({
+            "state": slot.state,
+            "restarts": slot.restarts,
+            "started_at": slot.started_at.map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string()),
+            "last_exit": slot.last_exit,
+        })
+    }
+
+    pub(crate) fn start(self: &Arc<Self>) -> Result<(), ErrorBody> {
+        let mut slot = self.lock_slot();
+        match slot.state {
+            RunnerState::Running | RunnerState::Restarting => Err(ErrorBody::new(
+                ErrorCode::AlreadyRunning,
+                "Daemon is already running",
+            )),
+            RunnerState::Stopping => Err(ErrorBody::new(
+                ErrorCode::Busy,
+                "Daemon is still stopping, try again shortly",
+            )),
+            RunnerState::Stopped | RunnerState::Failed => {
+                // The thread of a failed run has ended or is about to
+                if let Some(handle) = slot.handle.take() {
+                    handle.join().ok();
+                }
+
+                let token = CancelToken::new();
+                let (commands, receiver) = channel();
+                slot.token = Some(token.clone());
+                slot.commands = Some(commands);
+                slot.restarts = 0;
+                slot.started_at = Some(Local::now().naive_local());
+
+                let supervisor = Arc::clone(self);
+                slot.handle = Some(thread::spawn(move || supervisor.supervise(token, receiver)));
+                self.set_state(&mut slot, RunnerState::Running);
+                drop(slot);
+
+                self.log("Daemon started");
+                Ok(())
+            }
+        }
+    }
+
+    // Cancels the current run, callers that want to block until it is over use `wait_for_stop`
+    pub(crate) fn stop(&self) -> Result<RunnerState, ErrorBody> {
+        let mut slot = self.lock_slot();
+        match slot.state {
+            RunnerState::Stopped | RunnerState::Failed => Err(ErrorBody::new(
+                ErrorCode::NotRunning,
+                "Daemon is not running",
+            )),
+            RunnerState::Stopping => Ok(RunnerState::Stopping),
+            RunnerState::Running | RunnerState::Restarting => {
+                if let Some(token) = &slot.token {
+                    token.cancel();
+                }
+                slot.commands = None;
+                self.set_state(&mut slot, RunnerState::Stopping);
+                Ok(RunnerState::Stopping)
+            }
+        }
+    }
+
+    pub(crate) fn wait_for_stop(&self, timeout: Duration) -> RunnerState {
+        let slot = self.lock_slot();
+        let (slot, _) = self
+            .changed
+            .wait_timeout_while(slot, timeout, |slot| slot.state == RunnerState::Stopping)
+            .unwrap_or_else(|e| e.into_inner());
+        slot.state
+    }
+
+    fn supervise(&self, token: CancelToken, commands: Receiver<RunnerCommand>) {
+        let mut backoff = self.backoff.0;
+        loop {
+            let started = Instant::now();
+            let result = catch_unwind(AssertUnwindSafe(|| (self.run)(&token, &commands)))
+                .unwrap_or_else(|panic| Err(panic_message(&panic)));
+
+            if token.is_cancelled() {
+                break;
+            }
+
+            // The runner only returns cleanly when cancelled, anything else is a failure
+            let error = match result {
+                Ok(_) => "Runner returned without being stopped".to_string(),
+                Err(error) => error,
+            };
+
+            let mut slot = self.lock_slot();
+            if started.elapsed() >= STABLE_RUN {
+                slot.restarts = 0;
+                backoff = self.backoff.0;
+            }
+            slot.last_exit = Some(error.clone());
+
+            let policy = *self.policy.lock().unwrap_or_else(|e| e.into_inner());
+            if !policy.allows(slot.restarts) {
+                slot.token = None;
+                slot.commands = None;
+                self.set_state(&mut slot, RunnerState::Failed);
+                drop(slot);
+                self.log(&format!("Runner failed, not restarting: {}", error));
+                return;
+            }
+
+            slot.restarts += 1;
+            let attempt = slot.restarts;
+            self.set_state(&mut slot, RunnerState::Restarting);
+            drop(slot);
+            self.log(&format!(
+                "Runner failed, restarting in {}s (restart {}): {}",
+                backoff.as_secs_f32(),
+                attempt,
+                error
+            ));
+
+            if token.sleep(backoff) {
+                break;
+            }
+            backoff = (backoff * 2).min(self.backoff.1);
+
+            let mut slot = self.lock_slot();
+            if slot.state != RunnerState::Restarting {
+                break;
+            }
+            self.set_state(&mut slot, RunnerState::Running);
+        }
+
+        let mut slot = self.lock_slot();
+        slot.token = None;
+        slot.commands = None;
+        self.set_state(&mut slot, RunnerState::Stopped);
+        drop(slot);
+        self.log("Stopped listening");
+    }
+
+    fn set_state(&self, slot: &mut Slot, state: RunnerState) {
+        slot.state = state;
+        self.changed.notify_all();
+        self.events.publish(Event::RunnerState { state });
+    }
+
+    fn log(&self, msg: &str) {
+        if let Ok(logger) = self.logger.lock() {
+            logger.log(&msg.to_string()).ok();
+        }
+    }
+
+    fn lock_slot(&self) -> MutexGuard<'_, Slot> {
+        // A panicking client thread must not take the whole daemon with it
+        self.slot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    match panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+    {
+        Some(message) => format!("Runner panicked: {}", message),
+        None => "Runner panicked".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tempfile::TempDir;
+
+    // Runners log from their own threads, so the logger gets its path rather than a thread's
+    fn logger(log_dir: &TempDir) -> Arc<Mutex<Logger>> {
+        Arc::new(Mutex::new(Logger::with_path(
+            &log_dir.path().join("log.txt"),
+        )))
+    }
+
+    fn supervisor(run: Arc<RunFn>, policy: RestartPolicy) -> (TempDir, Arc<Supervisor>) {
+        let log_dir = TempDir::new().unwrap();
+        let supervisor = Arc::new(
+            Supervisor::new(run, policy, logger(&log_dir), Arc::new(EventBus::new()))
+                .with_backoff(Duration::from_millis(10), Duration::from_millis(40)),
+        );
+        (log_dir, supervisor)
+    }
+
+    fn wait_for(supervisor: &Supervisor, state: RunnerState) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if supervisor.state() == state {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    // Stands in for the runner: waits on the token like the real one does between reads
+    fn until_cancelled() -> Arc<RunFn> {
+        Arc::new(|token: &CancelToken, _: &Receiver<RunnerCommand>| {
+            while !token.sleep(Duration::from_secs(60)) {}
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_token_wakes_sleeper() {
+        let token = CancelToken::new();
+        assert!(!token.sleep(Duration::from_millis(1)));
+
+        let sleeper = token.clone();
+        let handle = thread::spawn(move || sleeper.sleep(Duration::from_secs(60)));
+        token.cancel();
+        assert!(handle.join().unwrap());
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_stop_is_immediate() {
+        let (_log_dir, supervisor) = supervisor(until_cancelled(), RestartPolicy::Never);
+        assert!(supervisor.start().is_ok());
+        assert_eq!(supervisor.state(), RunnerState::Running);
+        assert_eq!(
+            supervisor.start().unwrap_err().code,
+            ErrorCode::AlreadyRunning
+        );
+        assert!(supervisor.commands().is_some());
+
+        let started = Instant::now();
+        assert_eq!(supervisor.stop().unwrap(), RunnerState::Stopping);
+        assert_eq!(
+            supervisor.wait_for_stop(Duration::from_secs(5)),
+            RunnerState::Stopped
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(supervisor.commands().is_none());
+    }
+
+    #[test]
+    fn test_stop_does_not_reach_the_next_run() {
+        let (_log_dir, supervisor) = supervisor(until_cancelled(), RestartPolicy::Never);
+        supervisor.start().unwrap();
+        let first = supervisor.lock_slot().token.clone().unwrap();
+        supervisor.stop().unwrap();
+        supervisor.wait_for_stop(Duration::from_secs(5));
+
+        supervisor.start().unwrap();
+        let second = supervisor.lock_slot().token.clone().unwrap();
+        assert!(first.is_cancelled());
+        assert!(!second.is_cancelled());
+
+        supervisor.stop().unwrap();
+        supervisor.wait_for_stop(Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_restarts_then_gives_up() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&attempts);
+        let run: Arc<RunFn> = Arc::new(move |_: &CancelToken, _: &Receiver<RunnerCommand>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err("Serial port vanished".to_string())
+        });
+        let (_log_dir, supervisor) = supervisor(run, RestartPolicy::OnFailure { max_restarts: 2 });
+
+        supervisor.start().unwrap();
+        assert!(wait_for(&supervisor, RunnerState::Failed));
+        // The first run plus two restarts
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let report = supervisor.to_json();
+        assert_eq!(report["restarts"], json!(2));
+        assert_eq!(report["last_exit"], json!("Serial port vanished"));
+
+        // A failed runner can be started again by hand
+        assert_eq!(supervisor.stop().unwrap_err().code, ErrorCode::NotRunning);
+        assert!(supervisor.start().is_ok());
+        assert!(wait_for(&supervisor, RunnerState::Failed));
+    }
+
+    #[test]
+    fn test_panic_counts_as_failure() {
+        let run: Arc<RunFn> = Arc::new(|_: &CancelToken, _: &Receiver<RunnerCommand>| {
+            panic!("unwrap on a poisoned lock")
+        });
+        let (_log_dir, supervisor) = supervisor(run, RestartPolicy::Never);
+
+        supervisor.start().unwrap();
+        assert!(wait_for(&supervisor, RunnerState::Failed));
+        assert_eq!(
+            supervisor.to_json()["last_exit"],
+            json!("Runner panicked: unwrap on a poisoned lock")
+        );
+    }
+
+    #[test]
+    fn test_stop_during_backoff() {
+        let run: Arc<RunFn> =
+            Arc::new(|_: &CancelToken, _: &Receiver<RunnerCommand>| Err("boom".to_string()));
+        let log_dir = TempDir::new().unwrap();
+        let supervisor = Arc::new(
+            Supervisor::new(
+                run,
+                RestartPolicy::OnFailure { max_restarts: 0 },
+                logger(&log_dir),
+                Arc::new(EventBus::new()),
+            )
+            .with_backoff(Duration::from_secs(60), Duration::from_secs(60)),
+        );
+
+        supervisor.start().unwrap();
+        assert!(wait_for(&supervisor, RunnerState::Restarting));
+        supervisor.stop().unwrap();
+        assert_eq!(
+            supervisor.wait_for_stop(Duration::from_secs(5)),
+            RunnerState::Stopped
+        );
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        assert!(!RestartPolicy::Never.allows(0));
+        assert!(RestartPolicy::OnFailure { max_restarts: 2 }.allows(1));
+        assert!(!RestartPolicy::OnFailure { max_restarts: 2 }.allows(2));
+        assert!(RestartPolicy::OnFailure { max_restarts: 0 }.allows(1000));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::tools::events::{Event, EventRecord};
+use crate::tools::supervisor::RunnerState;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+// First descriptor systemd hands over, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/*
+    Minimal sd_notify(3) client. Every call is a no-op when the daemon was not started by
+    systemd (no NOTIFY_SOCKET), so callers never need to care how they were launched.
+*/
+#[derive(Debug, Clone)]
+pub(crate) struct SystemdNotifier {
+    target: Option<SocketAddr>,
+    watchdog: Option<Duration>,
+}
+
+impl SystemdNotifier {
+    pub(crate) fn from_env() -> Self {
+        let target = std::env::var("NOTIFY_SOCKET")
+            .ok()
+            .and_then(|path| Self::parse_address(&path));
+        let watchdog = watchdog_timeout(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+        Self { target, watchdog }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_socket(path: &std::path::Path) -> Self {
+        Self {
+            target: SocketAddr::from_pathname(path).ok(),
+            watchdog: None,
+        }
+    }
+
+    fn parse_address(path: &str) -> Option<SocketAddr> {
+        // A leading '@' means a socket in the abstract namespace
+        match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()).ok(),
+            None if path.starts_with('/') => SocketAddr::from_pathname(path).ok(),
+            None => None,
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.target.is_some()
+    }
+
+    pub(crate) fn notify(&self, state: &str) -> Result<(), io::Error> {
+        match &self.target {
+            Some(target) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.send_to_addr(state.as_bytes(), target)?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn ready(&self, status: &str) -> Result<(), io::Error> {
+        self.notify(&format!("READY=1\nSTATUS={}", status))
+    }
+
+    pub(crate) fn status(&self, status: &str) -> Result<(), io::Error> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    pub(crate) fn stopping(&self, status: &str) -> Result<(), io::Error> {
+        self.notify(&format!("STOPPING=1\nSTATUS={}", status))
+    }
+
+    /*
+        Pings the watchdog at half the timeout systemd asked for, but only while `alive` says the
+        daemon is healthy. A wedged daemon stops pinging and systemd restarts it. Does nothing
+        without WatchdogSec=.
+    */
+    pub(crate) fn spawn_watchdog<F>(&self, alive: F)
+    where
+        F: Fn() -> bool + Send + 'static,
+    {
+        if let Some(timeout) = self.watchdog
+            && self.is_enabled()
+        {
+            let notifier = self.clone();
+            thread::spawn(move || {
+                loop {
+                    if alive() {
+                        notifier.notify("WATCHDOG=1").ok();
+                    }
+                    thread::sleep(timeout / 2);
+                }
+            });
+        }
+    }
+
+    // Mirrors what the runner is doing into `systemctl status`
+    pub(crate) fn spawn_status_updates(&self, receiver: Receiver<EventRecord>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let notifier = self.clone();
+        thread::spawn(move || {
+            for record in receiver {
+                if let Some(status) = describe(&record.event) {
+                    notifier.status(&status).ok();
+                }
+            }
+        });
+    }
+}
+
+fn describe(event: &Event) -> Option<String> {
+    match event {
+        Event::RunnerState { state } => Some(
+            match state {
+                RunnerState::Running => "Listening to the switch",
+                RunnerState::Restarting => "Runner failed, restarting",
+                RunnerState::Stopping => "Stopping",
+                RunnerState::Failed => "Runner failed, not restarting",
+                RunnerState::Stopped => "Idle, not listening to the switch",
+            }
+            .to_string(),
+        ),
+        Event::VpnConnecting => Some("Connecting".to_string()),
+        Event::VpnConnected { profile, .. } => Some(match profile {
+            Some(profile) => format!("Connected to {}", profile),
+            None => "Connected".to_string(),
+        }),
+        Event::VpnFailed { reason, .. } => Some(format!("Failed to connect: {}", reason)),
+        Event::VpnReconnecting {
+            attempt,
+            delay_secs,
+        } => Some(format!(
+            "Reconnecting in {}s (attempt {})",
+            delay_secs, attempt
+        )),
+        Event::VpnDisconnected => Some("Disconnected".to_string()),
+        Event::Error { message } => Some(format!("Error: {}", message)),
+        Event::OverrideChanged { mode, until } => Some(match until {
+            Some(until) => format!("Override {} until {}", mode.get_name(), until),
+            None => format!("Override {}", mode.get_name()),
+        }),
+        Event::SwitchToggled { .. } | Event::LogRotated | Event::Reloaded { .. } => None,
+    }
+}
+
+fn watchdog_timeout(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(own_pid)
+    {
+        return None;
+    }
+    usec.and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+fn listen_fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, own_pid: u32) -> usize {
+    // The variables are only meant for us when LISTEN_PID matches, children must ignore them
+    match (listen_pid, listen_fds) {
+        (Some(pid), Some(fds)) if pid.parse::<u32>().ok() == Some(own_pid) => {
+            fds.parse::<usize>().unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+// Returns the control socket handed over by systemd when socket activated
+pub(crate) fn take_listener() -> Result<Option<UnixListener>, io::Error> {
+    let count = listen_fd_count(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    if count == 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Expected one socket from systemd, got {}", count),
+        ));
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Descriptor passed by systemd is not a socket",
+        ));
+    }
+
+    // OpenVPN and every other child must not inherit the listening socket
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::TempDir;
+
+    fn fake_notify_socket() -> (TempDir, UnixDatagram, SystemdNotifier) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (dir, socket, SystemdNotifier::with_socket(&path))
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buffer = [0; 1024];
+        let result = socket.recv(&mut buffer);
+        assert!(result.is_ok(), "Nothing arrived on the notify socket");
+        String::from_utf8_lossy(&buffer[..result.unwrap()]).to_string()
+    }
+
+    #[test]
+    fn test_ready_and_status() {
+        let (_dir, socket, notifier) = fake_notify_socket();
+
+        assert!(notifier.ready("Listening").is_ok());
+        assert_eq!(receive(&socket), "READY=1\nSTATUS=Listening");
+
+        assert!(notifier.status("Connected").is_ok());
+        assert_eq!(receive(&socket), "STATUS=Connected");
+
+        assert!(notifier.stopping("Shutting down").is_ok());
+        assert_eq!(receive(&socket), "STOPPING=1\nSTATUS=Shutting down");
+    }
+
+    #[test]
+    fn test_status_updates_follow_events() {
+        let (_dir, socket, notifier) = fake_notify_socket();
+        let bus = crate::tools::events::EventBus::new();
+        notifier.spawn_status_updates(bus.subscribe());
+
+        bus.publish(Event::LogRotated);
+        bus.publish(Event::VpnConnected {
+            profile: Some("/vpn/se.ovpn".to_string()),
+            pid: Some(1),
+        });
+        // Events without a status are skipped, so the first datagram is the connection
+        assert_eq!(receive(&socket), "STATUS=Connected to /vpn/se.ovpn");
+    }
+
+    #[test]
+    fn test_watchdog_pings_only_while_alive() {
+        let (_dir, socket, mut notifier) = fake_notify_socket();
+        notifier.watchdog = Some(Duration::from_millis(100));
+        let alive = Arc::new(AtomicBool::new(false));
+        let check = Arc::clone(&alive);
+        notifier.spawn_watchdog(move || check.load(Ordering::SeqCst));
+
+        socket
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let mut buffer = [0; 64];
+        assert!(socket.recv(&mut buffer).is_err(), "Pinged while not alive");
+
+        alive.store(true, Ordering::SeqCst);
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+    }
+
+    #[test]
+    fn test_disabled_without_socket() {
+        let notifier = SystemdNotifier {
+            target: None,
+            watchdog: None,
+        };
+        assert!(!notifier.is_enabled());
+        assert!(notifier.ready("Listening").is_ok());
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert!(SystemdNotifier::parse_address("/run/systemd/notify").is_some());
+        assert!(SystemdNotifier::parse_address("@/org/freedesktop/notify").is_some());
+        assert!(SystemdNotifier::parse_address("relative/path").is_none());
+    }
+
+    #[test]
+    fn test_listen_fd_count() {
+        assert_eq!(listen_fd_count(Some("42"), Some("1"), 42), 1);
+        assert_eq!(listen_fd_count(Some("41"), Some("1"), 42), 0);
+        assert_eq!(listen_fd_count(None, Some("1"), 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), Some("x"), 42), 0);
+    }
+
+    #[test]
+    fn test_watchdog_timeout() {
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_timeout(Some("30000000"), Some("41"), 42), None);
+        assert_eq!(watchdog_timeout(Some("0"), None, 42), None);
+        assert_eq!(watchdog_timeout(None, None, 42), None);
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF
+    | libc::IN_ONLYDIR;
+// How often a profile directory that went away is looked for again
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const EVENT_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+/*
+    Watches the profile directory and everything below it with inotify, so dropping a provider
+    bundle in is picked up without a reload. Directories created later are watched as they
+    appear, symlinked ones are not followed. A directory moved around, or events lost to a full
+    queue, has the whole tree walked again, see `rewatch`. Events only say that something
+    changed, the caller rescans: a burst of them (unpacking an archive) is waited out first, see
+    `wait_for_changes`.
+*/
+pub(crate) struct Watcher {
+    fd: OwnedFd,
+    root: PathBuf,
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Watcher {
+    pub(crate) fn new(root: &Path) -> Result<Self, io::Error> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut watcher = Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            root: root.to_path_buf(),
+            dirs: HashMap::new(),
+        };
+        watcher.add_tree(root)?;
+        Ok(watcher)
+    }
+
+    /*
+        Blocks until something changed, then until nothing has happened for `quiet` or `limit`
+        has passed since the first change, whichever comes first. A profile directory that was
+        removed is watched again once it is back, which counts as a change.
+    */
+    pub(crate) fn wait_for_changes(
+        &mut self,
+        quiet: Duration,
+        limit: Duration,
+    ) -> Result<(), io::Error> {
+        loop {
+            if self.wait(RETRY_INTERVAL)? {
+                break;
+            }
+            if !self.is_watching_root() && self.root.is_dir() {
+                let root = self.root.clone();
+                self.add_tree(&root)?;
+                return Ok(());
+            }
+        }
+        let started = Instant::now();
+        while let Some(left) = limit.checked_sub(started.elapsed()) {
+            if !self.wait(quiet.min(left))? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_watching_root(&self) -> bool {
+        self.dirs.values().any(|dir| *dir == self.root)
+    }
+
+    // Subdirectories that cannot be read are skipped, the scan reports on them
+    fn add_tree(&mut self, dir: &Path) -> Result<(), io::Error> {
+        self.add_watch(dir)?;
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                self.add_tree(&entry.path()).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        Walks the tree again after a directory moved or events were dropped. inotify hands an
+        inode that is watched already its old descriptor, so this only corrects the paths, picks
+        up what was missed and drops the watches on directories that left the tree.
+    */
+    fn rewatch(&mut self) {
+        let old = std::mem::take(&mut self.dirs);
+        if self.root.is_dir() {
+            let root = self.root.clone();
+            self.add_tree(&root).ok();
+        }
+        for wd in old.keys().filter(|wd| !self.dirs.contains_key(wd)) {
+            unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), *wd) };
+        }
+    }
+
+    fn add_watch(&mut self, dir: &Path) -> Result<(), io::Error> {
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    // Whether anything happened within `timeout`
+    fn wait(&mut self, timeout: Duration) -> Result<bool, io::Error> {
+        let mut poll = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut poll, 1, timeout) } {
+            0 => Ok(false),
+            ready if ready > 0 => self.read_events(),
+            _ => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+
+    fn read_events(&mut self) -> Result<bool, io::Error> {
+        let mut changed = false;
+        let mut rewatch = false;
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if read < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock => {
+                        if rewatch {
+                            self.rewatch();
+                        }
+                        Ok(changed)
+                    }
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(e),
+                };
+            }
+
+            let mut offset = 0;
+            while offset + EVENT_SIZE <= read as usize {
+                let event: libc::inotify_event = unsafe {
+                    std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + EVENT_SIZE;
+                let name_end = (name_start + event.len as usize).min(read as usize);
+                let name: Vec<u8> = buffer[name_start..name_end]
+                    .iter()
+                    .copied()
+                    .take_while(|byte| *byte != 0)
+                    .collect();
+                offset = name_end;
+                changed = true;
+
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                let is_dir = event.mask & libc::IN_ISDIR != 0;
+                if event.mask & libc::IN_Q_OVERFLOW != 0
+                    || is_dir && event.mask & (libc::IN_MOVED_FROM | libc::IN_MOVED_TO) != 0
+                {
+                    rewatch = true;
+                    continue;
+                }
+                if is_dir
+                    && event.mask & libc::IN_CREATE != 0
+                    && let Some(parent) = self.dirs.get(&event.wd)
+                {
+                    let dir = parent.join(std::ffi::OsStr::from_bytes(&name));
+                    self.add_tree(&dir).ok();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUIET: Duration = Duration::from_millis(100);
+
+    fn settle(watcher: &mut Watcher) {
+        while watcher.wait(QUIET).unwrap() {}
+    }
+
+    #[test]
+    fn test_sees_new_and_removed_profiles() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("nordvpn")).unwrap();
+        let mut watcher = Watcher::new(dir.path()).unwrap();
+        assert!(!watcher.wait(QUIET).unwrap());
+
+        std::fs::write(dir.path().join("nordvpn/se.ovpn"), "remote se").unwrap();
+        assert!(watcher.wait(QUIET).unwrap());
+        settle(&mut watcher);
+
+        // Directories made after the watch started are watched too
+        std::fs::create_dir(dir.path().join("mullvad")).unwrap();
+        settle(&mut watcher);
+        std::fs::write(dir.path().join("mullvad/us.ovpn"), "remote us").unwrap();
+        assert!(watcher.wait(QUIET).unwrap());
+        settle(&mut watcher);
+
+        std::fs::remove_file(dir.path().join("nordvpn/se.ovpn")).unwrap();
+        assert!(watcher.wait(QUIET).unwrap());
+    }
+
+    #[test]
+    fn test_waits_out_bursts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut watcher = Watcher::new(dir.path()).unwrap();
+        let path = dir.path().to_path_buf();
+        let writer = std::thread::spawn(move || {
+            for i in 0..5 {
+                std::fs::write(path.join(format!("{}.ovpn", i)), "remote x").unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        watcher
+            .wait_for_changes(Duration::from_millis(200), Duration::from_secs(5))
+            .unwrap();
+        writer.join().unwrap();
+        // Everything landed in one burst, nothing is left over
+        assert!(!watcher.wait(QUIET).unwrap());
+    }
+
+    #[test]
+    fn test_directory_moved() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let outside = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("old/nordvpn")).unwrap();
+        std::fs::create_dir(dir.path().join("mullvad")).unwrap();
+        let mut watcher = Watcher::new(dir.path()).unwrap();
+
+        // The watch follows the directory, under its new name
+        std::fs::rename(dir.path().join("old"), dir.path().join("new")).unwrap();
+        assert!(watcher.wait(QUIET).unwrap());
+        settle(&mut watcher);
+        let watched = |watcher: &Watcher, path: &Path| watcher.dirs.values().any(|dir| dir == path);
+        assert!(watched(&watcher, &dir.path().join("new/nordvpn")));
+        assert!(!watched(&watcher, &dir.path().join("old/nordvpn")));
+        std::fs::write(dir.path().join("new/nordvpn/se.ovpn"), "remote se").unwrap();
+        assert!(watcher.wait(QUIET).unwrap());
+        settle(&mut watcher);
+
+        // Out of the tree, so whatever happens to it now is none of our business
+        std::fs::rename(dir.path().join("mullvad"), outside.path().join("mullvad")).unwrap();
+        assert!(watcher.wait(QUIET).unwrap());
+        settle(&mut watcher);
+        assert!(!watched(&watcher, &dir.path().join("mullvad")));
+        std::fs::write(outside.path().join("mullvad/us.ovpn"), "remote us").unwrap();
+        assert!(!watcher.wait(QUIET).unwrap());
+    }
+
+    #[test]
+    fn test_root_removed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path().join("VPN");
+        std::fs::create_dir(&root).unwrap();
+        let mut watcher = Watcher::new(&root).unwrap();
+        assert!(watcher.is_watching_root());
+
+        std::fs::remove_dir(&root).unwrap();
+        settle(&mut watcher);
+        assert!(!watcher.is_watching_root());
+    }
+}
@@ -0,0 +1,953 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const DEFAULT_SOCKET_PATH: &str = "/tmp/vpn-control.sock";
+const PROTOCOL_VERSION: u32 = 1;
+
+// Exit codes are part of the interface, scripts and monitoring checks depend on them
+const EXIT_OK: u8 = 0;
+const EXIT_FAILED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_NOT_RUNNING: u8 = 3;
+const EXIT_UNAVAILABLE: u8 = 4;
+const EXIT_PROTOCOL: u8 = 5;
+
+const USAGE: &str = "Usage: vpnctl [--json] [--socket PATH] [--timeout SECS] <command>
+
+Commands:
+  status              Show whether the daemon is listening to the switch
+  start               Start listening to the Arduino switch
+  stop                Stop listening and disconnect the VPN
+  connect <profile>   Connect to a profile by name, glob or substring
+  reload              Rescan profiles and re-read the daemon settings
+  override <mode> [DURATION]
+                      force-on, force-off or follow-switch, optionally for
+                      a while (90s, 30m, 2h, 1d)
+  logs [-n LINES]     Show the last lines of the daemon log
+  profiles [-l]       List the available profiles, -l with where they are and
+                      what they connect to
+  profiles lint       Show what is wrong with the profiles, exits 1 when
+                      some were left out
+  quarantine [list]   Show profiles kept out after failing to connect
+  quarantine clear [PROFILE]
+                      Let one or every profile back in
+  quarantine pin PROFILE
+                      Keep matching profiles out until cleared
+
+Options:
+  --json              Print the raw daemon response
+  -s, --socket PATH   Control socket (default $VPN_CONTROL_SOCKET or /tmp/vpn-control.sock)
+  -t, --timeout SECS  Seconds to wait for the daemon (default 30)
+  -h, --help          Show this message
+
+Exit codes: 0 ok, 1 command failed, 2 usage, 3 not running, 4 daemon unreachable, 5 protocol error";
+
+#[derive(Debug)]
+enum CtlError {
+    Usage(String),
+    Unavailable(std::io::Error),
+    Protocol(String),
+    Daemon { code: String, message: String },
+}
+
+impl std::fmt::Display for CtlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CtlError::Usage(msg) => write!(f, "{}", msg),
+            CtlError::Unavailable(e) => write!(f, "Cannot reach the daemon: {}", e),
+            CtlError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            CtlError::Daemon { code, message } => write!(f, "{} ({})", message, code),
+        }
+    }
+}
+
+impl CtlError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CtlError::Usage(_) => EXIT_USAGE,
+            CtlError::Unavailable(_) => EXIT_UNAVAILABLE,
+            CtlError::Protocol(_) => EXIT_PROTOCOL,
+            CtlError::Daemon { code, .. } if code == "not_running" => EXIT_NOT_RUNNING,
+            CtlError::Daemon { .. } => EXIT_FAILED,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Subcommand {
+    Status,
+    Start,
+    Stop,
+    Connect {
+        profile: String,
+    },
+    Reload,
+    Override {
+        mode: String,
+        duration: Option<String>,
+    },
+    Logs {
+        lines: Option<u64>,
+    },
+    Profiles {
+        long: bool,
+    },
+    Lint,
+    Quarantine {
+        action: String,
+        profile: Option<String>,
+    },
+}
+
+impl Subcommand {
+    fn request(&self) -> (&'static str, Value) {
+        match self {
+            Subcommand::Status => ("status", json!({})),
+            Subcommand::Start => ("start", json!({})),
+            Subcommand::Stop => ("stop", json!({ "wait": true })),
+            Subcommand::Connect { profile } => ("connect", json!({ "profile": profile })),
+            Subcommand::Reload => ("reload", json!({})),
+            Subcommand::Override { mode, duration } => {
+                ("override", json!({ "mode": mode, "duration": duration }))
+            }
+            Subcommand::Logs { lines: Some(lines) } => ("logs", json!({ "lines": lines })),
+            Subcommand::Logs { lines: None } => ("logs", json!({})),
+            Subcommand::Profiles { long } => ("profiles", json!({ "details": long })),
+            Subcommand::Lint => ("profiles", json!({ "lint": true })),
+            Subcommand::Quarantine { action, profile } => (
+                "quarantine",
+                json!({ "action": action, "profile": profile }),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    socket: String,
+    json: bool,
+    timeout: Duration,
+    command: Subcommand,
+}
+
+#[derive(Deserialize, Debug)]
+struct Response {
+    #[serde(default)]
+    id: Value,
+    ok: bool,
+    #[serde(default)]
+    result: Value,
+    #[serde(default)]
+    error: Option<ErrorBody>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorBody {
+    code: String,
+    message: String,
+}
+
+struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl Client {
+    fn connect(path: &str, timeout: Duration) -> Result<Self, CtlError> {
+        let stream = UnixStream::connect(path).map_err(CtlError::Unavailable)?;
+        stream
+            .set_read_timeout(Some(timeout))
+            .map_err(CtlError::Unavailable)?;
+        let writer = stream.try_clone().map_err(CtlError::Unavailable)?;
+
+        let mut client = Self {
+            reader: BufReader::new(stream),
+            writer,
+            next_id: 1,
+        };
+
+        let (hello, _) = client.call("hello", json!({ "versions": [PROTOCOL_VERSION] }))?;
+        match hello.result.get("version").and_then(Value::as_u64) {
+            Some(version) if version == PROTOCOL_VERSION as u64 => Ok(client),
+            _ => Err(CtlError::Protocol(format!(
+                "Daemon did not agree on protocol version {}",
+                PROTOCOL_VERSION
+            ))),
+        }
+    }
+
+    // Returns the parsed response together with the raw line for --json output
+    fn call(&mut self, cmd: &str, args: Value) -> Result<(Response, String), CtlError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = json!({ "v": PROTOCOL_VERSION, "id": id, "cmd": cmd, "args": args });
+        writeln!(self.writer, "{}", request).map_err(CtlError::Unavailable)?;
+
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => {
+                return Err(CtlError::Protocol(
+                    "Daemon closed the connection".to_string(),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => return Err(CtlError::Unavailable(e)),
+        }
+
+        let response: Response = serde_json::from_str(line.trim())
+            .map_err(|e| CtlError::Protocol(format!("Invalid response: {}", e)))?;
+        if response.id != json!(id) {
+            return Err(CtlError::Protocol(format!(
+                "Expected response to request {}, got {}",
+                id, response.id
+            )));
+        }
+
+        if response.ok {
+            Ok((response, line.trim().to_string()))
+        } else {
+            match response.error {
+                Some(error) => Err(CtlError::Daemon {
+                    code: error.code,
+                    message: error.message,
+                }),
+                None => Err(CtlError::Protocol(
+                    "Failed response without an error".to_string(),
+                )),
+            }
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, CtlError> {
+    let mut socket =
+        std::env::var("VPN_CONTROL_SOCKET").unwrap_or_else(|_| DEFAULT_SOCKET_PATH.to_string());
+    let mut json = false;
+    let mut timeout = Duration::from_secs(30);
+    let mut lines = None;
+    let mut long = false;
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-s" | "--socket" => {
+                socket = args
+                    .next()
+                    .ok_or_else(|| CtlError::Usage(format!("{} needs a path", arg)))?
+                    .to_string();
+            }
+            "-t" | "--timeout" => {
+                timeout = args
+                    .next()
+                    .and_then(|secs| secs.parse::<u64>().ok())
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| CtlError::Usage(format!("{} needs a number of seconds", arg)))?;
+            }
+            "-n" | "--lines" => {
+                lines = args
+                    .next()
+                    .and_then(|count| count.parse::<u64>().ok())
+                    .map(Some)
+                    .ok_or_else(|| CtlError::Usage(format!("{} needs a number of lines", arg)))?;
+            }
+            "-l" | "--long" => long = true,
+            _ => positional.push(arg.to_string()),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        Some("status") => Subcommand::Status,
+        Some("start") => Subcommand::Start,
+        Some("stop") => Subcommand::Stop,
+        Some("connect") => match positional.next() {
+            Some(profile) => Subcommand::Connect { profile },
+            None => return Err(CtlError::Usage("connect needs a profile".to_string())),
+        },
+        Some("reload") => Subcommand::Reload,
+        Some("override") => match positional.next() {
+            Some(mode) => Subcommand::Override {
+                mode,
+                duration: positional.next(),
+            },
+            None => {
+                return Err(CtlError::Usage(
+                    "override needs force-on, force-off or follow-switch".to_string(),
+                ));
+            }
+        },
+        Some("logs") => Subcommand::Logs { lines },
+        Some("profiles") => match positional.next().as_deref() {
+            Some("lint") => Subcommand::Lint,
+            Some(other) => return Err(CtlError::Usage(format!("Unexpected argument: {}", other))),
+            None => Subcommand::Profiles { long },
+        },
+        Some("quarantine") => match positional.next() {
+            None => Subcommand::Quarantine {
+                action: "list".to_string(),
+                profile: None,
+            },
+            Some(action) if action == "list" => Subcommand::Quarantine {
+                action,
+                profile: None,
+            },
+            Some(action) if action == "clear" => Subcommand::Quarantine {
+                action,
+                profile: positional.next(),
+            },
+            Some(action) if action == "pin" => match positional.next() {
+                Some(profile) => Subcommand::Quarantine {
+                    action,
+                    profile: Some(profile),
+                },
+                None => return Err(CtlError::Usage("pin needs a profile".to_string())),
+            },
+            Some(other) => {
+                return Err(CtlError::Usage(format!(
+                    "Unknown quarantine action: {}",
+                    other
+                )));
+            }
+        },
+        Some(other) => return Err(CtlError::Usage(format!("Unknown command: {}", other))),
+        None => return Err(CtlError::Usage(USAGE.to_string())),
+    };
+
+    if lines.is_some() && !matches!(command, Subcommand::Logs { .. }) {
+        return Err(CtlError::Usage("-n only applies to logs".to_string()));
+    }
+    if long && !matches!(command, Subcommand::Profiles { .. }) {
+        return Err(CtlError::Usage("-l only applies to profiles".to_string()));
+    }
+    if let Some(extra) = positional.next() {
+        return Err(CtlError::Usage(format!("Unexpected argument: {}", extra)));
+    }
+
+    Ok(Options {
+        socket,
+        json,
+        timeout,
+        command,
+    })
+}
+
+fn render(command: &Subcommand, result: &Value) -> String {
+    match command {
+        Subcommand::Status => render_status(result),
+        Subcommand::Start => "Daemon started".to_string(),
+        Subcommand::Stop => match result.get("stopped").and_then(Value::as_bool) {
+            Some(false) => "Daemon is still stopping".to_string(),
+            _ => "Daemon stopped".to_string(),
+        },
+        Subcommand::Connect { profile } => match result.get("profile").and_then(Value::as_str) {
+            Some(path) => format!("Connected to {}", path),
+            None => format!("Connected to {}", profile),
+        },
+        Subcommand::Reload => render_reload(result),
+        Subcommand::Override { .. } => format!("Override: {}", render_override(result)),
+        Subcommand::Logs { .. } => lines_of(&result["lines"]).join("\n"),
+        Subcommand::Profiles { .. } => {
+            let profiles: Vec<String> = match result["profiles"].as_array() {
+                Some(profiles) => profiles.iter().map(render_profile).collect(),
+                None => Vec::new(),
+            };
+            let mut out = profiles.join("\n");
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("{} profiles", profiles.len()));
+            if let Some(strategy) = result["strategy"].as_str() {
+                out.push_str(&format!(", picked by {}", strategy));
+            }
+            out
+        }
+        Subcommand::Lint => render_lint(result),
+        Subcommand::Quarantine { .. } => render_quarantine(result),
+    }
+}
+
+fn render_quarantine(result: &Value) -> String {
+    let mut out = String::new();
+    for changed in lines_of(&result["changed"]) {
+        out.push_str(&format!("* {}\n", changed));
+    }
+    for entry in result["profiles"].as_array().into_iter().flatten() {
+        let held = if entry["pinned"].as_bool().unwrap_or(false) {
+            "pinned".to_string()
+        } else {
+            format!("until {}", render_value(&entry["until"]))
+        };
+        out.push_str(&format!(
+            "{} ({}, {} failures)\n",
+            render_value(&entry["profile"]),
+            held,
+            render_value(&entry["failures"])
+        ));
+    }
+    out.push_str(&format!(
+        "{} profiles in quarantine",
+        render_value(&result["count"])
+    ));
+    out
+}
+
+fn render_lint(result: &Value) -> String {
+    let mut out = String::new();
+    for report in result["reports"].as_array().into_iter().flatten() {
+        out.push_str(&render_value(&report["path"]));
+        out.push('\n');
+        for finding in report["findings"].as_array().into_iter().flatten() {
+            let at = match finding["line"].as_u64() {
+                Some(line) => format!(" line {}", line),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "  {}{}: {}\n",
+                render_value(&finding["severity"]),
+                at,
+                render_value(&finding["message"])
+            ));
+        }
+    }
+    out.push_str(&format!(
+        "{} invalid profiles left out",
+        render_value(&result["invalid"])
+    ));
+    out
+}
+
+// A path as is, or a line of what the daemon read from the profile when details were asked for
+fn render_profile(profile: &Value) -> String {
+    if !profile.is_object() {
+        return render_value(profile);
+    }
+    let remote = &profile["remotes"][0];
+    let remote = match (remote["host"].as_str(), remote["port"].as_u64()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => "-".to_string(),
+    };
+    let proto = match profile["remotes"][0]["proto"].as_str() {
+        Some(proto) => proto.to_string(),
+        None => render_value(&profile["proto"]),
+    };
+    format!(
+        "{:<24} {:<16} {:<12} {:<5} {}",
+        render_value(&profile["name"]),
+        render_location(profile),
+        render_value(&profile["provider"]),
+        proto,
+        remote
+    )
+}
+
+fn render_location(profile: &Value) -> String {
+    match (profile["country"].as_str(), profile["city"].as_str()) {
+        (Some(country), Some(city)) => format!("{}, {}", country, city),
+        (Some(country), None) => country.to_string(),
+        (None, _) => "-".to_string(),
+    }
+}
+
+fn render_backend(result: &Value) -> String {
+    let mut out = match (result["backend"].as_str(), result["interface"].as_str()) {
+        (Some(backend), Some(interface)) => format!("{} on {}", backend, interface),
+        (Some(backend), None) => backend.to_string(),
+        (None, _) => return "-".to_string(),
+    };
+    if let Some(state) = result["tunnel_state"].as_str() {
+        out.push_str(&format!(" ({})", state));
+    }
+    out
+}
+
+fn render_reconnect(reconnect: &Value) -> String {
+    match reconnect["attempt"].as_u64() {
+        Some(attempt) => format!("attempt {} at {}", attempt, render_value(&reconnect["at"])),
+        None => "-".to_string(),
+    }
+}
+
+fn render_reload(result: &Value) -> String {
+    let profiles = &result["profiles"];
+    let mut out = format!("{} profiles", render_value(&profiles["count"]));
+    if let Some(invalid) = profiles["invalid"].as_u64().filter(|invalid| *invalid > 0) {
+        out.push_str(&format!(", {} invalid (see profiles lint)", invalid));
+    }
+    for added in lines_of(&profiles["added"]) {
+        out.push_str(&format!("\n+ {}", added));
+    }
+    for removed in lines_of(&profiles["removed"]) {
+        out.push_str(&format!("\n- {}", removed));
+    }
+
+    let changed = lines_of(&result["settings"]["changed"]);
+    if changed.is_empty() {
+        out.push_str("\nSettings unchanged");
+    } else {
+        out.push_str(&format!("\nSettings changed: {}", changed.join(", ")));
+    }
+    let restart = lines_of(&result["settings"]["restart_required"]);
+    if !restart.is_empty() {
+        out.push_str(&format!(
+            "\nRestart the daemon to apply: {}",
+            restart.join(", ")
+        ));
+    }
+    out
+}
+
+fn render_status(result: &Value) -> String {
+    let mut out = if result["running"].as_bool().unwrap_or(false) {
+        "Daemon is running".to_string()
+    } else {
+        "Daemon is not running".to_string()
+    };
+
+    let switch = match result["switch"]["position"].as_str() {
+        Some(position) => format!(
+            "{} (last \"{}\" at {})",
+            position,
+            render_value(&result["switch"]["last_message"]),
+            render_value(&result["switch"]["at"])
+        ),
+        None => "-".to_string(),
+    };
+    // Older daemons send no kind, it is only there when the daemon recognized the failure
+    let last_error = match (
+        result["last_error"]["message"].as_str(),
+        result["last_error"]["kind"].as_str(),
+    ) {
+        (Some(message), Some(kind)) => format!(
+            "{} ({}, at {})",
+            message,
+            kind,
+            render_value(&result["last_error"]["at"])
+        ),
+        (Some(message), None) => format!(
+            "{} (at {})",
+            message,
+            render_value(&result["last_error"]["at"])
+        ),
+        (None, _) => "-".to_string(),
+    };
+
+    let rows = [
+        ("State", render_state(result)),
+        ("Profile", render_value(&result["profile"])),
+        ("Location", render_location(&result["profile_info"])),
+        ("Backend", render_backend(result)),
+        ("PID", render_value(&result["pid"])),
+        ("Uptime", format_uptime(&result["uptime_secs"])),
+        ("Switch", switch),
+        ("Override", render_override(&result["override"])),
+        ("Serial port", render_value(&result["serial_port"])),
+        ("Reconnect", render_reconnect(&result["reconnect"])),
+        ("Last error", last_error),
+    ];
+    for (label, value) in rows {
+        out.push_str(&format!("\n{:<12} {}", format!("{}:", label), value));
+    }
+    out
+}
+
+// The supervisor's state, with the restarts and the failure behind it when there were any
+fn render_state(result: &Value) -> String {
+    let mut state = render_value(&result["state"]);
+    match result["runner"]["restarts"].as_u64() {
+        Some(1) => state.push_str(", restarted once"),
+        Some(restarts) if restarts > 1 => {
+            state.push_str(&format!(", restarted {} times", restarts))
+        }
+        _ => {}
+    }
+    if let Some(last_exit) = result["runner"]["last_exit"].as_str() {
+        state.push_str(&format!(" (last exit: {})", last_exit));
+    }
+    state
+}
+
+fn render_override(value: &Value) -> String {
+    match (value["mode"].as_str(), value["until"].as_str()) {
+        (Some(mode), Some(until)) => format!("{} until {}", mode, until),
+        (Some(mode), None) => mode.to_string(),
+        (None, _) => "-".to_string(),
+    }
+}
+
+fn format_uptime(value: &Value) -> String {
+    match value.as_u64() {
+        Some(secs) => format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+        None => "-".to_string(),
+    }
+}
+
+fn render_value(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn lines_of(value: &Value) -> Vec<String> {
+    match value.as_array() {
+        Some(items) => items.iter().map(render_value).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn exit_code(command: &Subcommand, result: &Value) -> u8 {
+    match command {
+        // Lets `vpnctl status` double as a monitoring check
+        Subcommand::Status if !result["running"].as_bool().unwrap_or(false) => EXIT_NOT_RUNNING,
+        Subcommand::Lint if result["invalid"].as_u64().unwrap_or(0) > 0 => EXIT_FAILED,
+        _ => EXIT_OK,
+    }
+}
+
+fn run(options: &Options) -> Result<u8, CtlError> {
+    let mut client = Client::connect(&options.socket, options.timeout)?;
+    let (cmd, args) = options.command.request();
+    let (response, raw) = client.call(cmd, args)?;
+
+    if options.json {
+        println!("{}", raw);
+    } else {
+        let out = render(&options.command, &response.result);
+        if !out.is_empty() {
+            println!("{}", out);
+        }
+    }
+    Ok(exit_code(&options.command, &response.result))
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::from(EXIT_OK);
+    }
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(e.exit_code());
+        }
+    };
+
+    match run(&options) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            if options.json {
+                let (code, message) = match &e {
+                    CtlError::Daemon { code, message } => (code.to_string(), message.to_string()),
+                    other => ("client_error".to_string(), other.to_string()),
+                };
+                println!(
+                    "{}",
+                    json!({ "ok": false, "error": { "code": code, "message": message } })
+                );
+            } else {
+                eprintln!("vpnctl: {}", e);
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args("--json -s /tmp/x.sock connect se-sto")).unwrap();
+        assert!(options.json);
+        assert_eq!(options.socket, "/tmp/x.sock");
+        assert_eq!(
+            options.command,
+            Subcommand::Connect {
+                profile: "se-sto".to_string()
+            }
+        );
+
+        let options = parse_args(&args("logs -n 20")).unwrap();
+        assert_eq!(options.command, Subcommand::Logs { lines: Some(20) });
+
+        let options = parse_args(&args("override force-on 2h")).unwrap();
+        assert_eq!(
+            options.command,
+            Subcommand::Override {
+                mode: "force-on".to_string(),
+                duration: Some("2h".to_string())
+            }
+        );
+        assert!(parse_args(&args("override")).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_usage_errors() {
+        for line in [
+            "",
+            "connect",
+            "reboot",
+            "status extra",
+            "logs -n many",
+            "status -n 5",
+            "status -l",
+            "profiles lint -l",
+            "profiles all",
+            "quarantine pin",
+            "quarantine release se",
+            "quarantine list se",
+            "-t",
+        ] {
+            let result = parse_args(&args(line));
+            assert!(
+                matches!(result, Err(CtlError::Usage(_))),
+                "Expected usage error for {:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(
+            exit_code(&Subcommand::Status, &json!({ "running": true })),
+            EXIT_OK
+        );
+        assert_eq!(
+            exit_code(&Subcommand::Status, &json!({ "running": false })),
+            EXIT_NOT_RUNNING
+        );
+
+        let error = CtlError::Daemon {
+            code: "not_running".to_string(),
+            message: "Daemon is not running".to_string(),
+        };
+        assert_eq!(error.exit_code(), EXIT_NOT_RUNNING);
+        let error = CtlError::Unavailable(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(error.exit_code(), EXIT_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_render_status() {
+        let out = render(
+            &Subcommand::Status,
+            &json!({
+                "running": true,
+                "state": "running",
+                "profile": "/vpn/se.ovpn",
+                "pid": 4242,
+                "backend": "openvpn",
+                "interface": null,
+                "tunnel_state": "CONNECTED",
+                "uptime_secs": 3725,
+                "switch": { "position": "on", "last_message": "Turn On", "at": "2025-05-01 10:00:00" },
+                "serial_port": "/dev/ttyACM0",
+                "override": { "mode": "force-on", "until": "2025-05-01 12:00:00" },
+                "last_error": null,
+                "reconnect": { "attempt": 2, "at": "2025-05-01 10:01:00" },
+            }),
+        );
+        assert!(out.starts_with("Daemon is running"));
+        assert!(out.contains("Reconnect:   attempt 2 at 2025-05-01 10:01:00"));
+        assert!(out.contains("Override:    force-on until 2025-05-01 12:00:00"));
+        assert!(out.contains("Profile:     /vpn/se.ovpn"));
+        assert!(out.contains("Uptime:      1h 02m 05s"));
+        assert!(out.contains("Backend:     openvpn (CONNECTED)\n"));
+        assert!(out.contains("Switch:      on (last \"Turn On\" at 2025-05-01 10:00:00)"));
+        assert!(out.contains("Last error:  -"));
+        assert!(out.contains("State:       running\n"));
+
+        let out = render(
+            &Subcommand::Status,
+            &json!({
+                "running": true,
+                "state": "running",
+                "last_error": {
+                    "message": "Failed to connect to /vpn/se.ovpn: The server rejected the credentials (AUTH_FAILED)",
+                    "at": "2025-05-01 10:00:30",
+                    "kind": "auth_failed",
+                },
+            }),
+        );
+        assert!(out.contains(
+            "Last error:  Failed to connect to /vpn/se.ovpn: The server rejected the credentials (AUTH_FAILED) (auth_failed, at 2025-05-01 10:00:30)"
+        ));
+    }
+
+    #[test]
+    fn test_render_restarted_state() {
+        let out = render_state(&json!({
+            "state": "restarting",
+            "runner": { "restarts": 2, "last_exit": "Serial port vanished" },
+        }));
+        assert_eq!(
+            out,
+            "restarting, restarted 2 times (last exit: Serial port vanished)"
+        );
+    }
+
+    #[test]
+    fn test_render_quarantine() {
+        let options = parse_args(&args("quarantine pin se-*")).unwrap();
+        assert_eq!(
+            options.command.request(),
+            ("quarantine", json!({ "action": "pin", "profile": "se-*" }))
+        );
+        let options = parse_args(&args("quarantine")).unwrap();
+        assert_eq!(
+            options.command.request(),
+            ("quarantine", json!({ "action": "list", "profile": null }))
+        );
+
+        let out = render(
+            &options.command,
+            &json!({
+                "count": 2,
+                "changed": ["/vpn/se.ovpn"],
+                "profiles": [
+                    { "profile": "/vpn/se.ovpn", "failures": 0, "pinned": true, "until": null },
+                    { "profile": "/vpn/us.ovpn", "failures": 2, "pinned": false, "until": "2025-05-01 10:10:00" },
+                ],
+            }),
+        );
+        assert_eq!(
+            out,
+            "* /vpn/se.ovpn\n/vpn/se.ovpn (pinned, 0 failures)\n/vpn/us.ovpn (until 2025-05-01 10:10:00, 2 failures)\n2 profiles in quarantine"
+        );
+    }
+
+    #[test]
+    fn test_render_lint() {
+        let options = parse_args(&args("profiles lint")).unwrap();
+        assert_eq!(options.command, Subcommand::Lint);
+        let result = json!({
+            "invalid": 1,
+            "reports": [
+                {
+                    "path": "/vpn/README.md",
+                    "valid": false,
+                    "findings": [{ "severity": "error", "line": null, "message": "No `remote`" }],
+                },
+                {
+                    "path": "/vpn/se.ovpn",
+                    "valid": true,
+                    "findings": [{ "severity": "warning", "line": 3, "message": "`comp-lzo` is deprecated" }],
+                },
+            ],
+        });
+        assert_eq!(
+            render(&Subcommand::Lint, &result),
+            "/vpn/README.md\n  error: No `remote`\n/vpn/se.ovpn\n  warning line 3: `comp-lzo` is deprecated\n1 invalid profiles left out"
+        );
+        assert_eq!(exit_code(&Subcommand::Lint, &result), EXIT_FAILED);
+        assert_eq!(
+            exit_code(&Subcommand::Lint, &json!({ "invalid": 0 })),
+            EXIT_OK
+        );
+    }
+
+    #[test]
+    fn test_render_profiles() {
+        let out = render(
+            &Subcommand::Profiles { long: false },
+            &json!({ "count": 2, "profiles": ["/vpn/a.ovpn", "/vpn/b.ovpn"] }),
+        );
+        assert_eq!(out, "/vpn/a.ovpn\n/vpn/b.ovpn\n2 profiles");
+
+        let out = render(
+            &Subcommand::Profiles { long: false },
+            &json!({ "count": 1, "profiles": ["/vpn/a.ovpn"], "strategy": "round-robin" }),
+        );
+        assert_eq!(out, "/vpn/a.ovpn\n1 profiles, picked by round-robin");
+        let options = parse_args(&args("profiles -l")).unwrap();
+        assert_eq!(options.command, Subcommand::Profiles { long: true });
+        assert_eq!(options.command.request().1, json!({ "details": true }));
+        let out = render(
+            &options.command,
+            &json!({ "count": 1, "profiles": [{
+                "path": "/vpn/mullvad/se-got-01.ovpn",
+                "name": "se-got-01",
+                "remotes": [{ "host": "se-got.example.com", "port": 1194, "proto": null }],
+                "proto": "udp",
+                "provider": "mullvad",
+                "country": "SE",
+                "city": "got",
+            }] }),
+        );
+        assert_eq!(
+            out,
+            format!(
+                "{:<24} {:<16} {:<12} {:<5} {}\n1 profiles",
+                "se-got-01", "SE, got", "mullvad", "udp", "se-got.example.com:1194"
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_reload() {
+        let out = render(
+            &Subcommand::Reload,
+            &json!({
+                "profiles": { "count": 2, "added": ["/vpn/c.ovpn"], "removed": ["/vpn/a.ovpn"] },
+                "settings": { "changed": ["socket_mode"], "restart_required": ["socket_mode"] },
+            }),
+        );
+        assert_eq!(
+            out,
+            "2 profiles\n+ /vpn/c.ovpn\n- /vpn/a.ovpn\nSettings changed: socket_mode\nRestart the daemon to apply: socket_mode"
+        );
+    }
+
+    #[test]
+    fn test_client_round_trip() {
+        let path = std::env::temp_dir().join(format!("vpnctl-test-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Fake daemon that answers the hello and one status request
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            for result in [json!({ "version": 1 }), json!({ "running": true })] {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let request: Value = serde_json::from_str(&line).unwrap();
+                let response = json!({ "v": 1, "id": request["id"], "ok": true, "result": result });
+                writeln!(writer, "{}", response).unwrap();
+            }
+        });
+
+        let options = Options {
+            socket: path.to_str().unwrap().to_string(),
+            json: false,
+            timeout: Duration::from_secs(5),
+            command: Subcommand::Status,
+        };
+        let result = run(&options);
+        server.join().unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert!(result.is_ok(), "Client failed: {}", result.unwrap_err());
+        assert_eq!(result.unwrap(), EXIT_OK);
+    }
+}